@@ -0,0 +1,240 @@
+//! Writes frames out as PNG images, e.g. to record gameplay. The encoder is self-contained: the
+//! image data goes into uncompressed deflate blocks, so files are large but need no compression
+//! library.
+
+use crate::game::DisplayBuffer;
+use std::{fs, io, path::PathBuf};
+
+/// Every PNG file starts with these bytes
+pub const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// Where `--record` saves frames, relative to the working directory
+pub const RECORDING_DIRECTORY: &str = "frames";
+
+/// Largest block deflate can store uncompressed
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Encodes a `width` by `height` image of 8-bit RGBA pixels, rows from the top down, as a PNG.
+/// Panics if `rgba` doesn't hold exactly that many pixels.
+pub fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    let row_len = width as usize * 4;
+    assert!(rgba.len() == row_len * height as usize);
+
+    // Every row is prefixed with its filter type, 0 for none
+    let mut scanlines = Vec::with_capacity((row_len + 1) * height as usize);
+    if row_len > 0 {
+        for row in rgba.chunks_exact(row_len) {
+            scanlines.push(0);
+            scanlines.extend_from_slice(row);
+        }
+    } else {
+        scanlines.resize(height as usize, 0);
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, RGBA, deflate, adaptive filtering, not interlaced
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Appends a chunk: big-endian length, type, data, then a CRC of the type and data
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let block_count = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut stream = Vec::with_capacity(data.len() + block_count * 5 + 6);
+    // Deflate with a 32K window, no preset dictionary, header check bits making it divisible by 31
+    stream.extend_from_slice(&[0x78, 0x01]);
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // Even empty data needs a final block
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        let len = block.len() as u16;
+        stream.push(u8::from(is_final));
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+/// The CRC-32 PNG chunks end with (the same one zip and Ethernet use)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        *entry = (0..8).fold(n as u32, |c, _| {
+            if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            }
+        });
+    }
+
+    !data.iter().fold(!0u32, |crc, &byte| {
+        table[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The checksum a zlib stream ends with
+pub fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + u32::from(byte)) % MODULUS;
+        (a, (b + a) % MODULUS)
+    });
+    (b << 16) | a
+}
+
+/// Saves frames as `00000.png`, `00001.png` and so on into a directory, created with the first
+/// frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameRecorder {
+    directory: PathBuf,
+    next_frame: u32,
+}
+
+impl FrameRecorder {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> FrameRecorder {
+        FrameRecorder {
+            directory: directory.into(),
+            next_frame: 0,
+        }
+    }
+
+    /// Path the next frame will be written to
+    pub fn next_path(&self) -> PathBuf {
+        self.directory.join(format!("{:05}.png", self.next_frame))
+    }
+
+    /// Writes the finished frame in `buffer`'s backbuffer to the next file in the sequence
+    pub fn record(&mut self, buffer: &DisplayBuffer) -> io::Result<()> {
+        if self.next_frame == 0 {
+            fs::create_dir_all(&self.directory)?;
+        }
+        buffer.save_png(&self.next_path())?;
+        self.next_frame += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{BlendMode, GradientMode, Pixel};
+
+    /// Decodes a PNG as `encode_png` writes it, checking every CRC and checksum on the way.
+    /// Returns the width, height and RGBA pixels.
+    fn decode_png(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        let be32 = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+        let mut header = Vec::new();
+        let mut stream = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = be32(rest) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            assert_eq!(be32(&rest[8 + len..]), crc32(&rest[4..8 + len]));
+            match kind {
+                b"IHDR" => header = data.to_vec(),
+                b"IDAT" => stream.extend_from_slice(data),
+                _ => {}
+            }
+            rest = &rest[12 + len..];
+        }
+        let (width, height) = (be32(&header), be32(&header[4..]));
+        assert_eq!(header[8..], [8, 6, 0, 0, 0]);
+
+        // zlib header, stored blocks, then the Adler-32 of what they hold
+        assert_eq!((u16::from(stream[0]) << 8 | u16::from(stream[1])) % 31, 0);
+        let mut scanlines = Vec::new();
+        let mut blocks = &stream[2..];
+        loop {
+            let is_final = blocks[0] & 1 != 0;
+            assert_eq!(blocks[0] >> 1, 0, "only stored blocks");
+            let len = usize::from(u16::from_le_bytes([blocks[1], blocks[2]]));
+            assert_eq!(!u16::from_le_bytes([blocks[3], blocks[4]]), len as u16);
+            scanlines.extend_from_slice(&blocks[5..5 + len]);
+            blocks = &blocks[5 + len..];
+            if is_final {
+                break;
+            }
+        }
+        assert_eq!(be32(blocks), adler32(&scanlines));
+
+        let row_len = width as usize * 4;
+        let mut rgba = Vec::new();
+        for row in scanlines.chunks_exact(row_len + 1) {
+            assert_eq!(row[0], 0, "no filter");
+            rgba.extend_from_slice(&row[1..]);
+        }
+        assert_eq!(rgba.len(), row_len * height as usize);
+        (width, height, rgba)
+    }
+
+    #[test]
+    fn png_round_trips_a_tiny_image() {
+        #[rustfmt::skip]
+        let rgba = [
+            255, 0, 0, 255,   0, 255, 0, 128,
+            0, 0, 255, 255,   1, 2, 3, 4,
+        ];
+        let png = encode_png(2, 2, &rgba);
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        assert_eq!(decode_png(&png), (2, 2, rgba.to_vec()));
+
+        // Big enough to split over several stored blocks
+        let rgba: Vec<u8> = (0..200 * 100 * 4).map(|i| i as u8).collect();
+        assert_eq!(decode_png(&encode_png(200, 100, &rgba)), (200, 100, rgba));
+    }
+
+    #[test]
+    fn recorded_frames_are_rgba_top_row_first() {
+        let directory = std::env::temp_dir().join(format!("again-{}-frames", std::process::id()));
+        let red = Pixel::from_rgba(255, 0, 0, 255);
+        let blue = Pixel::from_rgba(0, 0, 255, 255);
+        let buffer = DisplayBuffer {
+            back: vec![red, red, blue, blue],
+            front: vec![Pixel::default(); 4],
+            gradient_mode: GradientMode::FullRgb,
+            blend_mode: BlendMode::Linear,
+            width: 2,
+            height: 2,
+        };
+
+        let mut recorder = FrameRecorder::new(&directory);
+        recorder.record(&buffer).unwrap();
+        recorder.record(&buffer).unwrap();
+        let first = fs::read(directory.join("00000.png")).unwrap();
+        assert!(directory.join("00001.png").exists());
+        assert_eq!(recorder.next_path(), directory.join("00002.png"));
+        fs::remove_dir_all(&directory).unwrap();
+
+        let (width, height, rgba) = decode_png(&first);
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(&rgba[..4], [255, 0, 0, 255]);
+        assert_eq!(&rgba[12..], [0, 0, 255, 255]);
+    }
+}
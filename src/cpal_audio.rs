@@ -0,0 +1,197 @@
+//! Sound output through cpal, used instead of DirectSound when built with the `cpal` feature and
+//! the only sound output on Linux. The device asks for samples from a callback on its own thread,
+//! which renders exactly that many frames from a `SoundBuffer` shared with the main loop.
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use game::*;
+use std::{
+    convert::TryFrom,
+    io,
+    sync::{Arc, Mutex},
+};
+
+/// Rate asked of the device when it supports it, the same as the DirectSound buffer uses
+const PREFERRED_SAMPLE_RATE: u32 = 48000;
+
+/// The sound the callback renders. The main loop only changes its settings, playback state like
+/// the ramps and phases moves on in the callback.
+struct StreamSound {
+    sound_buffer: SoundBuffer,
+    tone_hz: u16,
+    /// Renders silence, holding the phase, while set
+    paused: bool,
+    phase: f32,
+}
+
+pub struct CpalAudio {
+    shared: Arc<Mutex<StreamSound>>,
+    stream: cpal::Stream,
+}
+
+fn audio_error<E: std::fmt::Display>(message: &str, error: E) -> io::Error {
+    io::Error::other(format!("{}: {}", message, error))
+}
+
+impl CpalAudio {
+    /// Opens the default output device and starts playing, fading in with the settings of
+    /// `settings`
+    pub fn open(settings: &SoundBuffer, tone_hz: u16) -> io::Result<CpalAudio> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| io::Error::other("No audio output device"))?;
+
+        // 48kHz in a format we can write if the device has it, otherwise whatever it prefers
+        let preferred = device
+            .supported_output_configs()
+            .map_err(|error| audio_error("Could not query the audio device", error))?
+            .filter(|range| {
+                matches!(
+                    range.sample_format(),
+                    cpal::SampleFormat::I16 | cpal::SampleFormat::F32
+                )
+            })
+            .find(|range| {
+                (range.min_sample_rate().0..=range.max_sample_rate().0)
+                    .contains(&PREFERRED_SAMPLE_RATE)
+            })
+            .map(|range| range.with_sample_rate(cpal::SampleRate(PREFERRED_SAMPLE_RATE)));
+        let supported = match preferred {
+            Some(supported) => supported,
+            None => device
+                .default_output_config()
+                .map_err(|error| audio_error("Could not query the audio device", error))?,
+        };
+        let sample_format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+
+        let sample_rate = u16::try_from(config.sample_rate.0).map_err(|_| {
+            io::Error::other(format!("Unsupported sample rate {}", config.sample_rate.0))
+        })?;
+        let mut sound_buffer = SoundBuffer {
+            // Grown by the first callback
+            samples: Vec::new(),
+            sample_count: 0,
+            volume: 0.0,
+            muted: false,
+            tone_enabled: true,
+            sample_rate,
+            channels: config.channels,
+            waveform: Waveform::Sine,
+            sample_clock: 0,
+            // 10ms
+            ramp_samples: u32::from(sample_rate) / 100,
+            tone_ramp: Ramp::new(f32::from(tone_hz)),
+            // Fade in from silence on startup
+            volume_ramp: Ramp::new(0.0),
+            clip: None,
+            oscillators: Vec::new(),
+            filter_cutoff: 0.0,
+            filter_state: 0.0,
+        };
+        copy_settings(&mut sound_buffer, settings);
+        let shared = Arc::new(Mutex::new(StreamSound {
+            sound_buffer,
+            tone_hz,
+            paused: false,
+            phase: 0.0,
+        }));
+
+        let on_error = |error| eprintln!("Audio stream error: {}", error);
+        let stream = match sample_format {
+            cpal::SampleFormat::I16 => {
+                let shared = Arc::clone(&shared);
+                device.build_output_stream(
+                    &config,
+                    move |out: &mut [i16], _: &cpal::OutputCallbackInfo| render(&shared, out),
+                    on_error,
+                    None,
+                )
+            }
+            cpal::SampleFormat::F32 => {
+                let shared = Arc::clone(&shared);
+                // Reused across callbacks so rendering doesn't allocate once it has grown
+                let mut samples = Vec::new();
+                device.build_output_stream(
+                    &config,
+                    move |out: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                        samples.resize(out.len(), 0);
+                        render(&shared, &mut samples);
+                        for (out, &sample) in out.iter_mut().zip(samples.iter()) {
+                            *out = f32::from(sample) / 32768.0;
+                        }
+                    },
+                    on_error,
+                    None,
+                )
+            }
+            other => {
+                return Err(io::Error::other(format!(
+                    "Unsupported sample format {}",
+                    other
+                )))
+            }
+        }
+        .map_err(|error| audio_error("Could not open the audio stream", error))?;
+        stream
+            .play()
+            .map_err(|error| audio_error("Could not start the audio stream", error))?;
+
+        Ok(CpalAudio { shared, stream })
+    }
+
+    /// Hands the settings the game rendered into `sound_buffer` this frame to the callback
+    pub fn update(&self, sound_buffer: &SoundBuffer, tone_hz: u16, paused: bool) {
+        let mut stream_sound = self.shared.lock().unwrap();
+        stream_sound.tone_hz = tone_hz;
+        stream_sound.paused = paused;
+        copy_settings(&mut stream_sound.sound_buffer, sound_buffer);
+    }
+
+    /// The Linux layer keeps playing in the background
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn pause(&self) {
+        // Failing to pause only means the sound keeps playing
+        let _ = self.stream.pause();
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn play(&self) {
+        let _ = self.stream.play();
+    }
+}
+
+/// Copies what the game controls from `source`, leaving `target`'s playback state alone
+fn copy_settings(target: &mut SoundBuffer, source: &SoundBuffer) {
+    target.volume = source.volume;
+    target.muted = source.muted;
+    target.tone_enabled = source.tone_enabled;
+    target.waveform = source.waveform;
+    target.filter_cutoff = source.filter_cutoff;
+    if target.oscillators.len() == source.oscillators.len() {
+        // Retune in place, keeping phases so the voices don't click
+        for (target, source) in target.oscillators.iter_mut().zip(source.oscillators.iter()) {
+            target.frequency_hz = source.frequency_hz;
+            target.waveform = source.waveform;
+            target.amplitude = source.amplitude;
+        }
+    } else {
+        target.oscillators = source.oscillators.clone();
+    }
+}
+
+/// Fills `out` from the shared sound, or with silence while paused or if the main thread panicked
+/// holding it
+fn render(shared: &Mutex<StreamSound>, out: &mut [i16]) {
+    match shared.lock() {
+        Ok(mut stream_sound) if !stream_sound.paused => {
+            let StreamSound {
+                sound_buffer,
+                tone_hz,
+                phase,
+                ..
+            } = &mut *stream_sound;
+            sound_buffer.render_into(phase, *tone_hz, out);
+        }
+        _ => out.iter_mut().for_each(|sample| *sample = 0),
+    }
+}
@@ -1,6 +1,16 @@
-use std::f32;
+use crate::{
+    capture::encode_png,
+    font::{Font, GLYPH_SIZE},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    f32,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Pixel {
     b: u8,
     g: u8,
@@ -8,58 +18,4095 @@ pub struct Pixel {
     a: u8,
 }
 
+impl Pixel {
+    pub fn from_rgba(r: u8, g: u8, b: u8, a: u8) -> Pixel {
+        Pixel { b, g, r, a }
+    }
+
+    /// Unpacks a `0xAARRGGBB` value
+    pub fn from_u32(value: u32) -> Pixel {
+        let [b, g, r, a] = value.to_le_bytes();
+        Pixel { b, g, r, a }
+    }
+
+    /// Packs the channels as `0xAARRGGBB`, which is laid out as BGRA in memory like the DIB expects
+    pub fn to_u32(self) -> u32 {
+        u32::from_le_bytes([self.b, self.g, self.r, self.a])
+    }
+
+    /// Blends `self` over `dest` weighted by `self.a`, rounding to the nearest value
+    fn blend_over(self, dest: Pixel) -> Pixel {
+        let alpha = u32::from(self.a);
+        let blend_channel = |src: u8, dest: u8| {
+            ((u32::from(src) * alpha + u32::from(dest) * (255 - alpha) + 127) / 255) as u8
+        };
+
+        Pixel {
+            b: blend_channel(self.b, dest.b),
+            g: blend_channel(self.g, dest.g),
+            r: blend_channel(self.r, dest.r),
+            a: blend_channel(self.a, dest.a),
+        }
+    }
+
+    /// Like `blend_over`, but mixes the colors as light intensities instead of sRGB values, so a
+    /// half-transparent edge isn't darker than either side. Alpha is blended as is.
+    fn blend_over_linear(self, dest: Pixel) -> Pixel {
+        let alpha = f32::from(self.a) / 255.0;
+        let blend_channel = |src: u8, dest: u8| {
+            linear_to_srgb(srgb_to_linear(src) * alpha + srgb_to_linear(dest) * (1.0 - alpha))
+        };
+
+        Pixel {
+            b: blend_channel(self.b, dest.b),
+            g: blend_channel(self.g, dest.g),
+            r: blend_channel(self.r, dest.r),
+            a: self.blend_over(dest).a,
+        }
+    }
+}
+
+/// Decodes an sRGB channel to linear intensity in `[0, 1]`
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = f32::from(value) / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encodes a linear intensity in `[0, 1]` as the nearest sRGB channel value
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let encoded = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0).round() as u8
+}
+
+/// How `DisplayBuffer::blit_alpha` mixes colors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Blend the stored sRGB values directly. Fast, but darkens edges between bright colors.
+    Srgb,
+    /// Convert to linear light, blend and convert back. Slower but physically correct.
+    Linear,
+}
+
+/// Which channels `DisplayBuffer::render_gradient` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GradientMode {
+    /// Legacy monochrome ramp that only writes the green channel
+    GreenOnly,
+    /// Red tracks `x`, blue tracks `y` and green keeps the legacy pattern
+    FullRgb,
+}
+
+/// Fixed images drawn by `DisplayBuffer::test_pattern` for checking the picture reaches the
+/// screen undistorted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestPattern {
+    /// White and black squares of `TEST_PATTERN_CELL` pixels, white in the top-left corner
+    Checkerboard,
+    /// Eight full-height bars of equal width, left to right white, yellow, cyan, green, magenta,
+    /// red, blue and black
+    ColorBars,
+    /// White lines on black every `TEST_PATTERN_CELL` pixels from the top-left corner, with the
+    /// last row and column drawn too so every edge is marked
+    Grid,
+}
+
+/// Side of a checkerboard square and of a grid cell in pixels. Squares that come out as
+/// rectangles on screen give away a stretched picture.
+pub const TEST_PATTERN_CELL: i32 = 32;
+
+/// Colors of `TestPattern::ColorBars`, left to right
+const COLOR_BARS: [Pixel; 8] = [
+    Pixel {
+        b: 255,
+        g: 255,
+        r: 255,
+        a: 255,
+    },
+    Pixel {
+        b: 0,
+        g: 255,
+        r: 255,
+        a: 255,
+    },
+    Pixel {
+        b: 255,
+        g: 255,
+        r: 0,
+        a: 255,
+    },
+    Pixel {
+        b: 0,
+        g: 255,
+        r: 0,
+        a: 255,
+    },
+    Pixel {
+        b: 255,
+        g: 0,
+        r: 255,
+        a: 255,
+    },
+    Pixel {
+        b: 0,
+        g: 0,
+        r: 255,
+        a: 255,
+    },
+    Pixel {
+        b: 255,
+        g: 0,
+        r: 0,
+        a: 255,
+    },
+    Pixel {
+        b: 0,
+        g: 0,
+        r: 0,
+        a: 255,
+    },
+];
+
 pub struct DisplayBuffer {
-    pub memory: Vec<Pixel>,
-    pub current_offset: i32,
+    /// Frame being drawn. Every drawing method writes here.
+    pub back: Vec<Pixel>,
+    /// Last finished frame, the one presented to the window. `swap` replaces it with `back`.
+    pub front: Vec<Pixel>,
+    pub gradient_mode: GradientMode,
+    pub blend_mode: BlendMode,
     pub width: i32,
     pub height: i32,
 }
 
 impl DisplayBuffer {
-    pub fn step_render(&mut self, step_by: i32) {
+    /// Whether the backbuffer must be reallocated to match a `width` x `height` client area.
+    /// An empty client area (e.g. a minimized window) never requires a resize.
+    pub fn needs_resize(&self, width: i32, height: i32) -> bool {
+        if width <= 0 || height <= 0 {
+            return false;
+        }
+
+        width != self.width
+            || height != self.height
+            || self.back.len() != width as usize * height as usize
+            || self.front.len() != width as usize * height as usize
+    }
+
+    /// Changes the size to `width` x `height` (negative sizes count as 0), keeping each pixel of
+    /// both buffers at the same coordinates. Pixels that no longer fit are dropped and new ones
+    /// are black.
+    pub fn resize(&mut self, width: i32, height: i32) {
+        let (width, height) = (width.max(0), height.max(0));
+        let resize_buffer = |pixels: &mut Vec<Pixel>, old_width: i32, old_height: i32| {
+            let mut resized = vec![Pixel::default(); width as usize * height as usize];
+            let copy_width = old_width.min(width).max(0) as usize;
+            let copy_height = old_height.min(height).max(0);
+            for y in 0..copy_height as usize {
+                let old_row = y * old_width as usize;
+                // Missing pixels stay black if the buffer didn't match its old size
+                if let Some(old) = pixels.get(old_row..old_row + copy_width) {
+                    let new_row = y * width as usize;
+                    resized[new_row..new_row + copy_width].copy_from_slice(old);
+                }
+            }
+            *pixels = resized;
+        };
+
+        resize_buffer(&mut self.back, self.width, self.height);
+        resize_buffer(&mut self.front, self.width, self.height);
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Size of the buffers in pixels, the area drawing methods cover. This is where HUD elements
+    /// are laid out, and may not be the size of the window showing it:
+    ///
+    /// - By default the platform layer keeps it equal to the window's client area, in physical
+    ///   pixels. The window starts out at its logical size scaled for DPI, so on a scaled
+    ///   display it is bigger than the size asked for.
+    /// - With a fixed render resolution it stays at that size and is scaled to the window.
+    /// - While fullscreen it keeps the windowed size and is stretched to the monitor.
+    ///
+    /// It changes only through `resize`, so it always matches the last successful resize.
+    pub fn dimensions(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    /// Finishes the frame in `back`, making it the one presented, and hands the previous front
+    /// buffer back for drawing the next frame. Its contents are stale until drawn over.
+    pub fn swap(&mut self) {
+        std::mem::swap(&mut self.back, &mut self.front);
+    }
+
+    pub fn clear(&mut self, color: Pixel) {
+        debug_assert!(self.back.len() == (self.width * self.height) as usize);
+        self.back.iter_mut().for_each(|pixel| *pixel = color);
+    }
+
+    fn pixel_offset(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
+        }
+        Some(y as usize * self.width as usize + x as usize)
+    }
+
+    /// `(0, 0)` is the top-left corner with `y` increasing downwards, matching the top-down DIB
+    /// that `draw_to_window` presents. Out-of-range coordinates are ignored.
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: Pixel) {
+        if let Some(offset) = self.pixel_offset(x, y) {
+            self.back[offset] = color;
+        }
+    }
+
+    /// Same coordinate system as `set_pixel`. Returns `None` for out-of-range coordinates.
+    pub fn get_pixel(&self, x: i32, y: i32) -> Option<Pixel> {
+        self.pixel_offset(x, y).map(|offset| self.back[offset])
+    }
+
+    /// Fills the half-open rectangle `[min_x, max_x) x [min_y, max_y)`, clipped to the buffer
+    pub fn draw_rectangle(&mut self, min_x: i32, min_y: i32, max_x: i32, max_y: i32, color: Pixel) {
+        let min_x = min_x.max(0).min(self.width);
+        let min_y = min_y.max(0).min(self.height);
+        let max_x = max_x.max(0).min(self.width);
+        let max_y = max_y.max(0).min(self.height);
+        if min_x >= max_x {
+            return;
+        }
+
+        for y in min_y..max_y {
+            let row_start = (y * self.width) as usize;
+            self.back[row_start + min_x as usize..row_start + max_x as usize]
+                .iter_mut()
+                .for_each(|pixel| *pixel = color);
+        }
+    }
+
+    /// Draws the border of the half-open rectangle `[min_x, max_x) x [min_y, max_y)`, `thickness`
+    /// pixels thick on the inside, clipped to the buffer. A border too thick to leave a hole fills
+    /// the whole rectangle; a thickness of zero or less draws nothing.
+    pub fn draw_rectangle_outline(
+        &mut self,
+        min_x: i32,
+        min_y: i32,
+        max_x: i32,
+        max_y: i32,
+        thickness: i32,
+        color: Pixel,
+    ) {
+        if thickness <= 0 || min_x >= max_x || min_y >= max_y {
+            return;
+        }
+        let inner_min_x = min_x.saturating_add(thickness);
+        let inner_min_y = min_y.saturating_add(thickness);
+        let inner_max_x = max_x.saturating_sub(thickness);
+        let inner_max_y = max_y.saturating_sub(thickness);
+        if inner_min_x >= inner_max_x || inner_min_y >= inner_max_y {
+            self.draw_rectangle(min_x, min_y, max_x, max_y, color);
+            return;
+        }
+
+        self.draw_rectangle(min_x, min_y, max_x, inner_min_y, color);
+        self.draw_rectangle(min_x, inner_max_y, max_x, max_y, color);
+        self.draw_rectangle(min_x, inner_min_y, inner_min_x, inner_max_y, color);
+        self.draw_rectangle(inner_max_x, inner_min_y, max_x, inner_max_y, color);
+    }
+
+    /// Fills the polygon through `points`, closed back to the first, by the even-odd rule: a pixel
+    /// is filled if its center is crossed by an odd number of edges going left. Edges may cross.
+    /// Pixels outside the buffer are skipped, and fewer than 3 points or all of them on one line
+    /// draw nothing.
+    pub fn fill_polygon(&mut self, points: &[(i32, i32)], color: Pixel) {
+        if points.len() < 3 {
+            return;
+        }
+        // Wide enough that the cross products of any two edges can't overflow
+        let (first_x, first_y) = (i128::from(points[0].0), i128::from(points[0].1));
+        let (second_x, second_y) = match points.iter().find(|&&point| point != points[0]) {
+            Some(&(x, y)) => (i128::from(x), i128::from(y)),
+            None => return,
+        };
+        let collinear = points.iter().all(|&(x, y)| {
+            (second_x - first_x) * (i128::from(y) - first_y)
+                == (second_y - first_y) * (i128::from(x) - first_x)
+        });
+        if collinear {
+            return;
+        }
+
+        // Edge table: each non-horizontal edge as (top y, bottom y, x at top, x step per row)
+        let edges: Vec<(f64, f64, f64, f64)> = points
+            .iter()
+            .zip(points.iter().cycle().skip(1))
+            .filter(|((_, y0), (_, y1))| y0 != y1)
+            .map(|(&(x0, y0), &(x1, y1))| {
+                let ((x0, y0), (x1, y1)) = if y0 < y1 {
+                    ((x0, y0), (x1, y1))
+                } else {
+                    ((x1, y1), (x0, y0))
+                };
+                let (x0, y0, x1, y1) = (f64::from(x0), f64::from(y0), f64::from(x1), f64::from(y1));
+                (y0, y1, x0, (x1 - x0) / (y1 - y0))
+            })
+            .collect();
+
+        let min_y = points.iter().map(|&(_, y)| y).min().unwrap_or(0).max(0);
+        let max_y = points
+            .iter()
+            .map(|&(_, y)| y)
+            .max()
+            .unwrap_or(0)
+            .min(self.height);
+        let mut crossings = Vec::new();
+        for y in min_y..max_y {
+            // Sample at pixel centers. Edges are half-open at the bottom so a vertex shared by
+            // two edges is crossed once.
+            let center_y = f64::from(y) + 0.5;
+            crossings.clear();
+            crossings.extend(
+                edges
+                    .iter()
+                    .filter(|&&(top, bottom, _, _)| top <= center_y && center_y < bottom)
+                    .map(|&(top, _, x, step)| x + (center_y - top) * step),
+            );
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+            for span in crossings.chunks_exact(2) {
+                // Pixels whose centers fall in [start, end)
+                let start = (span[0] - 0.5).ceil().max(f64::from(i32::MIN)) as i32;
+                let end = (span[1] - 0.5).ceil().min(f64::from(i32::MAX)) as i32;
+                self.draw_rectangle(start, y, end, y + 1, color);
+            }
+        }
+    }
+
+    /// Draws the line from `(x0, y0)` to `(x1, y1)`, both endpoints inclusive, using Bresenham's
+    /// algorithm. The line is clipped to the buffer first, so only its visible part is stepped
+    /// through however far away the endpoints are.
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Pixel) {
+        let (x0, y0, x1, y1) = match clip_line(x0, y0, x1, y1, self.width, self.height) {
+            Some(clipped) => clipped,
+            None => return,
+        };
+
+        // Error terms are kept in i64 so distant endpoints can't overflow
+        let dx = (i64::from(x1) - i64::from(x0)).abs();
+        let dy = -(i64::from(y1) - i64::from(y0)).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+
+        let mut error = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            self.set_pixel(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+
+            let doubled_error = 2 * error;
+            if doubled_error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled_error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draws an anti-aliased line from `(x0, y0)` to `(x1, y1)` using Xiaolin Wu's algorithm.
+    /// Pixel centers sit on whole coordinates, as with `draw_line`. Each pixel the line touches
+    /// gets `color` blended over it like `blit_alpha`, weighted by how much of it the line covers.
+    /// Points that fall outside the buffer are skipped; non-finite endpoints draw nothing.
+    pub fn draw_line_aa(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, color: Pixel) {
+        if ![x0, y0, x1, y1].iter().all(|value| value.is_finite()) {
+            return;
+        }
+
+        // Walk along the major axis, one pixel per step, with `plot` swapping back if that is y
+        let steep = (y1 - y0).abs() > (x1 - x0).abs();
+        let (x0, y0, x1, y1) = if steep {
+            (y0, x0, y1, x1)
+        } else {
+            (x0, y0, x1, y1)
+        };
+        let (x0, y0, x1, y1) = if x0 > x1 {
+            (x1, y1, x0, y0)
+        } else {
+            (x0, y0, x1, y1)
+        };
+        let (major_size, minor_size) = if steep {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        };
+        let mut plot = |major: i32, minor: i32, coverage: f32| {
+            if steep {
+                self.blend_pixel(minor, major, color, coverage);
+            } else {
+                self.blend_pixel(major, minor, color, coverage);
+            }
+        };
+
+        let dx = x1 - x0;
+        let gradient = if dx == 0.0 { 1.0 } else { (y1 - y0) / dx };
+        // Spreads coverage between the two pixels straddling `y` in column `x`
+        let mut plot_pair = |x: i32, y: f32, weight: f32| {
+            let row = y.floor();
+            // Rows off the buffer are dropped while still floats, converting a distant (or NaN,
+            // from a huge gradient) row to i32 and stepping below it would overflow
+            if !(-1.0..minor_size as f32).contains(&row) {
+                return;
+            }
+            let below = y - row;
+            plot(x, row as i32, (1.0 - below) * weight);
+            plot(x, row as i32 + 1, below * weight);
+        };
+
+        // Endpoints only cover the part of their pixel the line reaches into
+        let fraction = |value: f32| value - value.floor();
+        let start_x = (x0 + 0.5).floor();
+        let start_y = y0 + gradient * (start_x - x0);
+        plot_pair(start_x as i32, start_y, 1.0 - fraction(x0 + 0.5));
+        let end_x = (x1 + 0.5).floor();
+        let end_y = y1 + gradient * (end_x - x1);
+        if end_x != start_x {
+            plot_pair(end_x as i32, end_y, fraction(x1 + 0.5));
+        }
+
+        // Only the columns inside the buffer, so distant endpoints don't loop for nothing
+        let first = (start_x + 1.0).max(0.0);
+        let last = (end_x - 1.0).min(major_size as f32 - 1.0);
+        let mut x = first;
+        while x <= last {
+            plot_pair(x as i32, start_y + gradient * (x - start_x), 1.0);
+            x += 1.0;
+        }
+    }
+
+    /// Blends `color` over the pixel at `(x, y)` with its alpha scaled by `coverage`, in the space
+    /// chosen by `blend_mode`. Out-of-range coordinates are ignored.
+    fn blend_pixel(&mut self, x: i32, y: i32, color: Pixel, coverage: f32) {
+        if let Some(offset) = self.pixel_offset(x, y) {
+            let color = Pixel {
+                a: (f32::from(color.a) * coverage.clamp(0.0, 1.0)).round() as u8,
+                ..color
+            };
+            let dest = self.back[offset];
+            self.back[offset] = match self.blend_mode {
+                BlendMode::Srgb => color.blend_over(dest),
+                BlendMode::Linear => color.blend_over_linear(dest),
+            };
+        }
+    }
+
+    /// Fills the disc of pixels within `radius` of `(cx, cy)`, clipped to the buffer.
+    /// A zero radius draws a single pixel and a negative radius draws nothing.
+    pub fn draw_circle(&mut self, cx: i32, cy: i32, radius: i32, color: Pixel) {
+        if radius < 0 {
+            return;
+        }
+
+        let min_x = cx.saturating_sub(radius).max(0);
+        let min_y = cy.saturating_sub(radius).max(0);
+        let max_x = cx.saturating_add(radius).min(self.width - 1);
+        let max_y = cy.saturating_add(radius).min(self.height - 1);
+
+        let radius_squared = i64::from(radius) * i64::from(radius);
+        for y in min_y..=max_y {
+            let dy = i64::from(y) - i64::from(cy);
+            for x in min_x..=max_x {
+                let dx = i64::from(x) - i64::from(cx);
+                if dx * dx + dy * dy <= radius_squared {
+                    self.set_pixel(x, y, color);
+                }
+            }
+        }
+    }
+
+    /// Draws `text` in the built-in font with the top-left corner of its first glyph at `(x, y)`.
+    /// Only lit pixels are written, and any that fall outside the buffer are skipped. `'\n'` starts
+    /// a new line at `x`; characters the font doesn't cover are drawn as a box.
+    pub fn draw_text(&mut self, x: i32, y: i32, text: &str, color: Pixel) {
+        let font = Font::default();
+        let (mut pen_x, mut pen_y) = (x, y);
+        for c in text.chars() {
+            if c == '\n' {
+                pen_x = x;
+                pen_y = pen_y.saturating_add(GLYPH_SIZE);
+                continue;
+            }
+
+            for glyph_y in 0..GLYPH_SIZE {
+                for glyph_x in 0..GLYPH_SIZE {
+                    if font.is_lit(c, glyph_x, glyph_y) {
+                        self.set_pixel(
+                            pen_x.saturating_add(glyph_x),
+                            pen_y.saturating_add(glyph_y),
+                            color,
+                        );
+                    }
+                }
+            }
+            pen_x = pen_x.saturating_add(GLYPH_SIZE);
+        }
+    }
+
+    /// Copies the `src_w` x `src_h` image `src` with its top-left corner at `(dest_x, dest_y)`.
+    /// Any part of the source that falls outside the buffer is skipped.
+    pub fn blit(&mut self, src: &[Pixel], src_w: i32, src_h: i32, dest_x: i32, dest_y: i32) {
+        self.blit_with(src, src_w, src_h, dest_x, dest_y, |dest, src| *dest = src);
+    }
+
+    /// Like `blit`, but blends each source pixel over the destination using the source alpha:
+    /// `out = src * a + dest * (1 - a)` per channel, in the space chosen by `blend_mode`.
+    /// `a == 0` leaves the destination untouched and `a == 255` overwrites it exactly.
+    pub fn blit_alpha(&mut self, src: &[Pixel], src_w: i32, src_h: i32, dest_x: i32, dest_y: i32) {
+        match self.blend_mode {
+            BlendMode::Srgb => self.blit_with(src, src_w, src_h, dest_x, dest_y, |dest, src| {
+                *dest = src.blend_over(*dest)
+            }),
+            BlendMode::Linear => self.blit_with(src, src_w, src_h, dest_x, dest_y, |dest, src| {
+                *dest = src.blend_over_linear(*dest)
+            }),
+        }
+    }
+
+    /// Clips the source rectangle against the buffer and calls `combine` for every overlapping
+    /// destination/source pixel pair
+    fn blit_with<F: FnMut(&mut Pixel, Pixel)>(
+        &mut self,
+        src: &[Pixel],
+        src_w: i32,
+        src_h: i32,
+        dest_x: i32,
+        dest_y: i32,
+        mut combine: F,
+    ) {
+        assert!(src_w >= 0 && src_h >= 0);
+        assert!(src.len() == src_w as usize * src_h as usize);
+
+        // Part of the source that lands inside the buffer
+        let src_min_x = dest_x.saturating_neg().max(0);
+        let src_min_y = dest_y.saturating_neg().max(0);
+        let src_max_x = src_w.min(self.width.saturating_sub(dest_x));
+        let src_max_y = src_h.min(self.height.saturating_sub(dest_y));
+
+        for src_y in src_min_y..src_max_y {
+            let src_row = src_y as usize * src_w as usize;
+            let dest_row = (dest_y + src_y) as usize * self.width as usize;
+            for src_x in src_min_x..src_max_x {
+                let dest_offset = dest_row + (dest_x + src_x) as usize;
+                combine(&mut self.back[dest_offset], src[src_row + src_x as usize]);
+            }
+        }
+    }
+
+    /// Box-filters this buffer down into `dst`, each destination pixel averaging a 2x2 block
+    /// starting at twice its coordinates. Rendering at double resolution and downsampling hides
+    /// banding. Blocks hanging off the edge of an odd-sized (or too small) source reuse its last
+    /// row or column.
+    pub fn downsample_into(&self, dst: &mut DisplayBuffer) {
+        assert!(dst.back.len() == dst.width.max(0) as usize * dst.height.max(0) as usize);
+        if self.width <= 0 || self.height <= 0 {
+            return;
+        }
+        assert!(self.back.len() == self.width as usize * self.height as usize);
+
+        let src_pixel = |x: i32, y: i32| {
+            let (x, y) = (x.min(self.width - 1), y.min(self.height - 1));
+            self.back[y as usize * self.width as usize + x as usize]
+        };
+        for y in 0..dst.height {
+            for x in 0..dst.width {
+                let block = [
+                    src_pixel(2 * x, 2 * y),
+                    src_pixel(2 * x + 1, 2 * y),
+                    src_pixel(2 * x, 2 * y + 1),
+                    src_pixel(2 * x + 1, 2 * y + 1),
+                ];
+                // Rounded to nearest
+                let average = |channel: fn(&Pixel) -> u8| {
+                    ((block
+                        .iter()
+                        .map(|pixel| u32::from(channel(pixel)))
+                        .sum::<u32>()
+                        + 2)
+                        / 4) as u8
+                };
+                dst.back[y as usize * dst.width as usize + x as usize] = Pixel {
+                    b: average(|pixel| pixel.b),
+                    g: average(|pixel| pixel.g),
+                    r: average(|pixel| pixel.r),
+                    a: average(|pixel| pixel.a),
+                };
+            }
+        }
+    }
+
+    /// Writes the buffer to `path` as an uncompressed 32-bit BMP. Rows are stored bottom-up,
+    /// which is what most image viewers expect.
+    pub fn save_bmp(&self, path: &Path) -> io::Result<()> {
+        const FILE_HEADER_SIZE: u32 = 14;
+        const INFO_HEADER_SIZE: u32 = 40;
+        // 72 DPI
+        const PIXELS_PER_METER: i32 = 2835;
+
+        assert!(self.width >= 0 && self.height >= 0);
+        assert!(self.back.len() == self.width as usize * self.height as usize);
+
+        let image_size = (self.back.len() * std::mem::size_of::<u32>()) as u32;
+        let pixel_data_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+
+        let mut file = BufWriter::new(File::create(path)?);
+
+        // BITMAPFILEHEADER
+        file.write_all(b"BM")?;
+        file.write_all(&(pixel_data_offset + image_size).to_le_bytes())?;
+        // Reserved
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&pixel_data_offset.to_le_bytes())?;
+
+        // BITMAPINFOHEADER
+        file.write_all(&INFO_HEADER_SIZE.to_le_bytes())?;
+        file.write_all(&self.width.to_le_bytes())?;
+        // Positive height for a bottom-up image
+        file.write_all(&self.height.to_le_bytes())?;
+        // Planes
+        file.write_all(&1u16.to_le_bytes())?;
+        // Bits per pixel
+        file.write_all(&32u16.to_le_bytes())?;
+        // BI_RGB: uncompressed
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&image_size.to_le_bytes())?;
+        file.write_all(&PIXELS_PER_METER.to_le_bytes())?;
+        file.write_all(&PIXELS_PER_METER.to_le_bytes())?;
+        // Colors used/important: none since there is no palette
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        // Memory is top-down, so write the rows in reverse. 32-bit rows never need padding.
+        if self.width > 0 {
+            for row in self.back.chunks_exact(self.width as usize).rev() {
+                for pixel in row {
+                    file.write_all(&pixel.to_u32().to_le_bytes())?;
+                }
+            }
+        }
+
+        file.flush()
+    }
+
+    /// Writes the buffer to `path` as a PNG, top row first as PNGs are stored
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        assert!(self.width >= 0 && self.height >= 0);
+        assert!(self.back.len() == self.width as usize * self.height as usize);
+
+        let rgba: Vec<u8> = self
+            .back
+            .iter()
+            .flat_map(|pixel| [pixel.r, pixel.g, pixel.b, pixel.a])
+            .collect();
+        fs::write(
+            path,
+            encode_png(self.width as u32, self.height as u32, &rgba),
+        )
+    }
+
+    /// Scales the colour channels of every pixel by `t`, clamped to `[0, 1]`: black at 0, unchanged
+    /// at 1. Results round to the nearest value with halves rounding up, so 255 at `t = 0.5`
+    /// becomes 128. Alpha is left alone.
+    pub fn fade_from_black(&mut self, t: f32) {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        if t == 1.0 {
+            return;
+        }
+
+        let scale = |channel: u8| (f32::from(channel) * t).round() as u8;
+        for pixel in self.back.iter_mut() {
+            pixel.r = scale(pixel.r);
+            pixel.g = scale(pixel.g);
+            pixel.b = scale(pixel.b);
+        }
+    }
+
+    /// Renders the gradient scrolled by `offset` pixels
+    pub fn render_gradient(&mut self, offset: f32) {
+        self.render_gradient_xy(offset, offset);
+    }
+
+    /// Renders the gradient with red scrolled by `offset_x` pixels, blue by `offset_y` and green
+    /// by their average, so equal offsets draw the same as `render_gradient`
+    pub fn render_gradient_xy(&mut self, offset_x: f32, offset_y: f32) {
         assert!(self.width > 0 && self.height > 0);
 
-        assert!(self.memory.len() == self.height as usize * self.width as usize);
-        for (i, pixel) in self.memory.iter_mut().enumerate() {
-            assert!(i < i32::max_value() as usize);
+        assert!(self.back.len() == self.height as usize * self.width as usize);
+        let (offset_x, offset_y) = (offset_x as i32, offset_y as i32);
+        let offset_green = ((i64::from(offset_x) + i64::from(offset_y)) / 2) as i32;
+        for (i, pixel) in self.back.iter_mut().enumerate() {
+            assert!(i < i32::MAX as usize);
             let x = i as i32 % self.width;
-            let y = i as i32 / self.height;
-            pixel.g = ((x ^ y) - self.current_offset) as u8;
+            let y = i as i32 / self.width;
+            // Overwrite the whole pixel so nothing drawn last frame leaks into this one
+            *pixel = Pixel {
+                g: ((x ^ y).wrapping_sub(offset_green)) as u8,
+                ..Pixel::default()
+            };
+            if self.gradient_mode == GradientMode::FullRgb {
+                pixel.r = x.wrapping_add(offset_x) as u8;
+                pixel.b = y.wrapping_add(offset_y) as u8;
+            }
+        }
+    }
+
+    /// Overwrites the whole buffer with `kind`. The same size always gives the same image.
+    pub fn test_pattern(&mut self, kind: TestPattern) {
+        let white = Pixel::from_rgba(255, 255, 255, 255);
+        let black = Pixel::from_rgba(0, 0, 0, 255);
+        let width = self.width.max(0) as usize;
+        match kind {
+            TestPattern::Checkerboard => {
+                for (i, pixel) in self.back.iter_mut().enumerate() {
+                    let cell_x = (i % width.max(1)) as i32 / TEST_PATTERN_CELL;
+                    let cell_y = (i / width.max(1)) as i32 / TEST_PATTERN_CELL;
+                    *pixel = if (cell_x + cell_y) % 2 == 0 {
+                        white
+                    } else {
+                        black
+                    };
+                }
+            }
+            TestPattern::ColorBars => {
+                let bar_count = COLOR_BARS.len() as i64;
+                for (i, pixel) in self.back.iter_mut().enumerate() {
+                    let x = (i % width.max(1)) as i64;
+                    *pixel = COLOR_BARS[(x * bar_count / width.max(1) as i64) as usize];
+                }
+            }
+            TestPattern::Grid => {
+                self.clear(black);
+                let (width, height) = (self.width, self.height);
+                for x in (0..width)
+                    .step_by(TEST_PATTERN_CELL as usize)
+                    .chain(Some(width - 1))
+                {
+                    self.draw_rectangle(x, 0, x + 1, height, white);
+                }
+                for y in (0..height)
+                    .step_by(TEST_PATTERN_CELL as usize)
+                    .chain(Some(height - 1))
+                {
+                    self.draw_rectangle(0, y, width, y + 1, white);
+                }
+            }
+        }
+    }
+}
+
+/// Clips the segment from `(x0, y0)` to `(x1, y1)` to the pixels of a `width` x `height` buffer
+/// (Liang-Barsky). Returns the endpoints of the part inside, rounded to the nearest pixel, or
+/// `None` if none of it is.
+fn clip_line(
+    x0: i32,
+    y0: i32,
+    x1: i32,
+    y1: i32,
+    width: i32,
+    height: i32,
+) -> Option<(i32, i32, i32, i32)> {
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+
+    let (start_x, start_y) = (f64::from(x0), f64::from(y0));
+    let (dx, dy) = (f64::from(x1) - start_x, f64::from(y1) - start_y);
+    let (max_x, max_y) = (f64::from(width - 1), f64::from(height - 1));
+
+    // Points along the line are `start + t * d` for `t` in `[0, 1]`. Each edge of the buffer
+    // keeps the points where `p * t <= q`, narrowing the range of `t` from one end or the other.
+    let (mut enter, mut leave) = (0.0f64, 1.0f64);
+    let edges = [
+        (-dx, start_x),
+        (dx, max_x - start_x),
+        (-dy, start_y),
+        (dy, max_y - start_y),
+    ];
+    for &(p, q) in edges.iter() {
+        if p == 0.0 {
+            // Parallel to this edge, and entirely on the wrong side of it
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            enter = enter.max(q / p);
+        } else {
+            leave = leave.min(q / p);
+        }
+    }
+    if enter > leave {
+        return None;
+    }
+
+    // Rounding can land half a pixel outside, the clamp brings it back
+    let point = |t: f64| {
+        (
+            (start_x + t * dx).round().clamp(0.0, max_x) as i32,
+            (start_y + t * dy).round().clamp(0.0, max_y) as i32,
+        )
+    };
+    let (x0, y0) = point(enter);
+    let (x1, y1) = point(leave);
+    Some((x0, y0, x1, y1))
+}
+
+/// An 8-bit indexed image: each pixel is an index into a 256-color palette, only turned into
+/// colors by `resolve_into`. Changing the palette recolors the whole image without redrawing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedBuffer {
+    /// Row-major from the top-left corner, `width * height` entries
+    pub indices: Vec<u8>,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl IndexedBuffer {
+    /// A `width` x `height` image (negative sizes count as 0) of index 0
+    pub fn new(width: i32, height: i32) -> IndexedBuffer {
+        let (width, height) = (width.max(0), height.max(0));
+        IndexedBuffer {
+            indices: vec![0; width as usize * height as usize],
+            width,
+            height,
+        }
+    }
+
+    /// Same coordinates as `DisplayBuffer::set_pixel`. Out-of-range coordinates are ignored.
+    pub fn set_index(&mut self, x: i32, y: i32, index: u8) {
+        if x >= 0 && y >= 0 && x < self.width && y < self.height {
+            self.indices[y as usize * self.width as usize + x as usize] = index;
+        }
+    }
+
+    /// Sets every pixel to `index`
+    pub fn clear_index(&mut self, index: u8) {
+        self.indices.iter_mut().for_each(|pixel| *pixel = index);
+    }
+
+    /// Writes the colors the indices pick from `palette` into `dst`'s back buffer, top-left
+    /// corners aligned. Where the sizes differ only the overlap is written.
+    pub fn resolve_into(&self, dst: &mut DisplayBuffer, palette: &[Pixel; 256]) {
+        let width = self.width.min(dst.width).max(0) as usize;
+        let height = self.height.min(dst.height).max(0) as usize;
+        for y in 0..height {
+            let src_row = &self.indices[y * self.width as usize..][..width];
+            let dst_row = &mut dst.back[y * dst.width as usize..][..width];
+            for (pixel, &index) in dst_row.iter_mut().zip(src_row) {
+                *pixel = palette[usize::from(index)];
+            }
+        }
+    }
+}
+
+/// Rotates the `len` entries of `palette` from `start` by `steps` places: positive towards the
+/// end, with the last entries wrapping round to the start of the range, negative the other way.
+/// The range is clipped to the palette.
+pub fn rotate_palette(palette: &mut [Pixel], start: usize, len: usize, steps: i32) {
+    let start = start.min(palette.len());
+    let end = start.saturating_add(len).min(palette.len());
+    let range = &mut palette[start..end];
+    if range.is_empty() {
+        return;
+    }
+
+    let steps = i64::from(steps).rem_euclid(range.len() as i64) as usize;
+    range.rotate_right(steps);
+}
+
+/// Cycles part of a palette at a steady rate, the classic way of animating water or fire in an
+/// indexed image without touching its indices
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PaletteCycle {
+    pub start: usize,
+    pub len: usize,
+    /// Places the entries move each second, negative to cycle towards the start
+    pub steps_per_second: f32,
+    /// Movement short of a whole step, carried so short frames add up
+    remainder: f32,
+}
+
+impl PaletteCycle {
+    pub fn new(start: usize, len: usize, steps_per_second: f32) -> PaletteCycle {
+        PaletteCycle {
+            start,
+            len,
+            steps_per_second,
+            remainder: 0.0,
+        }
+    }
+
+    /// Rotates the range of `palette` by `dt` seconds' worth of steps
+    pub fn advance(&mut self, palette: &mut [Pixel], dt: f32) {
+        if !dt.is_finite() || dt <= 0.0 || !self.steps_per_second.is_finite() {
+            return;
+        }
+
+        let steps = self.remainder + self.steps_per_second * dt;
+        let whole = steps.trunc();
+        self.remainder = steps - whole;
+        // A whole cycle is the same as none, so only the part of one matters
+        let cycle = self.len.max(1) as f32;
+        rotate_palette(palette, self.start, self.len, (whole % cycle) as i32);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Square,
+    Triangle,
+    Sawtooth,
+}
+
+impl Waveform {
+    /// Value in `[-1, 1]` at `phase` radians. Every waveform starts its period at the same point
+    /// as a sine: square is positive for the first half period and triangle peaks at a quarter.
+    fn sample(self, phase: f32) -> f32 {
+        // Position within the current period in [0, 1)
+        let t = (phase / (2.0 * f32::consts::PI)).rem_euclid(1.0);
+        match self {
+            Waveform::Sine => phase.sin(),
+            Waveform::Square => {
+                if t < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => {
+                if t < 0.25 {
+                    4.0 * t
+                } else if t < 0.75 {
+                    2.0 - 4.0 * t
+                } else {
+                    4.0 * t - 4.0
+                }
+            }
+            Waveform::Sawtooth => 2.0 * t - 1.0,
+        }
+    }
+}
+
+/// How each sample is stored in a sound device's buffer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    #[default]
+    Pcm16,
+    Pcm24,
+    Float32,
+}
+
+impl SampleFormat {
+    pub fn bits_per_sample(self) -> u16 {
+        match self {
+            SampleFormat::Pcm16 => 16,
+            SampleFormat::Pcm24 => 24,
+            SampleFormat::Float32 => 32,
         }
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        usize::from(self.bits_per_sample() / 8)
+    }
 
-        self.current_offset += step_by;
+    /// Writes `sample` into `out` little-endian. `out` must be `bytes_per_sample` long.
+    pub fn encode(self, sample: i16, out: &mut [u8]) {
+        match self {
+            SampleFormat::Pcm16 => out.copy_from_slice(&sample.to_le_bytes()),
+            SampleFormat::Pcm24 => {
+                // The extra low byte of precision stays zero
+                let [low, high] = sample.to_le_bytes();
+                out.copy_from_slice(&[0, low, high]);
+            }
+            SampleFormat::Float32 => {
+                out.copy_from_slice(&(f32::from(sample) / 32768.0).to_le_bytes())
+            }
+        }
     }
 }
 
 pub struct SoundBuffer {
+    /// Interleaved samples. Must hold at least `sample_count * channels` entries.
     pub samples: Vec<i16>,
+    /// Number of frames (one sample per channel) to render
     pub sample_count: usize,
-    pub t_sin: f32,
+    /// Peak amplitude of the output. Use `set_volume` to keep it in range.
     pub volume: f32,
+    /// Silences the output without forgetting `volume`
+    pub muted: bool,
+    /// While `false` the tone fades out and silence is written, keeping the oscillator's phase
+    pub tone_enabled: bool,
     pub sample_rate: u16,
+    pub channels: u16,
+    pub waveform: Waveform,
+    /// Total number of frames rendered so far, used to time notes
+    pub sample_clock: u64,
+    /// Number of frames over which frequency and volume changes are spread
+    pub ramp_samples: u32,
+    pub tone_ramp: Ramp,
+    pub volume_ramp: Ramp,
+    /// Recorded sound to play instead of the synthesized tone
+    pub clip: Option<Clip>,
+    /// Extra voices mixed on top of the tone, following its volume and envelope
+    pub oscillators: Vec<Oscillator>,
+    /// Cutoff of the low-pass filter softening the synthesized waves, in Hz.
+    /// At or above half the sample rate the filter is bypassed.
+    pub filter_cutoff: f32,
+    /// Last output of the low-pass filter, carried across buffers so their edges don't click
+    pub filter_state: f32,
 }
 
-impl SoundBuffer {
-    fn render_sound(&mut self, tone_hz: u16) {
-        let wave_period = f32::from(self.sample_rate) / f32::from(tone_hz);
+/// A single voice with its own frequency, waveform and phase
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Oscillator {
+    pub frequency_hz: f32,
+    pub waveform: Waveform,
+    /// Radians, kept in `[0, 2π)`
+    pub phase: f32,
+    /// Relative to the buffer's volume, 1.0 is as loud as the tone
+    pub amplitude: f32,
+}
+
+impl Oscillator {
+    /// Value at the current phase in `[-amplitude, amplitude]`, then advances by one frame
+    fn next_sample(&mut self, sample_rate: u16) -> f32 {
+        let value = self.waveform.sample(self.phase) * self.amplitude;
+        self.phase = (self.phase
+            + 2.0 * f32::consts::PI * self.frequency_hz / f32::from(sample_rate))
+        .rem_euclid(2.0 * f32::consts::PI);
+        value
+    }
+}
+
+/// Smoothing factor of a one-pole low-pass filter: each output moves this fraction of the way
+/// from the previous output to the input. A step input therefore reaches `1 - (1 - a)^n` of its
+/// height after `n` samples, with a time constant of `1 / (2π * cutoff_hz)` seconds.
+pub fn low_pass_coefficient(cutoff_hz: f32, sample_rate: u16) -> f32 {
+    let sample_rate = f32::from(sample_rate);
+    if cutoff_hz.is_nan() || cutoff_hz >= sample_rate / 2.0 {
+        return 1.0;
+    }
+    1.0 - (-2.0 * f32::consts::PI * cutoff_hz.max(0.0) / sample_rate).exp()
+}
+
+/// Converts mixed voices to a sample, clipping instead of wrapping around when they add up past
+/// the `i16` range. NaN, e.g. from a corrupted phase, becomes silence.
+pub fn mix_sample(value: f32) -> i16 {
+    if value.is_nan() {
+        return 0;
+    }
+    value.clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16
+}
+
+/// A looping `Wav` and the current playback position within it
+pub struct Clip {
+    pub wav: Wav,
+    /// Position in frames of `wav`. Fractional when resampling.
+    position: f64,
+}
+
+impl Clip {
+    pub fn new(wav: Wav) -> Clip {
+        Clip { wav, position: 0.0 }
+    }
+}
+
+/// 16-bit PCM audio decoded from a RIFF/WAVE file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wav {
+    /// Interleaved samples, `channels` per frame
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
 
-        // TODO(sawlody) `2` is the number of channels - should be put in a variable
-        for i in (0..self.sample_count * 2).step_by(2) {
-            let sample_value = (self.t_sin.sin() * self.volume) as i16;
+impl Wav {
+    pub fn load(path: &Path) -> io::Result<Wav> {
+        Wav::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Wav> {
+        const WAVE_FORMAT_PCM: u16 = 1;
+
+        fn invalid(message: &str) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        }
+        fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+            u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+        }
+        fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        }
+
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid("Not a RIFF/WAVE file"));
+        }
 
-            self.samples[i] = sample_value;
-            self.samples[i + 1] = sample_value;
+        let mut format = None;
+        let mut data = None;
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let chunk_id = &bytes[offset..offset + 4];
+            let chunk_size = read_u32(bytes, offset + 4) as usize;
+            let chunk_start = offset + 8;
+            let chunk = bytes
+                .get(chunk_start..chunk_start.saturating_add(chunk_size))
+                .ok_or_else(|| invalid("WAV chunk extends past the end of the file"))?;
 
-            self.t_sin += 2.0 * f32::consts::PI * 1.0 / wave_period;
+            match chunk_id {
+                b"fmt " => {
+                    if chunk.len() < 16 {
+                        return Err(invalid("WAV format chunk is too short"));
+                    }
+                    // (format tag, channels, sample rate, bits per sample)
+                    format = Some((
+                        read_u16(chunk, 0),
+                        read_u16(chunk, 2),
+                        read_u32(chunk, 4),
+                        read_u16(chunk, 14),
+                    ));
+                }
+                b"data" => data = Some(chunk),
+                _ => (),
+            }
+
+            // Chunks are padded to an even size
+            offset = chunk_start + chunk_size + (chunk_size & 1);
+        }
+
+        let (format_tag, channels, sample_rate, bits_per_sample) =
+            format.ok_or_else(|| invalid("WAV file has no format chunk"))?;
+        let data = data.ok_or_else(|| invalid("WAV file has no data chunk"))?;
+
+        if format_tag != WAVE_FORMAT_PCM || bits_per_sample != 16 {
+            return Err(invalid("Only 16-bit PCM WAV files are supported"));
         }
+        if channels == 0 || sample_rate == 0 {
+            return Err(invalid("WAV file has no channels or a zero sample rate"));
+        }
+
+        let samples = data
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+
+        Ok(Wav {
+            samples,
+            sample_rate,
+            channels,
+        })
     }
 }
 
-pub fn update_and_render(
-    display_buffer: &mut DisplayBuffer,
-    sound_buffer: &mut SoundBuffer,
-    tone_hz: u16,
-) {
-    sound_buffer.render_sound(tone_hz);
-    display_buffer.step_render(1);
+/// A value that moves linearly towards its target instead of jumping to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ramp {
+    current: f32,
+    target: f32,
+    step: f32,
+}
+
+impl Ramp {
+    pub fn new(value: f32) -> Ramp {
+        Ramp {
+            current: value,
+            target: value,
+            step: 0.0,
+        }
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    /// Starts a ramp from the current value to `target` lasting `duration` steps.
+    /// Setting the target that is already being approached keeps the ramp going unchanged.
+    pub fn set_target(&mut self, target: f32, duration: u32) {
+        if target != self.target {
+            self.target = target;
+            self.step = (target - self.current).abs() / duration.max(1) as f32;
+        }
+    }
+
+    /// Advances by one step, never overshooting the target, and returns the new value
+    pub fn advance(&mut self) -> f32 {
+        if self.current < self.target {
+            self.current = (self.current + self.step).min(self.target);
+        } else {
+            self.current = (self.current - self.step).max(self.target);
+        }
+        self.current
+    }
+}
+
+/// Loudest volume that can't overflow an `i16` sample
+pub const MAX_VOLUME: f32 = i16::MAX as f32;
+
+/// Volume the platform layers start at
+pub const DEFAULT_VOLUME_DBFS: f32 = -18.0;
+
+/// The calibration tone `Input::with_test_tone` plays: concert A at half full scale
+pub const TEST_TONE_HZ: u16 = 440;
+pub const TEST_TONE_DBFS: f32 = -6.0;
+
+/// Converts a level in decibels relative to full scale into the peak amplitude of a sample, so
+/// 0 dBFS is `MAX_VOLUME` and every -6 dB roughly halves it. Levels above 0 dBFS are clamped.
+pub fn dbfs_to_amplitude(dbfs: f32) -> f32 {
+    if dbfs.is_nan() {
+        return 0.0;
+    }
+    MAX_VOLUME * 10f32.powf(dbfs.min(0.0) / 20.0)
+}
+
+/// Lowest tone that gets synthesized, around the bottom of human hearing. Lower requests are
+/// raised to it; 0 Hz would make the wave period infinite.
+pub const MIN_TONE_HZ: u16 = 20;
+
+impl SoundBuffer {
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, MAX_VOLUME);
+    }
+
+    /// Volume the output ramps towards, taking mute and the tone toggle into account
+    fn target_volume(&self) -> f32 {
+        if self.muted || !self.tone_enabled {
+            0.0
+        } else {
+            self.volume
+        }
+    }
+
+    fn render_sound(&mut self, phase: &mut f32, tone_hz: u16) {
+        // Once faded out there is nothing to synthesize. The phase is left alone so the tone
+        // picks up where it stopped, fading back in along the volume ramp.
+        if !self.tone_enabled && self.volume_ramp.current() == 0.0 {
+            self.render_silence();
+            self.sample_clock += self.sample_count as u64;
+            return;
+        }
+
+        if self.clip.is_some() {
+            self.render_clip();
+        } else {
+            self.render_with_gain(phase, tone_hz, |_| 1.0);
+        }
+    }
+
+    /// Zeroes the next `sample_count` frames without moving the clock, ramps or phases
+    fn render_silence(&mut self) {
+        let channels = usize::from(self.channels);
+        self.samples[..self.sample_count * channels]
+            .iter_mut()
+            .for_each(|sample| *sample = 0);
+    }
+
+    /// Renders exactly as many whole frames as fit in `out` and copies them there, for outputs
+    /// that ask for a set number of frames at a time, like an audio callback. Overwrites
+    /// `sample_count` and grows `samples` as needed. A trailing partial frame is zeroed.
+    pub fn render_into(&mut self, phase: &mut f32, tone_hz: u16, out: &mut [i16]) {
+        let channels = usize::from(self.channels.max(1));
+        self.sample_count = out.len() / channels;
+        let len = self.sample_count * channels;
+        if self.samples.len() < len {
+            self.samples.resize(len, 0);
+        }
+
+        self.render_sound(phase, tone_hz);
+        out[..len].copy_from_slice(&self.samples[..len]);
+        out[len..].iter_mut().for_each(|sample| *sample = 0);
+    }
+
+    /// Encodes the rendered samples from `first_sample` on (counting samples, not frames) into
+    /// `out` as `format`, as many as fit. Returns the number of samples written.
+    pub fn encode_samples(
+        &self,
+        first_sample: usize,
+        format: SampleFormat,
+        out: &mut [u8],
+    ) -> usize {
+        let rendered = (self.sample_count * usize::from(self.channels)).min(self.samples.len());
+        let samples = self.samples.get(first_sample..rendered).unwrap_or(&[]);
+        let mut written = 0;
+        for (&sample, out) in samples
+            .iter()
+            .zip(out.chunks_exact_mut(format.bytes_per_sample()))
+        {
+            format.encode(sample, out);
+            written += 1;
+        }
+        written
+    }
+
+    /// Streams the loaded clip, looping at the end. Clip samples are scaled so that full scale
+    /// maps to `volume`, like the synthesized tone.
+    fn render_clip(&mut self) {
+        self.volume_ramp
+            .set_target(self.target_volume(), self.ramp_samples);
+
+        let clip = match &mut self.clip {
+            Some(clip) => clip,
+            None => return,
+        };
+
+        let channels = usize::from(self.channels);
+        let samples = &mut self.samples[..self.sample_count * channels];
+
+        let clip_channels = usize::from(clip.wav.channels);
+        let clip_frame_count = clip.wav.samples.len() / clip_channels;
+        if clip_frame_count == 0 {
+            samples.iter_mut().for_each(|sample| *sample = 0);
+            return;
+        }
+
+        // Nearest-neighbour resampling if the clip was recorded at a different rate
+        let step = f64::from(clip.wav.sample_rate) / f64::from(self.sample_rate);
+
+        for frame in samples.chunks_exact_mut(channels) {
+            let clip_frame = &clip.wav.samples[clip.position as usize * clip_channels..];
+            let amplitude = self.volume_ramp.advance() / f32::from(i16::MAX);
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                // Extra output channels repeat the clip's last channel (e.g. mono to stereo)
+                let clip_sample = clip_frame[channel.min(clip_channels - 1)];
+                *sample = mix_sample(f32::from(clip_sample) * amplitude);
+            }
+
+            clip.position = (clip.position + step) % clip_frame_count as f64;
+            self.sample_clock += 1;
+        }
+    }
+
+    /// Renders a tone whose amplitude follows `envelope`. `note_on` and `note_off` are
+    /// `sample_clock` values; the release continues past `note_off` until it reaches silence.
+    /// `phase` is the oscillator phase in radians, advanced by the rendered frames.
+    pub fn render_note(
+        &mut self,
+        phase: &mut f32,
+        tone_hz: u16,
+        envelope: &Envelope,
+        note_on: u64,
+        note_off: Option<u64>,
+    ) {
+        let released_at = note_off.map(|note_off| note_off.saturating_sub(note_on));
+        self.render_with_gain(phase, tone_hz, |clock| {
+            if clock < note_on {
+                0.0
+            } else {
+                envelope.gain(clock - note_on, released_at)
+            }
+        });
+    }
+
+    /// `gain` is called with the `sample_clock` of every frame and scales its amplitude
+    fn render_with_gain<F: FnMut(u64) -> f32>(
+        &mut self,
+        phase: &mut f32,
+        tone_hz: u16,
+        mut gain: F,
+    ) {
+        // Glide towards the new frequency and volume instead of jumping, which would click
+        self.tone_ramp
+            .set_target(f32::from(tone_hz.max(MIN_TONE_HZ)), self.ramp_samples);
+        self.volume_ramp
+            .set_target(self.target_volume(), self.ramp_samples);
+
+        let filter_coefficient = low_pass_coefficient(self.filter_cutoff, self.sample_rate);
+
+        // A NaN or infinite phase would stay that way (and so would the filter), silencing the
+        // tone for good. Start the wave over instead.
+        if !phase.is_finite() {
+            *phase = 0.0;
+        }
+        if !self.filter_state.is_finite() {
+            self.filter_state = 0.0;
+        }
+
+        // Samples are interleaved: one frame holds a copy of the sample for every channel
+        let channels = usize::from(self.channels);
+        for frame in self.samples[..self.sample_count * channels].chunks_exact_mut(channels) {
+            let wave_period = f32::from(self.sample_rate) / self.tone_ramp.advance();
+            debug_assert!(wave_period.is_finite(), "tone ramp reached 0 Hz");
+            let amplitude = self.volume_ramp.advance() * gain(self.sample_clock);
+            let sample_rate = self.sample_rate;
+            let voices = self.waveform.sample(*phase)
+                + self
+                    .oscillators
+                    .iter_mut()
+                    .map(|oscillator| oscillator.next_sample(sample_rate))
+                    .sum::<f32>();
+            self.filter_state += filter_coefficient * (voices * amplitude - self.filter_state);
+            let sample_value = mix_sample(self.filter_state);
+            frame.iter_mut().for_each(|sample| *sample = sample_value);
+
+            // Keep the phase in [0, 2π) - an ever-growing accumulator loses precision and detunes
+            *phase = (*phase + 2.0 * f32::consts::PI * 1.0 / wave_period)
+                .rem_euclid(2.0 * f32::consts::PI);
+            self.sample_clock += 1;
+        }
+    }
+}
+
+/// Attack/decay/sustain/release amplitude envelope. Durations are measured in samples (frames)
+/// and `sustain` is the gain held after the decay, in `[0, 1]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    pub attack: u32,
+    pub decay: u32,
+    pub sustain: f32,
+    pub release: u32,
+}
+
+impl Envelope {
+    /// Gain in `[0, 1]` at `t` samples after note-on. `released_at` is the time after note-on
+    /// that the note was released, if it has been.
+    pub fn gain(&self, t: u64, released_at: Option<u64>) -> f32 {
+        match released_at {
+            Some(released_at) if t >= released_at => {
+                let since_release = t - released_at;
+                if since_release >= u64::from(self.release) {
+                    0.0
+                } else {
+                    // Fade out linearly from wherever the note was when it was released
+                    let release_gain = self.held_gain(released_at);
+                    release_gain * (1.0 - since_release as f32 / self.release as f32)
+                }
+            }
+            _ => self.held_gain(t),
+        }
+    }
+
+    /// Gain while the note is held
+    fn held_gain(&self, t: u64) -> f32 {
+        let attack = u64::from(self.attack);
+        let decay = u64::from(self.decay);
+        if t < attack {
+            t as f32 / attack as f32
+        } else if t < attack + decay {
+            let decay_progress = (t - attack) as f32 / decay as f32;
+            1.0 - (1.0 - self.sustain) * decay_progress
+        } else {
+            self.sustain
+        }
+    }
+}
+
+/// Platform-independent snapshot of a controller for one frame
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GamepadInput {
+    /// `false` when no controller is plugged in, in which case every other field is zeroed
+    pub connected: bool,
+
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+
+    pub a: bool,
+    pub b: bool,
+    pub x: bool,
+    pub y: bool,
+
+    pub start: bool,
+    pub back: bool,
+    pub left_shoulder: bool,
+    pub right_shoulder: bool,
+
+    /// `[0, 1]`
+    pub left_trigger: f32,
+    pub right_trigger: f32,
+
+    /// `[-1, 1]`, positive is right/up
+    pub left_stick_x: f32,
+    pub left_stick_y: f32,
+    pub right_stick_x: f32,
+    pub right_stick_y: f32,
+}
+
+/// Controllers the game reads at once, as many as XInput supports
+pub const MAX_GAMEPADS: usize = 4;
+
+impl GamepadInput {
+    /// Combines several controllers into one, as if they were a single pad: a button is down if
+    /// it is down on any of them, triggers take the furthest pull and sticks add up, clamped to
+    /// `[-1, 1]`. Disconnected controllers are zeroed so they add nothing.
+    pub fn combine(pads: &[GamepadInput]) -> GamepadInput {
+        pads.iter()
+            .filter(|pad| pad.connected)
+            .fold(GamepadInput::default(), |combined, pad| {
+                let stick = |a: f32, b: f32| (a + b).clamp(-1.0, 1.0);
+                GamepadInput {
+                    connected: true,
+
+                    up: combined.up || pad.up,
+                    down: combined.down || pad.down,
+                    left: combined.left || pad.left,
+                    right: combined.right || pad.right,
+
+                    a: combined.a || pad.a,
+                    b: combined.b || pad.b,
+                    x: combined.x || pad.x,
+                    y: combined.y || pad.y,
+
+                    start: combined.start || pad.start,
+                    back: combined.back || pad.back,
+                    left_shoulder: combined.left_shoulder || pad.left_shoulder,
+                    right_shoulder: combined.right_shoulder || pad.right_shoulder,
+
+                    left_trigger: combined.left_trigger.max(pad.left_trigger),
+                    right_trigger: combined.right_trigger.max(pad.right_trigger),
+
+                    left_stick_x: stick(combined.left_stick_x, pad.left_stick_x),
+                    left_stick_y: stick(combined.left_stick_y, pad.left_stick_y),
+                    right_stick_x: stick(combined.right_stick_x, pad.right_stick_x),
+                    right_stick_y: stick(combined.right_stick_y, pad.right_stick_y),
+                }
+            })
+    }
+}
+
+/// Maps a raw stick position to `[-1, 1]` on each axis using a radial deadzone: anything within
+/// `deadzone` of the center is exactly `(0.0, 0.0)` and the remaining range is rescaled so
+/// movement starts smoothly from zero at the deadzone edge.
+pub fn apply_deadzone(x: i16, y: i16, deadzone: i16) -> (f32, f32) {
+    let max_magnitude = f32::from(i16::MAX);
+    let deadzone = f32::from(deadzone.max(0));
+    let (x, y) = (f32::from(x), f32::from(y));
+
+    let magnitude = (x * x + y * y).sqrt();
+    if magnitude <= deadzone || deadzone >= max_magnitude {
+        return (0.0, 0.0);
+    }
+
+    // Diagonals can exceed the axis maximum, treat them as fully deflected
+    let scaled_magnitude = (magnitude.min(max_magnitude) - deadzone) / (max_magnitude - deadzone);
+    let scale = scaled_magnitude / magnitude;
+    ((x * scale).clamp(-1.0, 1.0), (y * scale).clamp(-1.0, 1.0))
+}
+
+/// A key as the platform layer numbers it: a virtual-key code on Windows, a keysym under X
+pub type KeyCode = u32;
+
+/// Which keys are held and which changed this frame. The platform layer feeds it key messages as
+/// they arrive and calls `end_frame` once the frame has read it, so edges last exactly one frame.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Keyboard {
+    down: HashSet<KeyCode>,
+    pressed: HashSet<KeyCode>,
+    released: HashSet<KeyCode>,
+    /// Auto-repeated key-downs this frame, for keys that step while held
+    repeats: HashMap<KeyCode, u32>,
+}
+
+impl Keyboard {
+    /// Records a key-down message. Returns whether the key went down just now rather than
+    /// auto-repeating.
+    pub fn key_down(&mut self, key: KeyCode) -> bool {
+        let is_press = self.down.insert(key);
+        if is_press {
+            self.pressed.insert(key);
+        } else {
+            *self.repeats.entry(key).or_insert(0) += 1;
+        }
+        is_press
+    }
+
+    /// Records a key-up message. One for a key that isn't down is ignored.
+    pub fn key_up(&mut self, key: KeyCode) {
+        if self.down.remove(&key) {
+            self.released.insert(key);
+        }
+    }
+
+    /// Lets go of every key, e.g. when focus moves elsewhere and their key-ups would be missed
+    pub fn release_all(&mut self) {
+        for key in self.down.drain() {
+            self.released.insert(key);
+        }
+    }
+
+    /// Forgets this frame's presses, releases and repeats, keeping what is held
+    pub fn end_frame(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+        self.repeats.clear();
+    }
+
+    pub fn is_down(&self, key: KeyCode) -> bool {
+        self.down.contains(&key)
+    }
+
+    /// Whether `key` went down since the last `end_frame`, even if it has been let go again
+    pub fn was_pressed_this_frame(&self, key: KeyCode) -> bool {
+        self.pressed.contains(&key)
+    }
+
+    /// Whether `key` went up since the last `end_frame`, even if it has been pressed again
+    pub fn was_released_this_frame(&self, key: KeyCode) -> bool {
+        self.released.contains(&key)
+    }
+
+    /// Times `key` auto-repeated since the last `end_frame`
+    pub fn repeat_count(&self, key: KeyCode) -> u32 {
+        self.repeats.get(&key).copied().unwrap_or(0)
+    }
+}
+
+/// Cursor position in backbuffer coordinates and button state for one frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseInput {
+    pub x: i32,
+    pub y: i32,
+    pub left: bool,
+    pub right: bool,
+}
+
+/// Everything the platform layer feeds the game for one frame. Replaying the same sequence of
+/// `Input`s from the same `ReplaySnapshot` renders the same frames.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Input {
+    /// Base tone before pitch bend
+    pub tone_hz: u16,
+    pub volume: f32,
+    pub muted: bool,
+    pub tone_enabled: bool,
+    /// Whether each of the 2nd to 6th harmonics of the tone joins it, making a chord
+    pub harmonics: [bool; HARMONIC_COUNT],
+    /// Every controller slot combined by `GamepadInput::combine`
+    pub gamepad: GamepadInput,
+    /// Each controller slot on its own, disconnected ones left at default
+    pub gamepads: [GamepadInput; MAX_GAMEPADS],
+    pub mouse: MouseInput,
+    /// Keys as they stood at the end of the frame's messages, in the platform's key codes
+    pub keyboard: Keyboard,
+    /// Characters typed since the previous frame, in order. Backspace comes through as `'\u{8}'`
+    /// and Enter as `'\r'`, other control characters are left out.
+    pub text: String,
+    /// Freezes the game: no updates run and the sound goes silent until it is cleared again
+    pub paused: bool,
+    /// Updates to run this frame despite being paused, one per press of the step key
+    pub step_frames: u32,
+    /// Seconds since the previous frame
+    pub dt: f32,
+}
+
+/// How fast holding a tone key slides the tone, in Hz per second
+pub const TONE_SLIDE_HZ_PER_SECOND: f32 = 512.0;
+
+/// Which tone keys the platform layer has seen held down, turned into a smooth slide of the tone
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ToneKeys {
+    pub raise_held: bool,
+    pub lower_held: bool,
+    /// Slide not yet applied since the tone is whole hertz, carried so short frames add up
+    remainder: f32,
+}
+
+impl ToneKeys {
+    /// Slides `tone_hz` by `dt` seconds' worth of the held keys, stopping at `MIN_TONE_HZ` and
+    /// `u16::MAX`. Holding both keys or neither leaves it alone.
+    pub fn advance(&mut self, tone_hz: u16, dt: f32) -> u16 {
+        let direction = f32::from(i8::from(self.raise_held) - i8::from(self.lower_held));
+        if direction == 0.0 || dt.is_nan() || dt <= 0.0 {
+            self.remainder = 0.0;
+            return tone_hz;
+        }
+
+        let slide = self.remainder + direction * TONE_SLIDE_HZ_PER_SECOND * dt;
+        let whole = slide.trunc();
+        self.remainder = slide - whole;
+        (f32::from(tone_hz) + whole).clamp(f32::from(MIN_TONE_HZ), f32::from(u16::MAX)) as u16
+    }
+}
+
+/// Seconds simulated by one `update`
+pub const FIXED_DT: f32 = 1.0 / 120.0;
+/// Updates run per frame at most. Time beyond that is dropped so a long stall (e.g. a breakpoint)
+/// doesn't snowball into ever longer frames spent catching up.
+const MAX_STEPS_PER_FRAME: u32 = 8;
+
+/// Turns variable frame durations into a whole number of fixed-length updates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixedTimestep {
+    pub fixed_dt: f32,
+    /// Real time not yet simulated, in `[0, fixed_dt)` between frames
+    accumulator: f32,
+}
+
+impl Default for FixedTimestep {
+    fn default() -> FixedTimestep {
+        FixedTimestep::new(FIXED_DT)
+    }
+}
+
+impl FixedTimestep {
+    pub fn new(fixed_dt: f32) -> FixedTimestep {
+        assert!(fixed_dt > 0.0);
+        FixedTimestep {
+            fixed_dt,
+            accumulator: 0.0,
+        }
+    }
+
+    /// Adds `elapsed` seconds of real time and returns how many updates to run for them
+    pub fn advance(&mut self, elapsed: f32) -> u32 {
+        self.accumulator += elapsed.max(0.0);
+        let steps = (self.accumulator / self.fixed_dt) as u32;
+        self.accumulator -= steps as f32 * self.fixed_dt;
+        if steps > MAX_STEPS_PER_FRAME {
+            self.accumulator = 0.0;
+            return MAX_STEPS_PER_FRAME;
+        }
+        steps
+    }
+
+    /// How far between the last update and the next one the current frame is, in `[0, 1)`
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.fixed_dt).clamp(0.0, 1.0)
+    }
+}
+
+/// Weight of the newest frame time in `FrameRateCounter`'s average. Small enough that the
+/// displayed number settles instead of flickering with every frame.
+pub const FRAME_TIME_SMOOTHING: f32 = 0.05;
+
+/// Frames per second from an exponential moving average of measured frame times
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FrameRateCounter {
+    /// `None` until the first frame is recorded, which then seeds the average
+    average_frame_seconds: Option<f32>,
+}
+
+impl FrameRateCounter {
+    /// Folds in the duration of one frame. Non-positive or non-finite durations are ignored.
+    pub fn record(&mut self, frame_seconds: f32) {
+        if !frame_seconds.is_finite() || frame_seconds <= 0.0 {
+            return;
+        }
+        self.average_frame_seconds = Some(match self.average_frame_seconds {
+            Some(average) => average + FRAME_TIME_SMOOTHING * (frame_seconds - average),
+            None => frame_seconds,
+        });
+    }
+
+    /// Smoothed frame rate, or `None` before any frame has been recorded
+    pub fn fps(&self) -> Option<f32> {
+        self.average_frame_seconds.map(|average| 1.0 / average)
+    }
+}
+
+/// Frames `FrameTimeHistory` keeps by default, one bar each in the graph
+pub const FRAME_HISTORY_LEN: usize = 128;
+/// Size of the frame time graph in pixels. A frame that takes exactly the target time reaches
+/// halfway up, so frames up to twice as long still fit.
+pub const FRAME_GRAPH_BAR_WIDTH: i32 = 2;
+pub const FRAME_GRAPH_HEIGHT: i32 = 64;
+
+/// The most recent frame times in a ring buffer, drawn as a bar graph for profiling
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameTimeHistory {
+    /// Grows up to the capacity, then the oldest entry is overwritten
+    frame_seconds: Vec<f32>,
+    capacity: usize,
+    /// Where the next frame goes once full, which is also the oldest one
+    next: usize,
+}
+
+impl Default for FrameTimeHistory {
+    fn default() -> FrameTimeHistory {
+        FrameTimeHistory::new(FRAME_HISTORY_LEN)
+    }
+}
+
+impl FrameTimeHistory {
+    /// Keeps the last `capacity` frames, at least one
+    pub fn new(capacity: usize) -> FrameTimeHistory {
+        let capacity = capacity.max(1);
+        FrameTimeHistory {
+            frame_seconds: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    /// Adds the duration of one frame, dropping the oldest once full. Negative or non-finite
+    /// durations are ignored.
+    pub fn record(&mut self, frame_seconds: f32) {
+        if !frame_seconds.is_finite() || frame_seconds < 0.0 {
+            return;
+        }
+        if self.frame_seconds.len() < self.capacity {
+            self.frame_seconds.push(frame_seconds);
+        } else {
+            self.frame_seconds[self.next] = frame_seconds;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    pub fn len(&self) -> usize {
+        self.frame_seconds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frame_seconds.is_empty()
+    }
+
+    /// Recorded frame times, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = f32> + '_ {
+        let (newer, older) = if self.frame_seconds.len() < self.capacity {
+            (&self.frame_seconds[..], &[][..])
+        } else {
+            self.frame_seconds.split_at(self.next)
+        };
+        older.iter().chain(newer.iter()).copied()
+    }
+
+    /// Draws one bar per recorded frame, oldest on the left, with the bottom-left corner of the
+    /// graph at `(left, bottom)`. A line marks `target_seconds`; bars past it are red.
+    pub fn draw(&self, buffer: &mut DisplayBuffer, left: i32, bottom: i32, target_seconds: f32) {
+        let reference_height = FRAME_GRAPH_HEIGHT / 2;
+        let width = self.capacity as i32 * FRAME_GRAPH_BAR_WIDTH;
+        buffer.draw_rectangle(
+            left,
+            bottom - FRAME_GRAPH_HEIGHT,
+            left + width,
+            bottom,
+            Pixel::from_rgba(0, 0, 0, 255),
+        );
+
+        for (index, frame_seconds) in self.iter().enumerate() {
+            let height = frame_bar_height(
+                frame_seconds,
+                target_seconds,
+                reference_height,
+                FRAME_GRAPH_HEIGHT,
+            );
+            let color = if height > reference_height {
+                Pixel::from_rgba(255, 64, 64, 255)
+            } else {
+                Pixel::from_rgba(64, 255, 64, 255)
+            };
+            let x = left + index as i32 * FRAME_GRAPH_BAR_WIDTH;
+            buffer.draw_rectangle(x, bottom - height, x + FRAME_GRAPH_BAR_WIDTH, bottom, color);
+        }
+
+        let reference_y = bottom - reference_height;
+        buffer.draw_rectangle(
+            left,
+            reference_y,
+            left + width,
+            reference_y + 1,
+            Pixel::from_rgba(255, 255, 255, 255),
+        );
+    }
+}
+
+/// Height in pixels of the bar for a frame lasting `frame_seconds`, scaled so that
+/// `target_seconds` comes out at `reference_height` and clamped to `[0, max_height]`
+pub fn frame_bar_height(
+    frame_seconds: f32,
+    target_seconds: f32,
+    reference_height: i32,
+    max_height: i32,
+) -> i32 {
+    if target_seconds.is_nan() || target_seconds <= 0.0 || !frame_seconds.is_finite() {
+        return 0;
+    }
+    let height = (frame_seconds / target_seconds * reference_height as f32).round();
+    (height.max(0.0) as i32).min(max_height.max(0))
+}
+
+impl Input {
+    /// Replaces whatever the player set the sound to with a plain sine at `TEST_TONE_HZ` and
+    /// `TEST_TONE_DBFS`, for calibrating speakers and headphones. Controllers are dropped too so
+    /// they can't bend the pitch. The platform should also bypass the sound buffer's filter.
+    pub fn with_test_tone(self) -> Input {
+        Input {
+            tone_hz: TEST_TONE_HZ,
+            volume: dbfs_to_amplitude(TEST_TONE_DBFS),
+            muted: false,
+            tone_enabled: true,
+            harmonics: [false; HARMONIC_COUNT],
+            gamepad: GamepadInput::default(),
+            gamepads: [GamepadInput::default(); MAX_GAMEPADS],
+            ..self
+        }
+    }
+}
+
+/// How fast a rumble fades by default, in strength per second: a full one lasts a quarter second
+pub const DEFAULT_RUMBLE_DECAY_PER_SECOND: f32 = 4.0;
+
+/// Controller vibration the game asks for, each motor from 0 (off) to 1 (full). Setting a motor to
+/// 1.0 starts a rumble, which then fades out by itself as `decay` is called.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RumbleState {
+    /// The low-frequency motor
+    pub left: f32,
+    /// The high-frequency motor
+    pub right: f32,
+    /// Strength each motor loses per second
+    pub decay_per_second: f32,
+}
+
+impl Default for RumbleState {
+    fn default() -> RumbleState {
+        RumbleState {
+            left: 0.0,
+            right: 0.0,
+            decay_per_second: DEFAULT_RUMBLE_DECAY_PER_SECOND,
+        }
+    }
+}
+
+impl RumbleState {
+    /// Fades both motors by `dt` seconds' worth of decay, stopping at zero
+    pub fn decay(&mut self, dt: f32) {
+        let amount = self.decay_per_second * dt.max(0.0);
+        self.left = (self.left - amount).max(0.0);
+        self.right = (self.right - amount).max(0.0);
+    }
+
+    /// Motor speeds as XInput takes them, `0..=u16::MAX`
+    pub fn motor_speeds(&self) -> (u16, u16) {
+        let speed = |strength: f32| (strength.clamp(0.0, 1.0) * f32::from(u16::MAX)).round() as u16;
+        (speed(self.left), speed(self.right))
+    }
+}
+
+/// Platform-independent state the game carries from one frame to the next.
+/// The platform layer owns one and passes it to every `update` and `render` call.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GameState {
+    /// Scroll position of the gradient in pixels, kept in `[0, 256)` since channels wrap anyway
+    pub scroll_offset: f32,
+    /// `scroll_offset` before the last update, rendering interpolates from it
+    pub previous_scroll_offset: f32,
+    /// Tone played last frame, after pitch bend
+    pub tone_hz: u16,
+    /// Oscillator phase in radians, kept in `[0, 2π)`
+    pub t_sin: f32,
+    pub timestep: FixedTimestep,
+    /// Seconds of the intro fade played so far, stops counting at `INTRO_SECONDS`
+    pub intro_elapsed: f32,
+    /// Vibration for the platform layer to send to the controller
+    pub rumble: RumbleState,
+}
+
+/// The parts of the game that carry over between frames, restored when a replay loops
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplaySnapshot {
+    game_state: GameState,
+    sample_clock: u64,
+    tone_ramp: Ramp,
+    volume_ramp: Ramp,
+    clip_position: Option<f64>,
+    oscillators: Vec<Oscillator>,
+    filter_state: f32,
+}
+
+impl ReplaySnapshot {
+    pub fn capture(game_state: &GameState, sound_buffer: &SoundBuffer) -> ReplaySnapshot {
+        ReplaySnapshot {
+            game_state: *game_state,
+            sample_clock: sound_buffer.sample_clock,
+            tone_ramp: sound_buffer.tone_ramp,
+            volume_ramp: sound_buffer.volume_ramp,
+            clip_position: sound_buffer.clip.as_ref().map(|clip| clip.position),
+            oscillators: sound_buffer.oscillators.clone(),
+            filter_state: sound_buffer.filter_state,
+        }
+    }
+
+    pub fn restore(&self, game_state: &mut GameState, sound_buffer: &mut SoundBuffer) {
+        *game_state = self.game_state;
+        sound_buffer.sample_clock = self.sample_clock;
+        sound_buffer.tone_ramp = self.tone_ramp;
+        sound_buffer.volume_ramp = self.volume_ramp;
+        sound_buffer.oscillators.clone_from(&self.oscillators);
+        sound_buffer.filter_state = self.filter_state;
+        if let (Some(clip), Some(position)) = (&mut sound_buffer.clip, self.clip_position) {
+            clip.position = position;
+        }
+    }
+}
+
+enum ReplayState {
+    Idle,
+    Recording {
+        snapshot: ReplaySnapshot,
+        inputs: Vec<Input>,
+    },
+    Playing {
+        snapshot: ReplaySnapshot,
+        inputs: Vec<Input>,
+        next: usize,
+    },
+}
+
+/// Records per-frame input and plays it back in a loop, Handmade Hero style
+pub struct InputReplay {
+    state: ReplayState,
+}
+
+impl Default for InputReplay {
+    fn default() -> InputReplay {
+        InputReplay {
+            state: ReplayState::Idle,
+        }
+    }
+}
+
+impl InputReplay {
+    pub fn is_recording(&self) -> bool {
+        matches!(self.state, ReplayState::Recording { .. })
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, ReplayState::Playing { .. })
+    }
+
+    /// Idle starts recording, recording starts looping playback and playback stops.
+    /// An empty recording goes straight back to idle.
+    pub fn toggle(&mut self, game_state: &mut GameState, sound_buffer: &mut SoundBuffer) {
+        self.state = match std::mem::replace(&mut self.state, ReplayState::Idle) {
+            ReplayState::Idle => ReplayState::Recording {
+                snapshot: ReplaySnapshot::capture(game_state, sound_buffer),
+                inputs: Vec::new(),
+            },
+            ReplayState::Recording { inputs, .. } if inputs.is_empty() => ReplayState::Idle,
+            ReplayState::Recording { snapshot, inputs } => {
+                snapshot.restore(game_state, sound_buffer);
+                ReplayState::Playing {
+                    snapshot,
+                    inputs,
+                    next: 0,
+                }
+            }
+            ReplayState::Playing { .. } => ReplayState::Idle,
+        };
+    }
+
+    /// Returns the input the game should see this frame: `input` itself unless playing back.
+    /// Rewinds the game to the recording's start each time playback loops.
+    pub fn process(
+        &mut self,
+        input: Input,
+        game_state: &mut GameState,
+        sound_buffer: &mut SoundBuffer,
+    ) -> Input {
+        match &mut self.state {
+            ReplayState::Idle => input,
+            ReplayState::Recording { inputs, .. } => {
+                inputs.push(input.clone());
+                input
+            }
+            ReplayState::Playing {
+                snapshot,
+                inputs,
+                next,
+            } => {
+                if *next == inputs.len() {
+                    snapshot.restore(game_state, sound_buffer);
+                    *next = 0;
+                }
+                *next += 1;
+                inputs[*next - 1].clone()
+            }
+        }
+    }
+}
+
+/// Number of harmonics that can be layered on the tone
+pub const HARMONIC_COUNT: usize = 5;
+
+/// Keeps one oscillator per harmonic in tune with the tone, silencing the disabled ones.
+/// Their phases carry over between frames so toggling one doesn't disturb the others.
+fn update_harmonics(sound_buffer: &mut SoundBuffer, tone_hz: u16, harmonics: &[bool]) {
+    sound_buffer.oscillators.resize(
+        harmonics.len(),
+        Oscillator {
+            frequency_hz: 0.0,
+            waveform: Waveform::Sine,
+            phase: 0.0,
+            amplitude: 0.0,
+        },
+    );
+    for (index, (oscillator, &enabled)) in sound_buffer
+        .oscillators
+        .iter_mut()
+        .zip(harmonics)
+        .enumerate()
+    {
+        let harmonic = (index + 2) as f32;
+        oscillator.frequency_hz = f32::from(tone_hz) * harmonic;
+        oscillator.waveform = sound_buffer.waveform;
+        // Higher harmonics are quieter, as in most instruments
+        oscillator.amplitude = if enabled { 1.0 / harmonic } else { 0.0 };
+    }
+}
+
+/// How far a fully tilted left stick bends the tone
+const PITCH_BEND_HZ: f32 = 256.0;
+
+/// Length of the fade in from black at startup
+const INTRO_SECONDS: f32 = 1.0;
+
+/// Pixels per second
+const GRADIENT_SCROLL_SPEED: f32 = 60.0;
+
+/// Advances `game_state` by `dt` seconds. Called with `FixedTimestep::fixed_dt` zero or more
+/// times per frame, so nothing here may depend on the frame rate.
+pub fn update(game_state: &mut GameState, input: &Input, dt: f32) {
+    // The first controller's left stick bends the pitch up as it is pushed right, the second's
+    // bends it down, so two players can pull against each other
+    let bend = input.gamepads[0].left_stick_x - input.gamepads[1].left_stick_x;
+    game_state.tone_hz = (f32::from(input.tone_hz) + bend * PITCH_BEND_HZ)
+        .clamp(f32::from(MIN_TONE_HZ), f32::from(u16::MAX)) as u16;
+
+    game_state.previous_scroll_offset = game_state.scroll_offset;
+    game_state.scroll_offset =
+        (game_state.scroll_offset + GRADIENT_SCROLL_SPEED * dt).rem_euclid(256.0);
+
+    game_state.intro_elapsed = (game_state.intro_elapsed + dt).min(INTRO_SECONDS);
+
+    game_state.rumble.decay(dt);
+    // Buzz while A is held, fading out once it's let go
+    if input.gamepad.a {
+        game_state.rumble.left = 1.0;
+        game_state.rumble.right = 1.0;
+    }
+}
+
+/// Draws a scene into the backbuffer
+pub trait Renderer {
+    /// `dt` is the seconds since the previous frame
+    fn render(&mut self, buffer: &mut DisplayBuffer, dt: f32);
+}
+
+/// The scrolling gradient, with its own horizontal and vertical scroll so it can move in any
+/// direction. Offsets are in pixels, kept in `[0, 256)` since channels wrap anyway.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GradientRenderer {
+    pub offset_x: f32,
+    pub offset_y: f32,
+    /// Pixels per second each render moves the offsets by
+    pub vel_x: f32,
+    pub vel_y: f32,
+}
+
+impl GradientRenderer {
+    /// A gradient scrolling by itself at `vel_x` and `vel_y` pixels per second
+    pub fn scrolling(vel_x: f32, vel_y: f32) -> GradientRenderer {
+        GradientRenderer {
+            vel_x,
+            vel_y,
+            ..GradientRenderer::default()
+        }
+    }
+
+    /// The gradient as it stands `alpha` of the way from the previous update to the last, standing
+    /// still since `update` does the scrolling
+    pub fn interpolated(game_state: &GameState, alpha: f32) -> GradientRenderer {
+        // The gradient only scrolls forward, so going the long way round the wrap is never right
+        let scrolled =
+            (game_state.scroll_offset - game_state.previous_scroll_offset).rem_euclid(256.0);
+        let offset = (game_state.previous_scroll_offset + scrolled * alpha).rem_euclid(256.0);
+        GradientRenderer {
+            offset_x: offset,
+            offset_y: offset,
+            ..GradientRenderer::default()
+        }
+    }
+
+    /// Moves the offsets by `dt` seconds of their velocities. Negative or NaN `dt` is ignored.
+    pub fn advance(&mut self, dt: f32) {
+        let dt = dt.max(0.0);
+        self.offset_x = (self.offset_x + self.vel_x * dt).rem_euclid(256.0);
+        self.offset_y = (self.offset_y + self.vel_y * dt).rem_euclid(256.0);
+    }
+}
+
+impl Renderer for GradientRenderer {
+    fn render(&mut self, buffer: &mut DisplayBuffer, dt: f32) {
+        self.advance(dt);
+        buffer.render_gradient_xy(self.offset_x, self.offset_y);
+    }
+}
+
+/// Small, fast pseudo-random numbers (xorshift32) for game logic. Not for anything that needs to
+/// be unpredictable, but the same seed always gives the same sequence on every platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    /// A zero seed, which xorshift can't leave, is treated as 1
+    pub fn new(seed: u32) -> Rng {
+        Rng { state: seed.max(1) }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// Uniform in `[0, 1)`
+    pub fn next_f32(&mut self) -> f32 {
+        // The top 24 bits fit an f32 exactly, keeping the result below 1
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    /// Uniform in `[min, max)`
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + self.next_f32() * (max - min)
+    }
+}
+
+/// Slowest and fastest a star drifts, in pixels per second
+const STAR_MIN_SPEED: f32 = 16.0;
+const STAR_MAX_SPEED: f32 = 128.0;
+
+/// One point of a `StarfieldRenderer`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Star {
+    /// Position in pixels, kept inside the buffer
+    pub x: f32,
+    pub y: f32,
+    /// Pixels per second, negative towards the left
+    pub velocity_x: f32,
+}
+
+/// Stars drifting left across a black background at different speeds, wrapping round the edges.
+/// Each render moves them by that frame's `dt`, so the scene keeps its own state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StarfieldRenderer {
+    pub stars: Vec<Star>,
+}
+
+impl StarfieldRenderer {
+    /// `count` stars spread over a `width` x `height` buffer. The same `seed` always places them
+    /// the same way.
+    pub fn new(seed: u32, count: usize, width: i32, height: i32) -> StarfieldRenderer {
+        let mut rng = Rng::new(seed);
+        let stars = (0..count)
+            .map(|_| Star {
+                x: rng.range(0.0, width.max(0) as f32),
+                y: rng.range(0.0, height.max(0) as f32),
+                velocity_x: -rng.range(STAR_MIN_SPEED, STAR_MAX_SPEED),
+            })
+            .collect();
+        StarfieldRenderer { stars }
+    }
+}
+
+impl Renderer for StarfieldRenderer {
+    fn render(&mut self, buffer: &mut DisplayBuffer, dt: f32) {
+        buffer.clear(Pixel::from_rgba(0, 0, 0, 255));
+        if buffer.width <= 0 || buffer.height <= 0 {
+            return;
+        }
+
+        let (width, height) = (buffer.width as f32, buffer.height as f32);
+        for star in self.stars.iter_mut() {
+            // Wrapped against the current size, so stars stay on screen after a resize
+            star.x = (star.x + star.velocity_x * dt).rem_euclid(width);
+            star.y = star.y.rem_euclid(height);
+            // Faster stars look nearer, so brighter
+            let brightness = (255.0 * -star.velocity_x / STAR_MAX_SPEED) as u8;
+            buffer.set_pixel(
+                star.x as i32,
+                star.y as i32,
+                Pixel::from_rgba(brightness, brightness, brightness, 255),
+            );
+        }
+    }
+}
+
+/// Renders one frame into the buffers, `alpha` of the way from the previous update to the last.
+/// Sound keeps its own clock since the platform asks for as many samples as the device needs.
+pub fn render(
+    game_state: &mut GameState,
+    input: &Input,
+    alpha: f32,
+    display_buffer: &mut DisplayBuffer,
+    sound_buffer: &mut SoundBuffer,
+) {
+    let mut scene = GradientRenderer::interpolated(game_state, alpha);
+    render_with(game_state, input, display_buffer, sound_buffer, &mut scene);
+}
+
+/// Like `render`, but draws `scene` instead of the gradient. The scene is passed in rather than
+/// kept in `GameState`, which outlives the game DLL and so can't hold pointers into its code.
+pub fn render_with<R: Renderer + ?Sized>(
+    game_state: &mut GameState,
+    input: &Input,
+    display_buffer: &mut DisplayBuffer,
+    sound_buffer: &mut SoundBuffer,
+    scene: &mut R,
+) {
+    sound_buffer.set_volume(input.volume);
+    sound_buffer.muted = input.muted;
+    sound_buffer.tone_enabled = input.tone_enabled;
+    update_harmonics(sound_buffer, game_state.tone_hz, &input.harmonics);
+    // Paused sound holds its phase so the tone picks up where it stopped
+    if input.paused {
+        sound_buffer.render_silence();
+    } else {
+        sound_buffer.render_sound(&mut game_state.t_sin, game_state.tone_hz);
+    }
+
+    // A paused scene is drawn as it was, time doesn't pass for it either
+    scene.render(display_buffer, if input.paused { 0.0 } else { input.dt });
+    if game_state.intro_elapsed < INTRO_SECONDS {
+        display_buffer.fade_from_black(game_state.intro_elapsed / INTRO_SECONDS);
+    }
+
+    let mouse = &input.mouse;
+
+    // Cursor dot, red while a button is held
+    let cursor_color = if mouse.left || mouse.right {
+        Pixel::from_rgba(255, 0, 0, 255)
+    } else {
+        Pixel::from_rgba(255, 255, 255, 255)
+    };
+    display_buffer.draw_circle(mouse.x, mouse.y, 3, cursor_color);
+}
+
+/// Runs one whole frame the way the platform layer does: the `update`s that fall due by
+/// `GameState::due_updates`, then one `render`. Needs nothing but the buffers, so the game can be
+/// driven headless. Returns the number of updates that ran.
+///
+/// A paused frame runs no updates, or just `input.step_frames`, and renders silence. Without steps
+/// it leaves `game_state` exactly as it was, so unpausing carries on from the same point.
+pub fn update_and_render(
+    game_state: &mut GameState,
+    input: &Input,
+    display_buffer: &mut DisplayBuffer,
+    sound_buffer: &mut SoundBuffer,
+) -> u32 {
+    let steps = game_state.due_updates(input);
+    let fixed_dt = game_state.timestep.fixed_dt;
+    for _ in 0..steps {
+        update(game_state, input, fixed_dt);
+    }
+    let alpha = game_state.timestep.alpha();
+    render(game_state, input, alpha, display_buffer, sound_buffer);
+    steps
+}
+
+impl GameState {
+    /// Advances the timestep by `input.dt` and returns how many `update`s fall due. While paused
+    /// the timestep holds still and only the `input.step_frames` asked for do, each a whole
+    /// `fixed_dt`, while rendering keeps interpolating by the same fraction.
+    pub fn due_updates(&mut self, input: &Input) -> u32 {
+        if input.paused {
+            input.step_frames
+        } else {
+            self.timestep.advance(input.dt)
+        }
+    }
+
+    /// `update_and_render` on this state
+    pub fn tick(
+        &mut self,
+        input: &Input,
+        display_buffer: &mut DisplayBuffer,
+        sound_buffer: &mut SoundBuffer,
+    ) -> u32 {
+        update_and_render(self, input, display_buffer, sound_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rng;
+    use super::*;
+    use proptest::prelude::*;
+
+    /// A `width` x `height` buffer with both frames black and transparent
+    fn buffer(width: i32, height: i32) -> DisplayBuffer {
+        let len = width as usize * height as usize;
+        DisplayBuffer {
+            back: vec![Pixel::default(); len],
+            front: vec![Pixel::default(); len],
+            gradient_mode: GradientMode::FullRgb,
+            blend_mode: BlendMode::Linear,
+            width,
+            height,
+        }
+    }
+
+    /// Coordinates of every pixel of the backbuffer that isn't left at `Pixel::default()`, row by
+    /// row
+    fn touched(display: &DisplayBuffer) -> Vec<(i32, i32)> {
+        (0..display.height)
+            .flat_map(|y| (0..display.width).map(move |x| (x, y)))
+            .filter(|&(x, y)| display.get_pixel(x, y) != Some(Pixel::default()))
+            .collect()
+    }
+
+    /// Coordinates of the rectangle `xs` x `ys`, row by row like `touched`
+    fn cells(xs: std::ops::Range<i32>, ys: std::ops::Range<i32>) -> Vec<(i32, i32)> {
+        ys.flat_map(|y| xs.clone().map(move |x| (x, y))).collect()
+    }
+
+    const WHITE: Pixel = Pixel {
+        b: 255,
+        g: 255,
+        r: 255,
+        a: 255,
+    };
+
+    #[test]
+    fn green_gradient_is_x_xor_y() {
+        let mut display = buffer(4, 2);
+        display.gradient_mode = GradientMode::GreenOnly;
+        display.render_gradient(0.0);
+        for (i, pixel) in display.back.iter().enumerate() {
+            let (x, y) = (i % 4, i / 4);
+            assert_eq!(pixel.g, (x ^ y) as u8, "green at ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn needs_resize_only_for_a_new_nonempty_size() {
+        let display = buffer(4, 2);
+        assert!(!display.needs_resize(4, 2));
+        assert!(display.needs_resize(5, 2));
+        assert!(display.needs_resize(4, 3));
+        // A minimized window has an empty client area
+        assert!(!display.needs_resize(0, 0));
+        assert!(!display.needs_resize(4, 0));
+        assert!(!display.needs_resize(-1, 2));
+
+        // Same size, but the pixels were never allocated
+        let unallocated = DisplayBuffer {
+            back: Vec::new(),
+            front: Vec::new(),
+            ..buffer(4, 2)
+        };
+        assert!(unallocated.needs_resize(4, 2));
+    }
+
+    #[test]
+    fn pixel_packs_as_argb() {
+        assert_eq!(
+            Pixel::from_rgba(0x11, 0x22, 0x33, 0x44).to_u32(),
+            0x4411_2233
+        );
+        assert_eq!(
+            Pixel::from_u32(0x4411_2233),
+            Pixel::from_rgba(0x11, 0x22, 0x33, 0x44)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn pixel_round_trips_through_u32(r: u8, g: u8, b: u8, a: u8) {
+            let pixel = Pixel::from_rgba(r, g, b, a);
+            prop_assert_eq!(Pixel::from_u32(pixel.to_u32()), pixel);
+        }
+
+        #[test]
+        fn u32_round_trips_through_pixel(value: u32) {
+            prop_assert_eq!(Pixel::from_u32(value).to_u32(), value);
+        }
+    }
+
+    #[test]
+    fn clear_fills_every_pixel() {
+        let mut display = buffer(3, 3);
+        let color = Pixel::from_rgba(10, 20, 30, 255);
+        display.clear(color);
+        for &(x, y) in [(0, 0), (2, 0), (0, 2), (2, 2), (1, 1)].iter() {
+            assert_eq!(display.get_pixel(x, y), Some(color));
+        }
+        assert!(display.back.iter().all(|&pixel| pixel == color));
+        assert_eq!(touched(&display).len(), 9);
+    }
+
+    #[test]
+    fn rectangle_inside_the_buffer() {
+        let mut display = buffer(6, 6);
+        display.draw_rectangle(1, 2, 4, 4, WHITE);
+        assert_eq!(touched(&display), cells(1..4, 2..4));
+    }
+
+    #[test]
+    fn rectangle_clipped_on_each_edge() {
+        let cases = [
+            ((-3, 1, 2, 3), cells(0..2, 1..3)),
+            ((4, 1, 10, 3), cells(4..6, 1..3)),
+            ((1, -5, 3, 2), cells(1..3, 0..2)),
+            ((1, 4, 3, 99), cells(1..3, 4..6)),
+            ((i32::MIN, i32::MIN, i32::MAX, i32::MAX), cells(0..6, 0..6)),
+        ];
+        for ((min_x, min_y, max_x, max_y), expected) in cases.iter() {
+            let mut display = buffer(6, 6);
+            display.draw_rectangle(*min_x, *min_y, *max_x, *max_y, WHITE);
+            assert_eq!(&touched(&display), expected);
+        }
+    }
+
+    #[test]
+    fn rectangle_outside_the_buffer_draws_nothing() {
+        let mut display = buffer(6, 6);
+        display.draw_rectangle(-5, -5, 0, 6, WHITE);
+        display.draw_rectangle(6, 0, 9, 6, WHITE);
+        display.draw_rectangle(0, -4, 6, 0, WHITE);
+        display.draw_rectangle(0, 6, 6, 9, WHITE);
+        // Inverted corners are empty too
+        display.draw_rectangle(4, 4, 2, 2, WHITE);
+        assert!(touched(&display).is_empty());
+    }
+
+    #[test]
+    fn origin_is_the_first_pixel_in_memory() {
+        // The DIB is top-down, so memory starts with the top row
+        let mut display = buffer(3, 2);
+        display.set_pixel(0, 0, WHITE);
+        assert_eq!(display.back[0], WHITE);
+        assert_eq!(touched(&display), [(0, 0)]);
+
+        display.set_pixel(2, 1, WHITE);
+        assert_eq!(display.back[5], WHITE);
+    }
+
+    #[test]
+    fn full_rgb_gradient_channels() {
+        let mut display = buffer(300, 3);
+        display.render_gradient(2.0);
+        let pixel = display.get_pixel(3, 1).unwrap();
+        assert_eq!((pixel.r, pixel.g, pixel.b), (5, (3 ^ 1) - 2, 3));
+        // Red wraps every 256 columns
+        let pixel = display.get_pixel(260, 2).unwrap();
+        assert_eq!((pixel.r, pixel.g, pixel.b), (6, ((260 ^ 2) - 2) as u8, 4));
+
+        display.gradient_mode = GradientMode::GreenOnly;
+        display.render_gradient(2.0);
+        let pixel = display.get_pixel(3, 1).unwrap();
+        assert_eq!((pixel.r, pixel.g, pixel.b), (0, (3 ^ 1) - 2, 0));
+    }
+
+    /// A 2x2 sprite whose pixels have red 1 to 4, row by row
+    fn numbered_sprite() -> Vec<Pixel> {
+        (1..=4).map(|n| Pixel::from_rgba(n, 0, 0, 255)).collect()
+    }
+
+    /// The red channel of every touched pixel, with its coordinates
+    fn touched_red(display: &DisplayBuffer) -> Vec<((i32, i32), u8)> {
+        touched(display)
+            .into_iter()
+            .map(|(x, y)| ((x, y), display.get_pixel(x, y).unwrap().r))
+            .collect()
+    }
+
+    #[test]
+    fn blit_inside_the_buffer() {
+        let mut display = buffer(6, 6);
+        display.blit(&numbered_sprite(), 2, 2, 2, 2);
+        assert_eq!(
+            touched_red(&display),
+            [((2, 2), 1), ((3, 2), 2), ((2, 3), 3), ((3, 3), 4)]
+        );
+    }
+
+    #[test]
+    fn blit_clipped_off_the_left() {
+        let mut display = buffer(6, 6);
+        display.blit(&numbered_sprite(), 2, 2, -1, 1);
+        assert_eq!(touched_red(&display), [((0, 1), 2), ((0, 2), 4)]);
+    }
+
+    #[test]
+    fn blit_clipped_off_the_bottom() {
+        let mut display = buffer(6, 6);
+        display.blit(&numbered_sprite(), 2, 2, 3, 5);
+        assert_eq!(touched_red(&display), [((3, 5), 1), ((4, 5), 2)]);
+
+        // Entirely off the buffer
+        display.blit(&numbered_sprite(), 2, 2, 3, 6);
+        display.blit(&numbered_sprite(), 2, 2, i32::MIN, i32::MAX);
+        assert_eq!(touched(&display).len(), 2);
+    }
+
+    #[test]
+    fn blit_alpha_blends_by_source_alpha() {
+        let mut display = buffer(2, 2);
+        display.blend_mode = BlendMode::Srgb;
+        let background = Pixel::from_rgba(100, 50, 200, 255);
+        display.clear(background);
+        let sprite = [
+            Pixel::from_rgba(255, 255, 255, 0),
+            Pixel::from_rgba(255, 255, 255, 128),
+            Pixel::from_rgba(255, 0, 0, 255),
+            Pixel::from_rgba(0, 0, 0, 128),
+        ];
+        display.blit_alpha(&sprite, 2, 2, 0, 0);
+
+        // out = (src * a + dest * (255 - a) + 127) / 255, alpha included
+        assert_eq!(display.get_pixel(0, 0), Some(background));
+        assert_eq!(
+            display.get_pixel(1, 0),
+            Some(Pixel::from_rgba(178, 153, 228, 191))
+        );
+        assert_eq!(
+            display.get_pixel(0, 1),
+            Some(Pixel::from_rgba(255, 0, 0, 255))
+        );
+        assert_eq!(
+            display.get_pixel(1, 1),
+            Some(Pixel::from_rgba(50, 25, 100, 191))
+        );
+    }
+
+    #[test]
+    fn diagonal_line_touches_one_pixel_per_step() {
+        let mut display = buffer(5, 5);
+        display.draw_line(0, 0, 4, 4, WHITE);
+        assert_eq!(touched(&display), [(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]);
+
+        let mut display = buffer(5, 5);
+        display.draw_line(4, 2, 0, 0, WHITE);
+        assert_eq!(touched(&display), [(0, 0), (1, 0), (2, 1), (3, 1), (4, 2)]);
+    }
+
+    #[test]
+    fn horizontal_line_includes_both_endpoints() {
+        let mut display = buffer(5, 5);
+        display.draw_line(1, 2, 3, 2, WHITE);
+        assert_eq!(touched(&display), [(1, 2), (2, 2), (3, 2)]);
+    }
+
+    #[test]
+    fn line_is_clipped_before_stepping() {
+        // Stepping through every point of these would take billions of iterations
+        let mut display = buffer(8, 8);
+        display.draw_line(i32::MIN, i32::MIN, i32::MAX, i32::MAX, WHITE);
+        let diagonal: Vec<_> = (0..8).map(|i| (i, i)).collect();
+        assert_eq!(touched(&display), diagonal);
+
+        let mut display = buffer(8, 8);
+        display.draw_line(-200_000_000, 3, 200_000_000, 3, WHITE);
+        let row: Vec<_> = (0..8).map(|x| (x, 3)).collect();
+        assert_eq!(touched(&display), row);
+
+        let mut display = buffer(8, 8);
+        display.draw_line(-5, 20, 20, -5, WHITE);
+        display.draw_line(-10, -10, -1, 100, WHITE);
+        assert!(touched(&display).is_empty());
+    }
+
+    #[test]
+    fn circle_covers_center_and_cardinal_edges() {
+        let mut display = buffer(7, 7);
+        display.draw_circle(3, 3, 2, WHITE);
+        for &(x, y) in [(3, 3), (3, 1), (3, 5), (1, 3), (5, 3)].iter() {
+            assert_eq!(display.get_pixel(x, y), Some(WHITE), "({}, {})", x, y);
+        }
+        // Just outside the radius
+        for &(x, y) in [(1, 1), (5, 5), (3, 0), (6, 3)].iter() {
+            assert_eq!(
+                display.get_pixel(x, y),
+                Some(Pixel::default()),
+                "({}, {})",
+                x,
+                y
+            );
+        }
+    }
+
+    #[test]
+    fn circle_centered_off_screen_draws_its_visible_arc() {
+        let mut display = buffer(7, 7);
+        display.draw_circle(-2, 3, 3, WHITE);
+        assert_eq!(
+            touched(&display),
+            [(0, 1), (0, 2), (0, 3), (1, 3), (0, 4), (0, 5)]
+        );
+
+        let mut display = buffer(7, 7);
+        display.draw_circle(i32::MIN, i32::MAX, i32::MAX, WHITE);
+        display.draw_circle(100, 100, 5, WHITE);
+        assert!(touched(&display).is_empty());
+    }
+
+    /// A path in the temp directory unique to this process and `name`
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("again-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn save_bmp_writes_headers_and_bottom_up_rows() {
+        let mut display = buffer(2, 2);
+        display.back = vec![
+            Pixel::from_rgba(1, 2, 3, 4),
+            Pixel::from_rgba(5, 6, 7, 8),
+            Pixel::from_rgba(9, 10, 11, 12),
+            Pixel::from_rgba(13, 14, 15, 16),
+        ];
+        let path = temp_path("save.bmp");
+        display.save_bmp(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        assert_eq!(bytes.len(), 54 + 16);
+        assert_eq!(&bytes[..2], b"BM");
+        assert_eq!(u32_at(2), 70);
+        assert_eq!(u32_at(10), 54);
+        assert_eq!(u32_at(14), 40);
+        assert_eq!((u32_at(18), u32_at(22)), (2, 2));
+        assert_eq!((u16_at(26), u16_at(28)), (1, 32));
+        assert_eq!((u32_at(30), u32_at(34)), (0, 16));
+
+        // BGRA, bottom row first
+        assert_eq!(
+            &bytes[54..],
+            [11, 10, 9, 12, 15, 14, 13, 16, 3, 2, 1, 4, 7, 6, 5, 8]
+        );
+    }
+
+    /// A buffer of `frames` frames with `channels` channels at `volume`, unfiltered. Ramps reach
+    /// their targets on the first sample, so the output is exactly what was asked for.
+    fn sound(sample_rate: u16, channels: u16, frames: usize, volume: f32) -> SoundBuffer {
+        SoundBuffer {
+            samples: vec![0; frames * usize::from(channels)],
+            sample_count: frames,
+            volume,
+            muted: false,
+            tone_enabled: true,
+            sample_rate,
+            channels,
+            waveform: Waveform::Sine,
+            sample_clock: 0,
+            ramp_samples: 0,
+            tone_ramp: Ramp::new(f32::from(MIN_TONE_HZ)),
+            volume_ramp: Ramp::new(volume),
+            clip: None,
+            oscillators: Vec::new(),
+            filter_cutoff: f32::INFINITY,
+            filter_state: 0.0,
+        }
+    }
+
+    #[test]
+    fn square_wave_flips_sign_every_half_period() {
+        // 20 Hz at 160 samples a second is 8 samples a period
+        let mut sound = sound(160, 1, 16, 1000.0);
+        sound.waveform = Waveform::Square;
+        // Half a sample in, so no sample lands right on a flip
+        let mut phase = f32::consts::PI / 8.0;
+        sound.render_sound(&mut phase, 20);
+
+        let signs: Vec<_> = sound.samples.iter().map(|sample| sample.signum()).collect();
+        assert_eq!(
+            signs,
+            [1, 1, 1, 1, -1, -1, -1, -1, 1, 1, 1, 1, -1, -1, -1, -1]
+        );
+        assert!(sound.samples.iter().all(|sample| sample.abs() == 1000));
+    }
+
+    #[test]
+    fn phase_stays_wrapped_and_in_tune() {
+        let mut sound = sound(48000, 1, 4800, 10000.0);
+        let mut phase = 0.0;
+        // 100 seconds. Unwrapped, the phase would reach 2.8e5 radians, where an f32 can only
+        // step in 0.03s and the tone would be audibly out of tune.
+        for _ in 0..1000 {
+            sound.render_sound(&mut phase, 440);
+            assert!((0.0..2.0 * f32::consts::PI).contains(&phase));
+        }
+
+        // One more block against a sine started afresh from the same phase
+        let start_phase = f64::from(phase);
+        sound.render_sound(&mut phase, 440);
+        for (i, &sample) in sound.samples.iter().enumerate() {
+            let reference_phase =
+                start_phase + 2.0 * std::f64::consts::PI * 440.0 * i as f64 / 48000.0;
+            let reference = reference_phase.sin() * 10000.0;
+            assert!(
+                (f64::from(sample) - reference).abs() < 10.0,
+                "sample {} is {} instead of {}",
+                i,
+                sample,
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn mono_writes_one_sample_per_frame() {
+        let mut mono = sound(48000, 1, 10, 10000.0);
+        // Room for more than the 10 frames asked for, which must be left alone
+        mono.samples = vec![i16::MIN; 20];
+        let mut phase = 1.0;
+        mono.render_sound(&mut phase, 440);
+        assert!(mono.samples[..10].iter().all(|&sample| sample != i16::MIN));
+        assert!(mono.samples[10..].iter().all(|&sample| sample == i16::MIN));
+        assert_eq!(mono.sample_clock, 10);
+
+        // Stereo repeats each frame's sample on both channels
+        let mut stereo = sound(48000, 2, 10, 10000.0);
+        let mut phase = 1.0;
+        stereo.render_sound(&mut phase, 440);
+        let left: Vec<_> = stereo.samples.iter().step_by(2).copied().collect();
+        let right: Vec<_> = stereo.samples.iter().skip(1).step_by(2).copied().collect();
+        assert_eq!(left, right);
+        assert_eq!(left, mono.samples[..10]);
+    }
+
+    #[test]
+    fn envelope_gain_at_each_stage() {
+        let envelope = Envelope {
+            attack: 100,
+            decay: 50,
+            sustain: 0.5,
+            release: 200,
+        };
+        assert_eq!(envelope.gain(0, None), 0.0);
+        assert_eq!(envelope.gain(50, None), 0.5);
+        // Peak at the end of the attack, then halfway down to the sustain level
+        assert_eq!(envelope.gain(100, None), 1.0);
+        assert_eq!(envelope.gain(125, None), 0.75);
+        assert_eq!(envelope.gain(150, None), 0.5);
+        assert_eq!(envelope.gain(10_000, None), 0.5);
+
+        // Released during the sustain: a linear fade from 0.5 over 200 samples
+        assert_eq!(envelope.gain(1000, Some(1000)), 0.5);
+        assert_eq!(envelope.gain(1100, Some(1000)), 0.25);
+        assert_eq!(envelope.gain(1200, Some(1000)), 0.0);
+        assert_eq!(envelope.gain(5000, Some(1000)), 0.0);
+        // Released mid-attack, the fade starts from wherever the attack got to
+        assert_eq!(envelope.gain(150, Some(50)), 0.25);
+    }
+
+    #[test]
+    fn tone_glides_to_a_changed_target_without_overshooting() {
+        let mut sound = sound(48000, 1, 1, 1000.0);
+        sound.ramp_samples = 100;
+        sound.tone_ramp = Ramp::new(100.0);
+        let mut phase = 0.0;
+        // One frame at a time so the tone can be read after every sample
+        let mut tone_after = |sound: &mut SoundBuffer, tone_hz| {
+            sound.render_sound(&mut phase, tone_hz);
+            sound.tone_ramp.current()
+        };
+
+        // Heading for 300 Hz at 2 Hz a sample, then retargeted to 200 Hz partway
+        let rising: Vec<_> = (0..30).map(|_| tone_after(&mut sound, 300)).collect();
+        let retargeted: Vec<_> = (0..100).map(|_| tone_after(&mut sound, 200)).collect();
+
+        assert!(rising.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(rising[29], 160.0);
+        assert!(retargeted.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(retargeted[0] > 160.0);
+        assert!(retargeted.iter().all(|&tone| tone <= 200.0));
+        assert!((retargeted[99] - 200.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn wav_decodes_a_hand_built_file() {
+        let samples: [i16; 4] = [0, i16::MAX, -1, i16::MIN];
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(4 + 8 + 16 + 8 + 8u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        bytes.extend_from_slice(&2u16.to_le_bytes()); // channels
+        bytes.extend_from_slice(&22050u32.to_le_bytes()); // sample rate
+        bytes.extend_from_slice(&(22050 * 4u32).to_le_bytes()); // byte rate
+        bytes.extend_from_slice(&4u16.to_le_bytes()); // block align
+        bytes.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&8u32.to_le_bytes());
+        samples
+            .iter()
+            .for_each(|sample| bytes.extend_from_slice(&sample.to_le_bytes()));
+
+        let wav = Wav::from_bytes(&bytes).unwrap();
+        assert_eq!(wav.samples, samples);
+        assert_eq!(wav.sample_rate, 22050);
+        assert_eq!(wav.channels, 2);
+
+        // 8-bit samples are rejected
+        bytes[34] = 8;
+        assert!(Wav::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn mute_zeroes_output_and_unmute_restores_it() {
+        let mut sound = sound(160, 1, 16, 1000.0);
+        let mut phase = 0.0;
+        let peak = |sound: &SoundBuffer| sound.samples.iter().map(|s| s.abs()).max().unwrap();
+
+        sound.render_sound(&mut phase, MIN_TONE_HZ);
+        let unmuted_peak = peak(&sound);
+        assert!(unmuted_peak > 990);
+
+        sound.muted = true;
+        sound.render_sound(&mut phase, MIN_TONE_HZ);
+        assert!(sound.samples.iter().all(|&sample| sample == 0));
+        assert_eq!(sound.volume, 1000.0);
+
+        sound.muted = false;
+        sound.render_sound(&mut phase, MIN_TONE_HZ);
+        assert_eq!(peak(&sound), unmuted_peak);
+    }
+
+    #[test]
+    fn deadzone_boundary_is_zero_and_full_deflection_is_one() {
+        assert_eq!(apply_deadzone(0, 0, 8000), (0.0, 0.0));
+        assert_eq!(apply_deadzone(8000, 0, 8000), (0.0, 0.0));
+        assert_eq!(apply_deadzone(0, -8000, 8000), (0.0, 0.0));
+        // Radial, so a diagonal just inside the circle is still dead
+        assert_eq!(apply_deadzone(5600, 5600, 8000), (0.0, 0.0));
+
+        let (x, y) = apply_deadzone(8001, 0, 8000);
+        assert!(x > 0.0 && x < 0.001);
+        assert_eq!(y, 0.0);
+
+        assert_eq!(apply_deadzone(i16::MAX, 0, 8000), (1.0, 0.0));
+        assert_eq!(apply_deadzone(0, i16::MIN, 8000), (0.0, -1.0));
+        let (x, y) = apply_deadzone(i16::MAX, i16::MAX, 8000);
+        assert!((x - f32::consts::FRAC_1_SQRT_2).abs() < 0.001);
+        assert_eq!(x, y);
+    }
+
+    #[test]
+    fn held_tone_keys_saturate_at_the_tone_limits() {
+        let mut raise = ToneKeys {
+            raise_held: true,
+            ..ToneKeys::default()
+        };
+        assert_eq!(raise.advance(u16::MAX - 10, 1.0), u16::MAX);
+        assert_eq!(raise.advance(u16::MAX, 1.0), u16::MAX);
+
+        let mut lower = ToneKeys {
+            lower_held: true,
+            ..ToneKeys::default()
+        };
+        assert_eq!(lower.advance(MIN_TONE_HZ + 10, 1.0), MIN_TONE_HZ);
+        assert_eq!(lower.advance(MIN_TONE_HZ, 1.0), MIN_TONE_HZ);
+    }
+
+    #[test]
+    fn replay_renders_the_recorded_frames_again() {
+        let inputs: Vec<Input> = (0..5)
+            .map(|frame| Input {
+                tone_hz: 300 + frame * 50,
+                volume: 1000.0,
+                tone_enabled: true,
+                mouse: MouseInput {
+                    x: i32::from(frame) * 3,
+                    y: 4,
+                    left: frame % 2 == 0,
+                    right: false,
+                },
+                dt: 1.0 / 60.0 + f32::from(frame) * 0.002,
+                ..Input::default()
+            })
+            .collect();
+        let mut game_state = GameState::default();
+        let mut display = buffer(16, 8);
+        let mut sound = sound(48000, 2, 800, 1000.0);
+        sound.ramp_samples = 480;
+        let mut replay = InputReplay::default();
+        let mut frame = |input: Input,
+                         replay: &mut InputReplay,
+                         game_state: &mut GameState,
+                         sound: &mut SoundBuffer| {
+            let input = replay.process(input, game_state, sound);
+            update_and_render(game_state, &input, &mut display, sound);
+            (display.back.clone(), sound.samples.clone())
+        };
+
+        replay.toggle(&mut game_state, &mut sound);
+        assert!(replay.is_recording());
+        let recorded: Vec<_> = inputs
+            .into_iter()
+            .map(|input| frame(input, &mut replay, &mut game_state, &mut sound))
+            .collect();
+
+        replay.toggle(&mut game_state, &mut sound);
+        assert!(replay.is_playing());
+        // Live input is ignored while playing, and playback loops back to the start
+        for expected in recorded.iter().chain(&recorded[..1]) {
+            let played = frame(Input::default(), &mut replay, &mut game_state, &mut sound);
+            assert_eq!(&played, expected);
+        }
+    }
+
+    #[test]
+    fn game_state_scrolls_and_advances_the_phase() {
+        let mut game_state = GameState::default();
+        let input = Input {
+            tone_hz: MIN_TONE_HZ,
+            volume: 1000.0,
+            tone_enabled: true,
+            ..Input::default()
+        };
+
+        for _ in 0..3 {
+            update(&mut game_state, &input, 0.25);
+        }
+        assert_eq!(game_state.tone_hz, MIN_TONE_HZ);
+        assert_eq!(
+            game_state.previous_scroll_offset,
+            2.0 * GRADIENT_SCROLL_SPEED * 0.25
+        );
+        assert_eq!(game_state.scroll_offset, 3.0 * GRADIENT_SCROLL_SPEED * 0.25);
+
+        // 20 Hz at 160 samples a second: 4 samples are half a period
+        let mut display = buffer(4, 2);
+        let mut sound = sound(160, 1, 4, 1000.0);
+        render(&mut game_state, &input, 1.0, &mut display, &mut sound);
+        assert!((game_state.t_sin - f32::consts::PI).abs() < 1e-5);
+        render(&mut game_state, &input, 1.0, &mut display, &mut sound);
+        assert!(game_state.t_sin < 1e-5 || game_state.t_sin > 2.0 * f32::consts::PI - 1e-5);
+    }
+
+    #[test]
+    fn fixed_timestep_counts_whole_steps() {
+        let mut timestep = FixedTimestep::new(0.25);
+        assert_eq!(timestep.advance(0.125), 0);
+        assert_eq!(timestep.alpha(), 0.5);
+        assert_eq!(timestep.advance(0.25), 1);
+        assert_eq!(timestep.advance(0.625), 3);
+        assert_eq!(timestep.alpha(), 0.0);
+        assert_eq!(timestep.advance(0.0), 0);
+        assert_eq!(timestep.advance(-1.0), 0);
+
+        // A long stall runs the maximum and drops the rest
+        assert_eq!(timestep.advance(100.0), MAX_STEPS_PER_FRAME);
+        assert_eq!(timestep.advance(0.125), 0);
+        assert_eq!(timestep.advance(0.125), 1);
+    }
+
+    #[test]
+    fn disabled_tone_renders_silence_and_keeps_its_phase() {
+        let mut sound = sound(160, 2, 16, 1000.0);
+        sound.tone_enabled = false;
+        let mut phase = 1.0;
+
+        // The fade out takes the first buffer, after that nothing is synthesized
+        sound.render_sound(&mut phase, MIN_TONE_HZ);
+        let faded_phase = phase;
+        sound.render_sound(&mut phase, MIN_TONE_HZ);
+        assert!(sound.samples.iter().all(|&sample| sample == 0));
+        assert_eq!(phase, faded_phase);
+
+        sound.tone_enabled = true;
+        sound.render_sound(&mut phase, MIN_TONE_HZ);
+        assert!(sound.samples.iter().any(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn zero_hz_is_raised_to_the_lowest_tone() {
+        let mut zero = sound(48000, 1, 4800, 1000.0);
+        let mut zero_phase = 0.0;
+        zero.render_sound(&mut zero_phase, 0);
+        assert!(zero_phase.is_finite());
+
+        let mut lowest = sound(48000, 1, 4800, 1000.0);
+        let mut lowest_phase = 0.0;
+        lowest.render_sound(&mut lowest_phase, MIN_TONE_HZ);
+        assert_eq!(zero.samples, lowest.samples);
+        assert_eq!(zero_phase, lowest_phase);
+    }
+
+    #[test]
+    fn oscillators_mix_to_the_clamped_sum() {
+        let mixed_first_sample = |volume: f32, oscillator_phase: f32, amplitude: f32| {
+            let mut sound = sound(48000, 1, 1, volume);
+            sound.oscillators.push(Oscillator {
+                frequency_hz: 880.0,
+                waveform: Waveform::Sine,
+                phase: oscillator_phase,
+                amplitude,
+            });
+            let mut tone_phase = f32::consts::FRAC_PI_2;
+            sound.render_sound(&mut tone_phase, 440);
+            sound.samples[0]
+        };
+
+        // The tone at its peak plus a half-amplitude sine at π/6
+        let tone = Waveform::Sine.sample(f32::consts::FRAC_PI_2);
+        let oscillator = 0.5 * Waveform::Sine.sample(f32::consts::FRAC_PI_6);
+        assert_eq!(
+            mixed_first_sample(10000.0, f32::consts::FRAC_PI_6, 0.5),
+            mix_sample((tone + oscillator) * 10000.0)
+        );
+        assert_eq!(
+            mixed_first_sample(10000.0, f32::consts::FRAC_PI_6, 0.5),
+            12500
+        );
+
+        // Two voices at their peaks add up past the i16 range and clip
+        assert_eq!(
+            mixed_first_sample(MAX_VOLUME, f32::consts::FRAC_PI_2, 1.0),
+            i16::MAX
+        );
+    }
+
+    #[test]
+    fn low_pass_step_response_rises_exponentially() {
+        // A 20 Hz square wave holds its first level for 1200 samples: a step as far as the
+        // filter can tell
+        let mut sound = sound(48000, 1, 100, 10000.0);
+        sound.waveform = Waveform::Square;
+        sound.filter_cutoff = 100.0;
+        let mut phase = 0.0;
+        let mut output = Vec::new();
+        // Over two buffers, so the filter has to carry its state across
+        for _ in 0..2 {
+            sound.render_sound(&mut phase, MIN_TONE_HZ);
+            output.extend_from_slice(&sound.samples);
+        }
+
+        let coefficient = low_pass_coefficient(100.0, 48000);
+        for (n, &sample) in output.iter().enumerate() {
+            let expected = 10000.0 * (1.0 - (1.0 - coefficient).powi(n as i32 + 1));
+            assert!((f32::from(sample) - expected).abs() <= 1.0, "sample {}", n);
+        }
+        // One time constant, 1 / (2π * 100 Hz), is about 76 samples: 1 - 1/e of the way up
+        assert!((f32::from(output[75]) / 10000.0 - (1.0 - (-1.0f32).exp())).abs() < 0.01);
+    }
+
+    #[test]
+    fn downsample_averages_two_by_two_blocks() {
+        let black = Pixel::from_rgba(0, 0, 0, 255);
+        let mut checkerboard = buffer(4, 4);
+        for (index, pixel) in checkerboard.back.iter_mut().enumerate() {
+            let (x, y) = (index % 4, index / 4);
+            *pixel = if (x + y) % 2 == 0 { WHITE } else { black };
+        }
+
+        let mut downsampled = buffer(2, 2);
+        checkerboard.downsample_into(&mut downsampled);
+        assert!(downsampled
+            .back
+            .iter()
+            .all(|&pixel| pixel == Pixel::from_rgba(128, 128, 128, 255)));
+
+        // Odd sizes repeat the last row and column
+        let mut odd = buffer(3, 3);
+        odd.back[8] = WHITE;
+        let mut downsampled = buffer(2, 2);
+        odd.downsample_into(&mut downsampled);
+        assert_eq!(downsampled.back[3], WHITE);
+        assert_eq!(downsampled.back[0], Pixel::default());
+    }
+
+    #[test]
+    fn linear_blending_is_brighter_than_srgb() {
+        let half_white = [Pixel::from_rgba(255, 255, 255, 128)];
+        let blend_over_black = |blend_mode| {
+            let mut display = buffer(1, 1);
+            display.blend_mode = blend_mode;
+            display.clear(Pixel::from_rgba(0, 0, 0, 255));
+            display.blit_alpha(&half_white, 1, 1, 0, 0);
+            display.get_pixel(0, 0).unwrap()
+        };
+
+        let srgb = blend_over_black(BlendMode::Srgb);
+        assert_eq!((srgb.r, srgb.g, srgb.b), (128, 128, 128));
+
+        // Half the light of white is about 188 once encoded as sRGB
+        let linear = blend_over_black(BlendMode::Linear);
+        for channel in [linear.r, linear.g, linear.b] {
+            assert!((186..=190).contains(&channel), "{}", channel);
+        }
+    }
+
+    #[test]
+    fn draw_text_lights_the_glyph_pixels() {
+        const A: [&str; 8] = [
+            "..##....", ".####...", "##..##..", "##..##..", "######..", "##..##..", "##..##..",
+            "........",
+        ];
+        let lit_in = |art: &[&str], x0: i32, y0: i32| -> Vec<(i32, i32)> {
+            art.iter()
+                .enumerate()
+                .flat_map(|(y, row)| {
+                    row.char_indices()
+                        .filter(|&(_, c)| c == '#')
+                        .map(move |(x, _)| (x0 + x as i32, y0 + y as i32))
+                })
+                .collect()
+        };
+
+        let mut display = buffer(10, 10);
+        display.draw_text(1, 2, "A", WHITE);
+        assert_eq!(touched(&display), lit_in(&A, 1, 2));
+
+        // Characters the font doesn't cover are drawn as the outline of the cell
+        let mut display = buffer(8, 8);
+        display.draw_text(0, 0, "\u{e9}", WHITE);
+        let outline: Vec<_> = cells(0..8, 0..8)
+            .into_iter()
+            .filter(|&(x, y)| x == 0 || y == 0 || x == 7 || y == 7)
+            .collect();
+        assert_eq!(touched(&display), outline);
+    }
+
+    #[test]
+    fn frame_rate_average_settles_despite_jitter() {
+        let mut counter = FrameRateCounter::default();
+        assert_eq!(counter.fps(), None);
+        counter.record(1.0 / 60.0);
+        assert!((counter.fps().unwrap() - 60.0).abs() < 0.01);
+
+        // Alternating 50 and 75 fps averages out near 60 with little movement frame to frame
+        let mut readings = Vec::new();
+        for frame in 0..200 {
+            counter.record(if frame % 2 == 0 {
+                1.0 / 50.0
+            } else {
+                1.0 / 75.0
+            });
+            readings.push(counter.fps().unwrap());
+        }
+        for pair in readings[100..].windows(2) {
+            assert!((pair[0] - pair[1]).abs() < 1.5);
+        }
+        assert!((readings[199] - 60.0).abs() < 1.0);
+
+        // Bogus frame times are ignored
+        let fps = counter.fps();
+        counter.record(0.0);
+        counter.record(f32::NAN);
+        counter.record(-1.0);
+        assert_eq!(counter.fps(), fps);
+    }
+
+    #[test]
+    fn swap_presents_back_and_recycles_front() {
+        let mut display = buffer(2, 2);
+        let first = Pixel::from_rgba(255, 0, 0, 255);
+        let second = Pixel::from_rgba(0, 0, 255, 255);
+
+        display.clear(first);
+        display.swap();
+        assert!(display.front.iter().all(|&pixel| pixel == first));
+        assert!(display.back.iter().all(|&pixel| pixel == Pixel::default()));
+
+        display.clear(second);
+        assert!(display.front.iter().all(|&pixel| pixel == first));
+        display.swap();
+        assert!(display.front.iter().all(|&pixel| pixel == second));
+        assert!(display.back.iter().all(|&pixel| pixel == first));
+    }
+
+    #[test]
+    fn render_with_dispatches_to_the_scene() {
+        struct FillRed {
+            calls: Vec<f32>,
+        }
+        impl Renderer for FillRed {
+            fn render(&mut self, buffer: &mut DisplayBuffer, dt: f32) {
+                self.calls.push(dt);
+                buffer.clear(Pixel::from_rgba(255, 0, 0, 255));
+            }
+        }
+
+        let mut game_state = GameState {
+            intro_elapsed: INTRO_SECONDS,
+            ..GameState::default()
+        };
+        let input = Input {
+            dt: 0.5,
+            // Keep the cursor dot out of the way
+            mouse: MouseInput {
+                x: -100,
+                y: -100,
+                ..MouseInput::default()
+            },
+            ..Input::default()
+        };
+        let mut display = buffer(4, 2);
+        let mut sound = sound(160, 1, 4, 0.0);
+        let mut scene = FillRed { calls: Vec::new() };
+        render_with(
+            &mut game_state,
+            &input,
+            &mut display,
+            &mut sound,
+            &mut scene,
+        );
+
+        assert_eq!(scene.calls, [0.5]);
+        assert!(display
+            .back
+            .iter()
+            .all(|&pixel| pixel == Pixel::from_rgba(255, 0, 0, 255)));
+    }
+
+    #[test]
+    fn render_into_fills_exactly_the_frames_asked_for() {
+        // Starts with no room, as an audio callback's buffer would
+        let mut callback = sound(160, 2, 0, 1000.0);
+        let mut phase = 0.0;
+
+        // 5 stereo frames and half of another
+        let mut out = [i16::MIN; 11];
+        callback.render_into(&mut phase, MIN_TONE_HZ, &mut out);
+        assert_eq!(callback.sample_count, 5);
+        assert_eq!(callback.sample_clock, 5);
+        assert_eq!(out[10], 0);
+        for frame in out[..10].chunks_exact(2) {
+            assert_eq!(frame[0], frame[1]);
+        }
+        // Carries on from the same phase as one big buffer would
+        let mut reference = sound(160, 2, 8, 1000.0);
+        let mut reference_phase = 0.0;
+        reference.render_sound(&mut reference_phase, MIN_TONE_HZ);
+        assert_eq!(out[..10], reference.samples[..10]);
+
+        let mut out = [0; 6];
+        callback.render_into(&mut phase, MIN_TONE_HZ, &mut out);
+        assert_eq!(out, reference.samples[10..]);
+    }
+
+    #[test]
+    fn held_tone_keys_slide_smoothly_across_frames() {
+        let mut tone_keys = ToneKeys {
+            raise_held: true,
+            ..ToneKeys::default()
+        };
+        // 512 Hz a second at 1/1024 s a frame is half a hertz a frame, applied every other frame
+        let mut tone_hz = 440;
+        let tones: Vec<u16> = (0..4)
+            .map(|_| {
+                tone_hz = tone_keys.advance(tone_hz, 1.0 / 1024.0);
+                tone_hz
+            })
+            .collect();
+        assert_eq!(tones, [440, 441, 441, 442]);
+
+        // A whole second slides the full rate regardless of how it's split up
+        let mut split = ToneKeys {
+            lower_held: true,
+            ..ToneKeys::default()
+        };
+        let tone_hz = (0..64).fold(1000, |tone_hz, _| split.advance(tone_hz, 1.0 / 64.0));
+        assert_eq!(tone_hz, 1000 - 512);
+
+        // Both keys, or neither, hold still and drop the partial slide
+        let mut both = ToneKeys {
+            raise_held: true,
+            lower_held: true,
+            ..ToneKeys::default()
+        };
+        assert_eq!(both.advance(440, 1.0), 440);
+        tone_keys.raise_held = false;
+        assert_eq!(tone_keys.advance(442, 1.0 / 1024.0), 442);
+    }
+
+    #[test]
+    fn fade_from_black_scales_the_colour_channels() {
+        let faded = |t: f32| {
+            let mut display = buffer(2, 1);
+            display.back[0] = Pixel::from_rgba(255, 100, 3, 200);
+            display.back[1] = WHITE;
+            display.fade_from_black(t);
+            display.back
+        };
+
+        assert_eq!(
+            faded(0.0),
+            [
+                Pixel::from_rgba(0, 0, 0, 200),
+                Pixel::from_rgba(0, 0, 0, 255)
+            ]
+        );
+        assert_eq!(faded(1.0), [Pixel::from_rgba(255, 100, 3, 200), WHITE]);
+        // Halves round up
+        assert_eq!(
+            faded(0.5),
+            [
+                Pixel::from_rgba(128, 50, 2, 200),
+                Pixel::from_rgba(128, 128, 128, 255)
+            ]
+        );
+        assert_eq!(faded(-1.0), faded(0.0));
+        assert_eq!(faded(2.0), faded(1.0));
+    }
+
+    #[test]
+    fn resize_keeps_pixels_at_their_coordinates() {
+        let pattern = numbered_sprite();
+        let mut display = buffer(2, 2);
+        display.back.copy_from_slice(&pattern);
+        display.front.copy_from_slice(&pattern);
+
+        display.resize(4, 4);
+        assert_eq!(display.dimensions(), (4, 4));
+        for pixels in [&display.back, &display.front] {
+            assert_eq!(pixels.len(), 16);
+            for (index, &pixel) in pixels.iter().enumerate() {
+                let (x, y) = (index % 4, index / 4);
+                let expected = if x < 2 && y < 2 {
+                    pattern[y * 2 + x]
+                } else {
+                    Pixel::default()
+                };
+                assert_eq!(pixel, expected, "({}, {})", x, y);
+            }
+        }
+
+        // Shrinking back crops to the same pixels
+        display.resize(2, 2);
+        assert_eq!(display.back, pattern);
+    }
+
+    #[test]
+    fn sample_format_sizes() {
+        let formats = [
+            SampleFormat::Pcm16,
+            SampleFormat::Pcm24,
+            SampleFormat::Float32,
+        ];
+        let bits: Vec<_> = formats
+            .iter()
+            .map(|format| format.bits_per_sample())
+            .collect();
+        let bytes: Vec<_> = formats
+            .iter()
+            .map(|format| format.bytes_per_sample())
+            .collect();
+        assert_eq!(bits, [16, 24, 32]);
+        assert_eq!(bytes, [2, 3, 4]);
+        for (format, &len) in formats.iter().zip(&bytes) {
+            // `encode` fills exactly `bytes_per_sample` bytes
+            format.encode(i16::MIN, &mut vec![0; len]);
+        }
+    }
+
+    #[test]
+    fn aa_line_coverage_matches_wu_weights() {
+        let mut display = buffer(9, 3);
+        // With sRGB blending over black, each channel comes out as 255 * coverage
+        display.blend_mode = BlendMode::Srgb;
+        // A gradient of 1/4: the line is at y = x / 4
+        display.draw_line_aa(0.0, 0.0, 8.0, 2.0, WHITE);
+
+        let coverage = |x, y| f32::from(display.get_pixel(x, y).unwrap().r) / 255.0;
+        let expected = [
+            ((1, 0), 0.75),
+            ((1, 1), 0.25),
+            ((2, 0), 0.5),
+            ((2, 1), 0.5),
+            ((4, 1), 1.0),
+            ((4, 2), 0.0),
+            ((7, 1), 0.25),
+            ((7, 2), 0.75),
+        ];
+        for &((x, y), weight) in expected.iter() {
+            assert!(
+                (coverage(x, y) - weight).abs() < 0.01,
+                "({}, {}) covered {} instead of {}",
+                x,
+                y,
+                coverage(x, y),
+                weight
+            );
+        }
+    }
+
+    #[test]
+    fn aa_line_with_distant_endpoints_is_clipped() {
+        let mut display = buffer(8, 8);
+        display.draw_line_aa(0.0, 0.0, 1e10, 0.99e10, WHITE);
+        assert_ne!(display.get_pixel(0, 0), Some(Pixel::default()));
+        assert_ne!(display.get_pixel(7, 7), Some(Pixel::default()));
+
+        let mut display = buffer(8, 8);
+        display.draw_line_aa(0.0, 3e9, 1e10, 3e9, WHITE);
+        display.draw_line_aa(-3e38, -3e38, 3e38, 3e38, WHITE);
+        display.draw_line_aa(f32::MIN, 4.0, f32::MAX, 4.0, WHITE);
+    }
+
+    #[test]
+    fn stars_wrap_round_the_left_edge() {
+        let mut starfield = StarfieldRenderer {
+            stars: vec![Star {
+                x: 2.0,
+                y: 3.0,
+                velocity_x: -64.0,
+            }],
+        };
+        let mut display = buffer(16, 8);
+        // 4 pixels a frame, past the left edge on the first: 2 -> 14 -> 10 -> 6
+        for _ in 0..3 {
+            starfield.render(&mut display, 1.0 / 16.0);
+        }
+        assert_eq!(starfield.stars[0].x, 6.0);
+        assert_eq!(starfield.stars[0].y, 3.0);
+        let black = Pixel::from_rgba(0, 0, 0, 255);
+        let lit: Vec<_> = (0..8)
+            .flat_map(|y| (0..16).map(move |x| (x, y)))
+            .filter(|&(x, y)| display.get_pixel(x, y) != Some(black))
+            .collect();
+        assert_eq!(lit, [(6, 3)]);
+        assert_eq!(
+            display.get_pixel(6, 3),
+            Some(Pixel::from_rgba(127, 127, 127, 255))
+        );
+
+        // The same seed places the same stars
+        let positions = |starfield: &StarfieldRenderer| {
+            starfield
+                .stars
+                .iter()
+                .map(|star| (star.x, star.y, star.velocity_x))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            positions(&StarfieldRenderer::new(7, 20, 16, 8)),
+            positions(&StarfieldRenderer::new(7, 20, 16, 8))
+        );
+    }
+
+    #[test]
+    fn rng_sequence_is_stable() {
+        // xorshift32 from a seed of 1
+        let mut rng = Rng::new(1);
+        let draws: Vec<_> = (0..3).map(|_| rng.next_u32()).collect();
+        assert_eq!(draws, [270369, 67634689, 2647435461]);
+
+        // A zero seed would stay zero forever
+        let mut zero = Rng::new(0);
+        assert_eq!(zero.next_u32(), 270369);
+    }
+
+    #[test]
+    fn rng_floats_stay_in_range() {
+        let mut rng = Rng::new(12345);
+        for _ in 0..100_000 {
+            let value = rng.next_f32();
+            assert!((0.0..1.0).contains(&value), "{}", value);
+            let value = rng.range(-3.0, 5.0);
+            assert!((-3.0..5.0).contains(&value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn indices_resolve_through_the_palette() {
+        let mut palette = [Pixel::default(); 256];
+        palette[0] = Pixel::from_rgba(0, 0, 64, 255);
+        palette[5] = Pixel::from_rgba(255, 0, 0, 255);
+        palette[200] = Pixel::from_rgba(0, 255, 0, 255);
+
+        let mut indexed = IndexedBuffer::new(3, 2);
+        indexed.set_index(1, 0, 5);
+        indexed.set_index(2, 1, 200);
+        // Off the image, ignored
+        indexed.set_index(3, 0, 5);
+        indexed.set_index(-1, 1, 5);
+
+        let mut display = buffer(3, 2);
+        indexed.resolve_into(&mut display, &palette);
+        let background = palette[0];
+        assert_eq!(
+            display.back,
+            [
+                background,
+                palette[5],
+                background,
+                background,
+                background,
+                palette[200]
+            ]
+        );
+
+        indexed.clear_index(5);
+        indexed.resolve_into(&mut display, &palette);
+        assert!(display.back.iter().all(|&pixel| pixel == palette[5]));
+
+        // A smaller destination gets the overlap
+        let mut small = buffer(2, 1);
+        IndexedBuffer::new(3, 2).resolve_into(&mut small, &palette);
+        assert_eq!(small.back, [background, background]);
+    }
+
+    #[test]
+    fn palette_range_rotates_both_ways() {
+        let red = |r| Pixel::from_rgba(r, 0, 0, 255);
+        // Entries 1..=4 cycle, 0 and 5 stay put
+        let original: Vec<_> = (0..6).map(red).collect();
+
+        let mut palette = original.clone();
+        rotate_palette(&mut palette, 1, 4, 1);
+        assert_eq!(palette, [red(0), red(4), red(1), red(2), red(3), red(5)]);
+
+        let mut palette = original.clone();
+        rotate_palette(&mut palette, 1, 4, -1);
+        assert_eq!(palette, [red(0), red(2), red(3), red(4), red(1), red(5)]);
+
+        // Whole cycles and back again leave it as it was
+        let mut palette = original.clone();
+        rotate_palette(&mut palette, 1, 4, 5);
+        rotate_palette(&mut palette, 1, 4, -9);
+        assert_eq!(palette, original);
+
+        // Clipped to the palette
+        let mut palette = original.clone();
+        rotate_palette(&mut palette, 4, 10, 1);
+        assert_eq!(palette, [red(0), red(1), red(2), red(3), red(5), red(4)]);
+
+        // Half a step a frame at 30 steps per second, one step every other frame
+        let mut palette = original.clone();
+        let mut cycle = PaletteCycle::new(1, 4, 30.0);
+        cycle.advance(&mut palette, 1.0 / 60.0);
+        assert_eq!(palette, original);
+        cycle.advance(&mut palette, 1.0 / 60.0);
+        assert_eq!(palette, [red(0), red(4), red(1), red(2), red(3), red(5)]);
+    }
+
+    #[test]
+    fn rumble_decays_to_zero_at_its_rate() {
+        let mut rumble = RumbleState {
+            left: 1.0,
+            right: 0.5,
+            decay_per_second: 2.0,
+        };
+        // 0.2 a frame at 10 frames per second
+        let mut strengths = Vec::new();
+        for _ in 0..4 {
+            rumble.decay(0.1);
+            strengths.push((rumble.left, rumble.right));
+        }
+        let expected = [(0.8, 0.3), (0.6, 0.1), (0.4, 0.0), (0.2, 0.0)];
+        for (&(left, right), &(expected_left, expected_right)) in strengths.iter().zip(&expected) {
+            assert!((left - expected_left).abs() < 1e-5, "{:?}", strengths);
+            assert!((right - expected_right).abs() < 1e-5, "{:?}", strengths);
+        }
+
+        // Stops at zero rather than going negative, and a negative dt doesn't bring it back
+        rumble.decay(1.0);
+        rumble.decay(-1.0);
+        assert_eq!((rumble.left, rumble.right), (0.0, 0.0));
+
+        let rumble = RumbleState {
+            left: 1.0,
+            right: 0.5,
+            ..RumbleState::default()
+        };
+        assert_eq!(rumble.motor_speeds(), (u16::MAX, 32768));
+    }
+
+    #[test]
+    fn paused_tick_leaves_the_game_state_alone() {
+        let mut display = buffer(16, 8);
+        let mut samples = sound(48000, 2, 800, 1000.0);
+        let mut input = Input {
+            tone_hz: 440,
+            volume: 1000.0,
+            tone_enabled: true,
+            dt: 1.0 / 50.0,
+            ..Input::default()
+        };
+        let mut game_state = GameState::default();
+        for _ in 0..3 {
+            game_state.tick(&input, &mut display, &mut samples);
+        }
+        let running = game_state;
+        let frame = display.back.clone();
+
+        input.paused = true;
+        for _ in 0..5 {
+            assert_eq!(game_state.tick(&input, &mut display, &mut samples), 0);
+            assert_eq!(game_state, running);
+            // The frozen frame is drawn again, over silence
+            assert_eq!(display.back, frame);
+            assert!(samples.samples.iter().all(|&sample| sample == 0));
+        }
+
+        // Unpausing carries on from the same point
+        input.paused = false;
+        assert!(game_state.tick(&input, &mut display, &mut samples) > 0);
+        assert!(game_state.scroll_offset > running.scroll_offset);
+    }
+
+    #[test]
+    fn single_step_advances_one_fixed_update() {
+        let mut display = buffer(16, 8);
+        let mut samples = sound(48000, 2, 800, 1000.0);
+        let mut game_state = GameState {
+            scroll_offset: 10.0,
+            ..GameState::default()
+        };
+        let before = game_state;
+        // However long the frame took, a step is one fixed update
+        let input = Input {
+            paused: true,
+            step_frames: 1,
+            dt: 0.5,
+            ..Input::default()
+        };
+
+        assert_eq!(game_state.tick(&input, &mut display, &mut samples), 1);
+        let expected = 10.0 + GRADIENT_SCROLL_SPEED * FIXED_DT;
+        assert!((game_state.scroll_offset - expected).abs() < 1e-5);
+        assert_eq!(game_state.previous_scroll_offset, 10.0);
+        assert!((game_state.intro_elapsed - FIXED_DT).abs() < 1e-6);
+        // The timestep holds still, so rendering keeps the same interpolation
+        assert_eq!(game_state.timestep, before.timestep);
+
+        // Two presses in one frame are two steps
+        let input = Input {
+            step_frames: 2,
+            ..input
+        };
+        assert_eq!(game_state.tick(&input, &mut display, &mut samples), 2);
+        assert!(
+            (game_state.scroll_offset - (10.0 + 3.0 * GRADIENT_SCROLL_SPEED * FIXED_DT)).abs()
+                < 1e-5
+        );
+    }
+
+    #[test]
+    fn frame_history_overwrites_the_oldest() {
+        let mut history = FrameTimeHistory::new(3);
+        assert!(history.is_empty());
+        history.record(1.0);
+        history.record(2.0);
+        assert_eq!(history.iter().collect::<Vec<_>>(), [1.0, 2.0]);
+
+        history.record(3.0);
+        history.record(4.0);
+        history.record(5.0);
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.iter().collect::<Vec<_>>(), [3.0, 4.0, 5.0]);
+
+        // Bad durations don't take a slot
+        history.record(-1.0);
+        history.record(f32::NAN);
+        assert_eq!(history.iter().collect::<Vec<_>>(), [3.0, 4.0, 5.0]);
+
+        // Zero capacity keeps one
+        let mut history = FrameTimeHistory::new(0);
+        history.record(1.0);
+        history.record(2.0);
+        assert_eq!(history.iter().collect::<Vec<_>>(), [2.0]);
+    }
+
+    #[test]
+    fn frame_bar_height_is_proportional_to_duration() {
+        let target = 1.0 / 60.0;
+        assert_eq!(frame_bar_height(target, target, 32, 64), 32);
+        assert_eq!(frame_bar_height(target / 2.0, target, 32, 64), 16);
+        assert_eq!(frame_bar_height(target * 1.5, target, 32, 64), 48);
+        // Clamped to the graph
+        assert_eq!(frame_bar_height(target * 10.0, target, 32, 64), 64);
+        assert_eq!(frame_bar_height(0.0, target, 32, 64), 0);
+        // Nothing sensible to scale by
+        assert_eq!(frame_bar_height(target, 0.0, 32, 64), 0);
+        assert_eq!(frame_bar_height(f32::INFINITY, target, 32, 64), 0);
+    }
+
+    #[test]
+    fn rectangle_outlines_leave_the_interior_alone() {
+        // Cells of `xs` x `ys` outside `inner_xs` x `inner_ys`
+        let ring = |xs, ys, inner_xs: std::ops::Range<i32>, inner_ys: std::ops::Range<i32>| {
+            cells(xs, ys)
+                .into_iter()
+                .filter(|(x, y)| !(inner_xs.contains(x) && inner_ys.contains(y)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut display = buffer(10, 10);
+        display.draw_rectangle_outline(1, 1, 8, 8, 1, WHITE);
+        assert_eq!(touched(&display), ring(1..8, 1..8, 2..7, 2..7));
+
+        let mut display = buffer(10, 10);
+        display.draw_rectangle_outline(1, 1, 9, 9, 3, WHITE);
+        assert_eq!(touched(&display), ring(1..9, 1..9, 4..6, 4..6));
+
+        // Too thick for a hole, filled
+        let mut display = buffer(10, 10);
+        display.draw_rectangle_outline(1, 1, 9, 9, 4, WHITE);
+        assert_eq!(touched(&display), cells(1..9, 1..9));
+
+        // Clipped, the sides off the buffer are lost
+        let mut display = buffer(10, 10);
+        display.draw_rectangle_outline(-5, -5, 5, 5, 1, WHITE);
+        assert_eq!(touched(&display), ring(0..5, 0..5, 0..4, 0..4));
+
+        let mut display = buffer(10, 10);
+        display.draw_rectangle_outline(1, 1, 8, 8, 0, WHITE);
+        assert!(touched(&display).is_empty());
+    }
+
+    #[test]
+    fn polygon_fill_covers_pixel_centers_inside() {
+        let mut display = buffer(8, 8);
+        display.fill_polygon(&[(0, 0), (4, 0), (0, 4)], WHITE);
+        assert_eq!(
+            touched(&display),
+            [(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (0, 2)]
+        );
+
+        // An arrowhead pointing right, with its notch at (3, 3)
+        let mut display = buffer(8, 8);
+        display.fill_polygon(&[(0, 0), (6, 3), (0, 6), (3, 3)], WHITE);
+        assert_eq!(
+            touched(&display),
+            [
+                (0, 0),
+                (1, 1),
+                (2, 1),
+                (2, 2),
+                (3, 2),
+                (4, 2),
+                (2, 3),
+                (3, 3),
+                (4, 3),
+                (1, 4),
+                (2, 4),
+                (0, 5),
+            ]
+        );
+
+        // Degenerate polygons draw nothing
+        let mut display = buffer(8, 8);
+        display.fill_polygon(&[(0, 0), (4, 4)], WHITE);
+        display.fill_polygon(&[(0, 0), (2, 2), (5, 5)], WHITE);
+        display.fill_polygon(&[(1, 1), (1, 1), (1, 1)], WHITE);
+        assert!(touched(&display).is_empty());
+
+        // Clipped to the buffer
+        let mut display = buffer(4, 4);
+        display.fill_polygon(&[(-4, -4), (8, -4), (8, 8), (-4, 8)], WHITE);
+        assert_eq!(touched(&display), cells(0..4, 0..4));
+    }
+
+    #[test]
+    fn dbfs_converts_to_a_peak_amplitude() {
+        assert_eq!(dbfs_to_amplitude(0.0), MAX_VOLUME);
+        // -6 dBFS is a hair over half, -20 dBFS a tenth
+        assert!((dbfs_to_amplitude(-6.0) / MAX_VOLUME - 0.501187).abs() < 1e-5);
+        assert!((dbfs_to_amplitude(-20.0) / MAX_VOLUME - 0.1).abs() < 1e-6);
+        // Nothing louder than full scale, and no level at all is silence
+        assert_eq!(dbfs_to_amplitude(6.0), MAX_VOLUME);
+        assert_eq!(dbfs_to_amplitude(f32::NEG_INFINITY), 0.0);
+        assert_eq!(dbfs_to_amplitude(f32::NAN), 0.0);
+    }
+
+    #[test]
+    fn test_tone_is_a_440_hz_sine_at_minus_6_dbfs() {
+        let mut display = buffer(4, 4);
+        let mut samples = sound(48000, 1, 480, 0.0);
+        let mut game_state = GameState::default();
+        // Whatever the player had set, the test tone replaces it
+        let input = Input {
+            tone_hz: 1000,
+            volume: 10.0,
+            muted: true,
+            ..Input::default()
+        }
+        .with_test_tone();
+        update(&mut game_state, &input, FIXED_DT);
+        // The first block glides in from the buffer's previous tone and volume
+        render(&mut game_state, &input, 0.0, &mut display, &mut samples);
+        let start_phase = f64::from(game_state.t_sin);
+        render(&mut game_state, &input, 0.0, &mut display, &mut samples);
+
+        let amplitude = f64::from(dbfs_to_amplitude(TEST_TONE_DBFS));
+        for (i, &sample) in samples.samples.iter().enumerate() {
+            let reference_phase =
+                start_phase + 2.0 * std::f64::consts::PI * 440.0 * i as f64 / 48000.0;
+            let reference = reference_phase.sin() * amplitude;
+            assert!(
+                (f64::from(sample) - reference).abs() < 2.0,
+                "sample {} is {} instead of {}",
+                i,
+                sample,
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn checkerboard_alternates_by_cell() {
+        let white = Pixel::from_rgba(255, 255, 255, 255);
+        let black = Pixel::from_rgba(0, 0, 0, 255);
+        let cell = TEST_PATTERN_CELL;
+        let mut display = buffer(4 * cell, 2 * cell);
+        display.test_pattern(TestPattern::Checkerboard);
+
+        assert_eq!(display.get_pixel(0, 0), Some(white));
+        assert_eq!(display.get_pixel(cell - 1, cell - 1), Some(white));
+        assert_eq!(display.get_pixel(cell, 0), Some(black));
+        assert_eq!(display.get_pixel(0, cell), Some(black));
+        assert_eq!(display.get_pixel(cell, cell), Some(white));
+        assert_eq!(display.get_pixel(3 * cell + 5, 2 * cell - 1), Some(white));
+    }
+
+    #[test]
+    fn color_bars_split_the_width_evenly() {
+        // Eight bars of 10 pixels
+        let mut display = buffer(80, 4);
+        display.test_pattern(TestPattern::ColorBars);
+
+        for (bar, &color) in COLOR_BARS.iter().enumerate() {
+            let left = bar as i32 * 10;
+            for x in [left, left + 9] {
+                assert_eq!(display.get_pixel(x, 0), Some(color), "column {}", x);
+                assert_eq!(display.get_pixel(x, 3), Some(color), "column {}", x);
+            }
+        }
+        // White, yellow, then the last bar
+        assert_eq!(
+            display.get_pixel(0, 0),
+            Some(Pixel::from_rgba(255, 255, 255, 255))
+        );
+        assert_eq!(
+            display.get_pixel(10, 0),
+            Some(Pixel::from_rgba(255, 255, 0, 255))
+        );
+        assert_eq!(display.get_pixel(79, 0), Some(COLOR_BARS[7]));
+    }
+
+    #[test]
+    fn gradient_renderer_scrolls_by_velocity_times_dt() {
+        let mut scene = GradientRenderer::scrolling(40.0, -20.0);
+        let mut display = buffer(16, 8);
+        scene.render(&mut display, 0.25);
+        // 40 * 0.25 right, 20 * 0.25 up wrapping round
+        assert_eq!((scene.offset_x, scene.offset_y), (10.0, 251.0));
+        let scrolled = display.back.clone();
+
+        scene.render(&mut display, 0.25);
+        assert_eq!((scene.offset_x, scene.offset_y), (20.0, 246.0));
+
+        // Red follows x and blue y, so the unscrolled image sampled further along is the same
+        let mut still = buffer(32, 16);
+        still.render_gradient_xy(0.0, 0.0);
+        for (y, x) in [(0, 0), (3, 5), (7, 15)] {
+            let pixel = scrolled[y * 16 + x];
+            assert_eq!(pixel.r, still.get_pixel(x as i32 + 10, 0).unwrap().r);
+            assert_eq!(pixel.b, (y as u8).wrapping_sub(5));
+        }
+
+        // A negative dt stands still
+        scene.render(&mut display, -1.0);
+        assert_eq!((scene.offset_x, scene.offset_y), (20.0, 246.0));
+    }
+
+    #[test]
+    fn keyboard_edges_last_one_frame() {
+        const SPACE: KeyCode = 0x20;
+        const P: KeyCode = 0x50;
+        let mut keyboard = Keyboard::default();
+
+        // Frame 1: space goes down and auto-repeats, P is tapped
+        assert!(keyboard.key_down(SPACE));
+        assert!(!keyboard.key_down(SPACE));
+        assert!(!keyboard.key_down(SPACE));
+        assert!(keyboard.key_down(P));
+        keyboard.key_up(P);
+        assert!(keyboard.is_down(SPACE) && keyboard.was_pressed_this_frame(SPACE));
+        assert_eq!(keyboard.repeat_count(SPACE), 2);
+        assert!(!keyboard.is_down(P));
+        assert!(keyboard.was_pressed_this_frame(P) && keyboard.was_released_this_frame(P));
+        keyboard.end_frame();
+
+        // Frame 2: space is still held, but went down last frame
+        assert!(keyboard.is_down(SPACE));
+        assert!(!keyboard.was_pressed_this_frame(SPACE));
+        assert_eq!(keyboard.repeat_count(SPACE), 0);
+        assert!(!keyboard.was_pressed_this_frame(P) && !keyboard.was_released_this_frame(P));
+        keyboard.key_up(SPACE);
+        // A stray key-up for a key that isn't down
+        keyboard.key_up(P);
+        assert!(!keyboard.is_down(SPACE) && keyboard.was_released_this_frame(SPACE));
+        assert!(!keyboard.was_released_this_frame(P));
+        keyboard.end_frame();
+
+        assert_eq!(keyboard, Keyboard::default());
+
+        // Losing focus lets go of everything held
+        keyboard.key_down(SPACE);
+        keyboard.end_frame();
+        keyboard.release_all();
+        assert!(!keyboard.is_down(SPACE) && keyboard.was_released_this_frame(SPACE));
+    }
+
+    #[test]
+    fn extreme_volume_clips_instead_of_wrapping() {
+        assert_eq!(mix_sample(1e9), i16::MAX);
+        assert_eq!(mix_sample(-1e9), i16::MIN);
+        assert_eq!(mix_sample(f32::INFINITY), i16::MAX);
+        assert_eq!(mix_sample(f32::NEG_INFINITY), i16::MIN);
+        assert_eq!(mix_sample(f32::NAN), 0);
+        assert_eq!(mix_sample(-1234.7), -1234);
+
+        for volume in [1e9, f32::MAX] {
+            let mut samples = sound(48000, 1, 480, volume);
+            let mut phase = 0.0;
+            samples.render_sound(&mut phase, 440);
+            // Nearly a square wave at full scale, with the sine's sign
+            let period = 48000 / 440;
+            assert!(samples.samples[1..period / 2]
+                .iter()
+                .all(|&s| s == i16::MAX));
+            assert!(samples.samples[period / 2 + 1..period]
+                .iter()
+                .all(|&s| s == i16::MIN));
+            assert!(phase.is_finite());
+        }
+    }
+
+    #[test]
+    fn nan_phase_restarts_the_wave() {
+        let mut samples = sound(48000, 2, 480, 10000.0);
+        let mut phase = f32::NAN;
+        samples.render_sound(&mut phase, 440);
+        assert!((0.0..2.0 * f32::consts::PI).contains(&phase));
+        // Started over from zero
+        assert_eq!(samples.samples[..2], [0, 0]);
+        assert!(samples.samples.iter().any(|&sample| sample > 9000));
+        assert!(samples.samples.iter().all(|&sample| sample.abs() <= 10000));
+
+        let mut phase = f32::INFINITY;
+        samples.render_sound(&mut phase, 440);
+        assert!(phase.is_finite());
+    }
 }
@@ -0,0 +1,225 @@
+//! Runs the game without a window or sound device, e.g. to render reference images on any platform
+//! or to fuzz it with `simulate`
+
+use crate::game::*;
+
+/// Size of the buffer `run_headless` renders into, matching the default window
+pub const HEADLESS_WIDTH: i32 = 1280;
+pub const HEADLESS_HEIGHT: i32 = 720;
+const HEADLESS_SAMPLE_RATE: u16 = 48000;
+const HEADLESS_CHANNELS: u16 = 2;
+
+/// Runs `frames` frames from a fresh `GameState` and returns the display buffer, with the last frame
+/// left in `back` where `get_pixel` and `save_bmp` read it.
+///
+/// Frame `i` is fed `inputs[i]`. Frames past the end of `inputs` are idle: default input lasting
+/// one `FIXED_DT`. The sound is rendered as the platform layer would, then thrown away.
+pub fn run_headless(frames: usize, inputs: &[Input]) -> DisplayBuffer {
+    let idle = Input {
+        dt: FIXED_DT,
+        ..Input::default()
+    };
+
+    let (mut display_buffer, mut sound_buffer) = headless_buffers();
+    let mut game_state = GameState::default();
+    for frame in 0..frames {
+        let input = inputs.get(frame).unwrap_or(&idle);
+        run_frame(
+            &mut game_state,
+            input,
+            &mut display_buffer,
+            &mut sound_buffer,
+        );
+    }
+
+    display_buffer
+}
+
+/// Runs `frames` frames of made-up input from a fresh `GameState` and returns hashes of the
+/// display buffer's last frame and of the sound rendered for it. The same `seed` always gives the
+/// same input, and so the same hashes, which makes any panic or NaN it turns up reproducible.
+///
+/// The input covers everything a platform layer could send, pushed to the edges: frames from no
+/// time at all to long stalls, any tone, volumes past `MAX_VOLUME`, the mouse off the edges of
+/// the buffer, controllers coming and going, pausing and stepping.
+pub fn simulate(seed: u64, frames: u32) -> (u64, u64) {
+    // xorshift only keeps 32 bits of state, so fold the high half of the seed in
+    let mut rng = Rng::new((seed ^ (seed >> 32)) as u32);
+
+    let (mut display_buffer, mut sound_buffer) = headless_buffers();
+    let mut game_state = GameState::default();
+    for _ in 0..frames {
+        let input = random_input(&mut rng);
+        run_frame(
+            &mut game_state,
+            &input,
+            &mut display_buffer,
+            &mut sound_buffer,
+        );
+    }
+
+    let display_hash = fnv1a(
+        display_buffer
+            .back
+            .iter()
+            .flat_map(|pixel| pixel.to_u32().to_le_bytes()),
+    );
+    let sample_len = sound_buffer.sample_count * usize::from(sound_buffer.channels);
+    let sound_hash = fnv1a(
+        sound_buffer.samples[..sample_len]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes()),
+    );
+    (display_hash, sound_hash)
+}
+
+/// One frame of `simulate`'s input
+fn random_input(rng: &mut Rng) -> Input {
+    let mut harmonics = [false; HARMONIC_COUNT];
+    for harmonic in &mut harmonics {
+        *harmonic = rng.next_u32().is_multiple_of(2);
+    }
+    let muted = rng.next_u32().is_multiple_of(8);
+    let tone_enabled = !rng.next_u32().is_multiple_of(8);
+    let paused = rng.next_u32().is_multiple_of(16);
+
+    let mut gamepads = [GamepadInput::default(); MAX_GAMEPADS];
+    for gamepad in &mut gamepads {
+        if rng.next_u32().is_multiple_of(2) {
+            continue;
+        }
+        *gamepad = GamepadInput {
+            connected: true,
+            a: rng.next_u32().is_multiple_of(2),
+            left_trigger: rng.next_f32(),
+            right_trigger: rng.next_f32(),
+            left_stick_x: rng.range(-1.0, 1.0),
+            left_stick_y: rng.range(-1.0, 1.0),
+            right_stick_x: rng.range(-1.0, 1.0),
+            right_stick_y: rng.range(-1.0, 1.0),
+            ..GamepadInput::default()
+        };
+    }
+
+    // A few pixels beyond every edge, where the cursor has to be clipped
+    let margin = 16;
+    let mouse = MouseInput {
+        x: (rng.next_u32() % (HEADLESS_WIDTH + 2 * margin) as u32) as i32 - margin,
+        y: (rng.next_u32() % (HEADLESS_HEIGHT + 2 * margin) as u32) as i32 - margin,
+        left: rng.next_u32().is_multiple_of(2),
+        right: rng.next_u32().is_multiple_of(4),
+    };
+
+    Input {
+        tone_hz: rng.next_u32() as u16,
+        volume: rng.range(0.0, 2.0 * MAX_VOLUME),
+        muted,
+        tone_enabled,
+        harmonics,
+        gamepad: GamepadInput::combine(&gamepads),
+        gamepads,
+        mouse,
+        paused,
+        step_frames: rng.next_u32() % 3,
+        // Mostly ordinary frames, now and then one long enough to hit the update limit
+        dt: if rng.next_u32().is_multiple_of(32) {
+            rng.range(0.0, 0.5)
+        } else {
+            rng.range(0.0, 4.0 * FIXED_DT)
+        },
+        ..Input::default()
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is the same on every platform and Rust version
+fn fnv1a<I: IntoIterator<Item = u8>>(bytes: I) -> u64 {
+    bytes.into_iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+/// A buffer the size of the default window and a sound buffer at a typical rate, both empty
+fn headless_buffers() -> (DisplayBuffer, SoundBuffer) {
+    let display_buffer = DisplayBuffer {
+        back: vec![Pixel::default(); HEADLESS_WIDTH as usize * HEADLESS_HEIGHT as usize],
+        front: Vec::new(),
+        gradient_mode: GradientMode::FullRgb,
+        blend_mode: BlendMode::Linear,
+        width: HEADLESS_WIDTH,
+        height: HEADLESS_HEIGHT,
+    };
+    let sound_buffer = SoundBuffer {
+        samples: Vec::new(),
+        sample_count: 0,
+        volume: 0.0,
+        muted: false,
+        tone_enabled: true,
+        sample_rate: HEADLESS_SAMPLE_RATE,
+        channels: HEADLESS_CHANNELS,
+        waveform: Waveform::Sine,
+        sample_clock: 0,
+        // 10ms
+        ramp_samples: u32::from(HEADLESS_SAMPLE_RATE) / 100,
+        tone_ramp: Ramp::new(0.0),
+        volume_ramp: Ramp::new(0.0),
+        clip: None,
+        oscillators: Vec::new(),
+        filter_cutoff: 8000.0,
+        filter_state: 0.0,
+    };
+    (display_buffer, sound_buffer)
+}
+
+/// Runs one frame, rendering as many samples as it lasted like a sound device being kept exactly
+/// topped up. The sound is thrown away by the next frame.
+fn run_frame(
+    game_state: &mut GameState,
+    input: &Input,
+    display_buffer: &mut DisplayBuffer,
+    sound_buffer: &mut SoundBuffer,
+) {
+    sound_buffer.sample_count =
+        (f32::from(HEADLESS_SAMPLE_RATE) * input.dt.max(0.0)).round() as usize;
+    let sample_len = sound_buffer.sample_count * usize::from(HEADLESS_CHANNELS);
+    if sound_buffer.samples.len() < sample_len {
+        sound_buffer.samples.resize(sample_len, 0);
+    }
+
+    game_state.tick(input, display_buffer, sound_buffer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ten_idle_frames_fade_in_the_scrolled_gradient() {
+        let display_buffer = run_headless(10, &[]);
+        assert_eq!(
+            display_buffer.dimensions(),
+            (HEADLESS_WIDTH, HEADLESS_HEIGHT)
+        );
+
+        // Ten updates scroll the gradient 5 pixels and are 1/12 of the way through the intro
+        // fade, so (100, 50), at (105, 81, 55) before fading, comes out at a twelfth of that
+        assert_eq!(
+            display_buffer.get_pixel(100, 50),
+            Some(Pixel::from_rgba(9, 7, 5, 0))
+        );
+        assert_eq!(
+            display_buffer.get_pixel(HEADLESS_WIDTH - 1, HEADLESS_HEIGHT - 1),
+            Some(Pixel::from_rgba(0, 4, 18, 0))
+        );
+        // The cursor dot, drawn over the fade at the default mouse position
+        assert_eq!(
+            display_buffer.get_pixel(0, 0),
+            Some(Pixel::from_rgba(255, 255, 255, 255))
+        );
+    }
+
+    #[test]
+    fn simulation_is_deterministic_per_seed() {
+        assert_eq!(simulate(42, 10), simulate(42, 10));
+        assert_ne!(simulate(42, 10), simulate(43, 10));
+    }
+}
@@ -0,0 +1,49 @@
+//! Platform-independent game code. Built as an rlib the platform layer links for the shared types
+//! and a fallback copy of the code, and as a DLL it loads at runtime and reloads whenever it is
+//! rebuilt (`cargo build --lib` while the game runs).
+//!
+//! The DLL exports `game_update` and `game_render`, which work like `update` and `render` on the
+//! `GameState` kept in `GameMemory`. Their arguments are Rust types without a stable layout, so
+//! the DLL must be built by the same compiler from the same version of this crate as the
+//! executable. Both sides allocate from the process heap, so `Vec`s can be grown on either side
+//! of the boundary.
+
+mod capture;
+mod font;
+mod game;
+mod headless;
+mod memory;
+
+pub use capture::*;
+pub use font::*;
+pub use game::*;
+pub use headless::*;
+pub use memory::*;
+
+/// Signature of `game_update`
+pub type UpdateFn = unsafe extern "C" fn(&mut GameMemory, &Input, f32);
+/// Signature of `game_render`
+pub type RenderFn =
+    unsafe extern "C" fn(&mut GameMemory, &Input, f32, &mut DisplayBuffer, &mut SoundBuffer);
+
+#[no_mangle]
+pub extern "C" fn game_update(memory: &mut GameMemory, input: &Input, dt: f32) {
+    update(memory.game_state(), input, dt);
+}
+
+#[no_mangle]
+pub extern "C" fn game_render(
+    memory: &mut GameMemory,
+    input: &Input,
+    alpha: f32,
+    display_buffer: &mut DisplayBuffer,
+    sound_buffer: &mut SoundBuffer,
+) {
+    render(
+        memory.game_state(),
+        input,
+        alpha,
+        display_buffer,
+        sound_buffer,
+    );
+}
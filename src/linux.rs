@@ -0,0 +1,643 @@
+//! Xlib platform layer. Opens a window, maps the same keys as the Win32 layer and presents the
+//! backbuffer with `XPutImage`. Sound needs the `cpal` feature.
+
+// Xlib constants keep their C names, also where they are matched on
+#![allow(non_upper_case_globals)]
+
+#[cfg(feature = "cpal")]
+use crate::cpal_audio::CpalAudio;
+use crate::{Options, PresentMode, SettingsWatcher};
+use game::*;
+use std::{
+    ffi::CString,
+    io,
+    os::raw::{c_char, c_int, c_uint},
+    ptr, thread,
+    time::{Duration, Instant},
+};
+
+/// The parts of Xlib this layer uses, declared by hand like the Win32 API is through `winapi`
+#[allow(non_snake_case)]
+mod xlib {
+    use std::os::raw::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
+
+    pub type Display = c_void;
+    pub type GC = *mut c_void;
+    pub type Window = c_ulong;
+    pub type Atom = c_ulong;
+    pub type KeySym = c_ulong;
+    pub type Bool = c_int;
+    pub type Status = c_int;
+
+    pub const True: Bool = 1;
+    pub const False: Bool = 0;
+
+    pub const KeyPress: c_int = 2;
+    pub const KeyRelease: c_int = 3;
+    pub const ButtonPress: c_int = 4;
+    pub const ButtonRelease: c_int = 5;
+    pub const MotionNotify: c_int = 6;
+    pub const ConfigureNotify: c_int = 22;
+    pub const ClientMessage: c_int = 33;
+
+    pub const KeyPressMask: c_long = 1 << 0;
+    pub const KeyReleaseMask: c_long = 1 << 1;
+    pub const ButtonPressMask: c_long = 1 << 2;
+    pub const ButtonReleaseMask: c_long = 1 << 3;
+    pub const PointerMotionMask: c_long = 1 << 6;
+    pub const StructureNotifyMask: c_long = 1 << 17;
+
+    pub const Button1: c_uint = 1;
+    pub const Button3: c_uint = 3;
+    /// The wheel presses these, one click per notch
+    pub const Button4: c_uint = 4;
+    pub const Button5: c_uint = 5;
+
+    pub const ZPixmap: c_int = 2;
+    pub const LSBFirst: c_int = 0;
+
+    pub const XK_space: KeySym = 0x0020;
+    pub const XK_plus: KeySym = 0x002b;
+    pub const XK_minus: KeySym = 0x002d;
+    pub const XK_period: KeySym = 0x002e;
+    pub const XK_1: KeySym = 0x0031;
+    pub const XK_5: KeySym = 0x0035;
+    pub const XK_equal: KeySym = 0x003d;
+    pub const XK_l: KeySym = 0x006c;
+    pub const XK_m: KeySym = 0x006d;
+    pub const XK_p: KeySym = 0x0070;
+    pub const XK_Escape: KeySym = 0xff1b;
+    pub const XK_Up: KeySym = 0xff52;
+    pub const XK_Down: KeySym = 0xff54;
+    pub const XK_KP_Add: KeySym = 0xffab;
+    pub const XK_KP_Subtract: KeySym = 0xffad;
+
+    #[repr(C)]
+    pub struct XImage {
+        pub width: c_int,
+        pub height: c_int,
+        pub xoffset: c_int,
+        pub format: c_int,
+        pub data: *mut c_char,
+        pub byte_order: c_int,
+        pub bitmap_unit: c_int,
+        pub bitmap_bit_order: c_int,
+        pub bitmap_pad: c_int,
+        pub depth: c_int,
+        pub bytes_per_line: c_int,
+        pub bits_per_pixel: c_int,
+        pub red_mask: c_ulong,
+        pub green_mask: c_ulong,
+        pub blue_mask: c_ulong,
+        pub obdata: *mut c_char,
+        /// Image manipulation routines, filled in by `XInitImage`
+        pub f: [*mut c_void; 6],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct XKeyEvent {
+        pub type_: c_int,
+        pub serial: c_ulong,
+        pub send_event: Bool,
+        pub display: *mut Display,
+        pub window: Window,
+        pub root: Window,
+        pub subwindow: Window,
+        pub time: c_ulong,
+        pub x: c_int,
+        pub y: c_int,
+        pub x_root: c_int,
+        pub y_root: c_int,
+        pub state: c_uint,
+        pub keycode: c_uint,
+        pub same_screen: Bool,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct XButtonEvent {
+        pub type_: c_int,
+        pub serial: c_ulong,
+        pub send_event: Bool,
+        pub display: *mut Display,
+        pub window: Window,
+        pub root: Window,
+        pub subwindow: Window,
+        pub time: c_ulong,
+        pub x: c_int,
+        pub y: c_int,
+        pub x_root: c_int,
+        pub y_root: c_int,
+        pub state: c_uint,
+        pub button: c_uint,
+        pub same_screen: Bool,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct XMotionEvent {
+        pub type_: c_int,
+        pub serial: c_ulong,
+        pub send_event: Bool,
+        pub display: *mut Display,
+        pub window: Window,
+        pub root: Window,
+        pub subwindow: Window,
+        pub time: c_ulong,
+        pub x: c_int,
+        pub y: c_int,
+        pub x_root: c_int,
+        pub y_root: c_int,
+        pub state: c_uint,
+        pub is_hint: c_char,
+        pub same_screen: Bool,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct XConfigureEvent {
+        pub type_: c_int,
+        pub serial: c_ulong,
+        pub send_event: Bool,
+        pub display: *mut Display,
+        pub event: Window,
+        pub window: Window,
+        pub x: c_int,
+        pub y: c_int,
+        pub width: c_int,
+        pub height: c_int,
+        pub border_width: c_int,
+        pub above: Window,
+        pub override_redirect: Bool,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct XClientMessageEvent {
+        pub type_: c_int,
+        pub serial: c_ulong,
+        pub send_event: Bool,
+        pub display: *mut Display,
+        pub window: Window,
+        pub message_type: Atom,
+        pub format: c_int,
+        pub data: [c_long; 5],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub union XEvent {
+        pub type_: c_int,
+        pub key: XKeyEvent,
+        pub button: XButtonEvent,
+        pub motion: XMotionEvent,
+        pub configure: XConfigureEvent,
+        pub client_message: XClientMessageEvent,
+        pub pad: [c_long; 24],
+    }
+
+    #[link(name = "X11")]
+    extern "C" {
+        pub fn XOpenDisplay(name: *const c_char) -> *mut Display;
+        pub fn XCloseDisplay(display: *mut Display) -> c_int;
+        pub fn XDefaultScreen(display: *mut Display) -> c_int;
+        pub fn XRootWindow(display: *mut Display, screen: c_int) -> Window;
+        pub fn XDefaultDepth(display: *mut Display, screen: c_int) -> c_int;
+        pub fn XDefaultGC(display: *mut Display, screen: c_int) -> GC;
+        pub fn XBlackPixel(display: *mut Display, screen: c_int) -> c_ulong;
+        pub fn XCreateSimpleWindow(
+            display: *mut Display,
+            parent: Window,
+            x: c_int,
+            y: c_int,
+            width: c_uint,
+            height: c_uint,
+            border_width: c_uint,
+            border: c_ulong,
+            background: c_ulong,
+        ) -> Window;
+        pub fn XDestroyWindow(display: *mut Display, window: Window) -> c_int;
+        pub fn XSelectInput(display: *mut Display, window: Window, event_mask: c_long) -> c_int;
+        pub fn XStoreName(display: *mut Display, window: Window, name: *const c_char) -> c_int;
+        pub fn XMapWindow(display: *mut Display, window: Window) -> c_int;
+        pub fn XInternAtom(
+            display: *mut Display,
+            name: *const c_char,
+            only_if_exists: Bool,
+        ) -> Atom;
+        pub fn XSetWMProtocols(
+            display: *mut Display,
+            window: Window,
+            protocols: *mut Atom,
+            count: c_int,
+        ) -> Status;
+        pub fn XkbSetDetectableAutoRepeat(
+            display: *mut Display,
+            detectable: Bool,
+            supported: *mut Bool,
+        ) -> Bool;
+        pub fn XPending(display: *mut Display) -> c_int;
+        pub fn XNextEvent(display: *mut Display, event: *mut XEvent) -> c_int;
+        pub fn XLookupKeysym(event: *mut XKeyEvent, index: c_int) -> KeySym;
+        pub fn XInitImage(image: *mut XImage) -> Status;
+        pub fn XPutImage(
+            display: *mut Display,
+            drawable: Window,
+            gc: GC,
+            image: *mut XImage,
+            src_x: c_int,
+            src_y: c_int,
+            dest_x: c_int,
+            dest_y: c_int,
+            width: c_uint,
+            height: c_uint,
+        ) -> c_int;
+        pub fn XFlush(display: *mut Display) -> c_int;
+    }
+}
+
+use xlib::*;
+
+/// Frames per second the loop sleeps to. There is no vsync to follow under plain Xlib.
+const TARGET_FPS: u32 = 60;
+const VOLUME_STEP: f32 = 500.0;
+
+/// Input state gathered from X events for the next frame
+struct WindowState {
+    tone_hz: u16,
+    /// Tone keys held down, applied to `tone_hz` once per frame
+    tone_keys: ToneKeys,
+    volume: f32,
+    muted: bool,
+    tone_enabled: bool,
+    harmonics: [bool; HARMONIC_COUNT],
+    mouse: MouseInput,
+    /// Tone change per wheel notch
+    wheel_step_hz: u16,
+    /// Keys held and changed this frame, also telling auto-repeat from fresh presses
+    keyboard: Keyboard,
+    replay_toggle_requested: bool,
+    paused: bool,
+    /// Step key presses while paused, consumed by the next frame
+    step_frames: u32,
+    running: bool,
+}
+
+impl WindowState {
+    /// Same bindings as the Win32 layer's `handle_key_press`
+    fn handle_key_press(&mut self, key: KeySym) {
+        let was_down = !self.keyboard.key_down(key as KeyCode);
+        match key {
+            // The main loop slides the tone for as long as these are held
+            XK_Up => self.tone_keys.raise_held = true,
+            XK_Down => self.tone_keys.lower_held = true,
+            // Ignore auto-repeat so holding the key doesn't flicker
+            XK_m if !was_down => self.muted = !self.muted,
+            XK_space if !was_down => self.tone_enabled = !self.tone_enabled,
+            XK_l if !was_down => self.replay_toggle_requested = true,
+            XK_p if !was_down => self.paused = !self.paused,
+            XK_period if !was_down && self.paused => self.step_frames += 1,
+            XK_plus | XK_equal | XK_KP_Add => {
+                self.volume = (self.volume + VOLUME_STEP).min(MAX_VOLUME)
+            }
+            XK_minus | XK_KP_Subtract => self.volume = (self.volume - VOLUME_STEP).max(0.0),
+            XK_1..=XK_5 if !was_down => {
+                let harmonic = &mut self.harmonics[(key - XK_1) as usize];
+                *harmonic = !*harmonic;
+            }
+            XK_Escape => self.running = false,
+            _ => (),
+        }
+    }
+
+    fn handle_key_release(&mut self, key: KeySym) {
+        self.keyboard.key_up(key as KeyCode);
+        match key {
+            XK_Up => self.tone_keys.raise_held = false,
+            XK_Down => self.tone_keys.lower_held = false,
+            _ => (),
+        }
+    }
+
+    fn handle_button(&mut self, button: c_uint, is_down: bool) {
+        match button {
+            Button1 => self.mouse.left = is_down,
+            Button3 => self.mouse.right = is_down,
+            Button4 | Button5 if is_down => {
+                let step = if button == Button4 {
+                    i32::from(self.wheel_step_hz)
+                } else {
+                    -i32::from(self.wheel_step_hz)
+                };
+                self.tone_hz = (i32::from(self.tone_hz) + step)
+                    .clamp(i32::from(MIN_TONE_HZ), i32::from(u16::MAX))
+                    as u16;
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Creates the XImage describing `buffer`'s front buffer. Only valid until the buffer is resized
+/// or swapped.
+fn front_image(buffer: &DisplayBuffer, depth: c_int) -> XImage {
+    let mut image = XImage {
+        width: buffer.width,
+        height: buffer.height,
+        xoffset: 0,
+        format: ZPixmap,
+        // XPutImage only reads the data
+        data: buffer.front.as_ptr() as *mut c_char,
+        // `Pixel`s are stored as little-endian 0xAARRGGBB
+        byte_order: LSBFirst,
+        bitmap_unit: 32,
+        bitmap_bit_order: LSBFirst,
+        bitmap_pad: 32,
+        depth,
+        bytes_per_line: buffer.width * std::mem::size_of::<Pixel>() as c_int,
+        bits_per_pixel: 32,
+        red_mask: 0x00ff_0000,
+        green_mask: 0x0000_ff00,
+        blue_mask: 0x0000_00ff,
+        obdata: ptr::null_mut(),
+        f: [ptr::null_mut(); 6],
+    };
+    unsafe { XInitImage(&mut image) };
+    image
+}
+
+pub fn linux_main(options: &Options, mut settings: SettingsWatcher) -> io::Result<()> {
+    let display = unsafe { XOpenDisplay(ptr::null()) };
+    if display.is_null() {
+        return Err(io::Error::other(
+            "Could not open the X display, is DISPLAY set?",
+        ));
+    }
+
+    let screen = unsafe { XDefaultScreen(display) };
+    let depth = unsafe { XDefaultDepth(display, screen) };
+    // The backbuffer is 32-bit xRGB, which only maps directly onto true-colour visuals
+    if depth != 24 && depth != 32 {
+        unsafe { XCloseDisplay(display) };
+        return Err(io::Error::other(format!(
+            "Unsupported display depth {}, need 24 or 32 bits",
+            depth
+        )));
+    }
+
+    let window = unsafe {
+        XCreateSimpleWindow(
+            display,
+            XRootWindow(display, screen),
+            0,
+            0,
+            options.width as c_uint,
+            options.height as c_uint,
+            0,
+            XBlackPixel(display, screen),
+            XBlackPixel(display, screen),
+        )
+    };
+    let title = CString::new("Again").unwrap();
+    let delete_window_name = CString::new("WM_DELETE_WINDOW").unwrap();
+    let mut delete_window = unsafe { XInternAtom(display, delete_window_name.as_ptr(), False) };
+    unsafe {
+        XStoreName(display, window, title.as_ptr());
+        XSelectInput(
+            display,
+            window,
+            KeyPressMask
+                | KeyReleaseMask
+                | ButtonPressMask
+                | ButtonReleaseMask
+                | PointerMotionMask
+                | StructureNotifyMask,
+        );
+        // Closing the window asks us to quit instead of killing the connection
+        XSetWMProtocols(display, window, &mut delete_window, 1);
+        // Held keys repeat presses only, without a release in between
+        XkbSetDetectableAutoRepeat(display, True, ptr::null_mut());
+        XMapWindow(display, window);
+    }
+    let gc = unsafe { XDefaultGC(display, screen) };
+
+    let mut state = WindowState {
+        tone_hz: options.tone_hz,
+        tone_keys: ToneKeys::default(),
+        volume: dbfs_to_amplitude(options.volume_dbfs),
+        muted: false,
+        tone_enabled: true,
+        harmonics: [false; HARMONIC_COUNT],
+        mouse: MouseInput::default(),
+        wheel_step_hz: options.wheel_step_hz,
+        keyboard: Keyboard::default(),
+        replay_toggle_requested: false,
+        paused: false,
+        step_frames: 0,
+        running: true,
+    };
+
+    let mut display_buffer = DisplayBuffer {
+        back: Vec::new(),
+        front: Vec::new(),
+        gradient_mode: GradientMode::FullRgb,
+        blend_mode: BlendMode::Linear,
+        width: 0,
+        height: 0,
+    };
+    display_buffer.resize(options.width, options.height);
+
+    // Never asked for samples, it only carries the game's sound settings to the audio callback
+    let mut sound_buffer = SoundBuffer {
+        samples: Vec::new(),
+        sample_count: 0,
+        volume: state.volume,
+        muted: false,
+        tone_enabled: true,
+        sample_rate: 48000,
+        channels: 2,
+        waveform: Waveform::Sine,
+        sample_clock: 0,
+        ramp_samples: 480,
+        tone_ramp: Ramp::new(f32::from(state.tone_hz)),
+        volume_ramp: Ramp::new(0.0),
+        clip: None,
+        oscillators: Vec::new(),
+        // Off for the test tone, which has to come out at exactly its level
+        filter_cutoff: if options.test_tone {
+            f32::INFINITY
+        } else {
+            8000.0
+        },
+        filter_state: 0.0,
+    };
+    #[cfg(feature = "cpal")]
+    let audio = match CpalAudio::open(&sound_buffer, state.tone_hz) {
+        Ok(audio) => Some(audio),
+        Err(error) => {
+            // Not fatal - the game is still playable without sound
+            eprintln!("Running without sound: {}", error);
+            None
+        }
+    };
+
+    let mut game_state = GameState::default();
+    let mut replay = InputReplay::default();
+    let mut frame_rate = FrameRateCounter::default();
+    let mut frame_times = FrameTimeHistory::default();
+    let mut present_mode = options.present_mode;
+    let mut recorder = options
+        .record_frames
+        .then(|| FrameRecorder::new(RECORDING_DIRECTORY));
+    let target_frame = Duration::from_secs(1) / TARGET_FPS;
+    let mut last_frame_seconds = target_frame.as_secs_f32();
+
+    while state.running {
+        let frame_start = Instant::now();
+
+        while unsafe { XPending(display) } > 0 {
+            let mut event = unsafe { std::mem::zeroed::<XEvent>() };
+            unsafe { XNextEvent(display, &mut event) };
+            // The union member read always matches the event type
+            match unsafe { event.type_ } {
+                KeyPress => {
+                    let key = unsafe { XLookupKeysym(&mut event.key, 0) };
+                    state.handle_key_press(key);
+                }
+                KeyRelease => {
+                    let key = unsafe { XLookupKeysym(&mut event.key, 0) };
+                    state.handle_key_release(key);
+                }
+                ButtonPress | ButtonRelease => {
+                    let button = unsafe { event.button };
+                    state.handle_button(button.button, button.type_ == ButtonPress);
+                }
+                MotionNotify => {
+                    let motion = unsafe { event.motion };
+                    state.mouse.x = motion.x;
+                    state.mouse.y = motion.y;
+                }
+                ConfigureNotify => {
+                    let configure = unsafe { event.configure };
+                    if display_buffer.needs_resize(configure.width, configure.height) {
+                        display_buffer.resize(configure.width, configure.height);
+                    }
+                }
+                ClientMessage => {
+                    let message = unsafe { event.client_message };
+                    if message.data[0] as Atom == delete_window {
+                        state.running = false;
+                    }
+                }
+                _ => (),
+            }
+        }
+        if !state.running {
+            break;
+        }
+
+        // Pick up edits to the settings file. The command line still overrides them.
+        let changed_settings = settings.poll();
+        if !changed_settings.is_empty() {
+            let live = Options::from_settings(settings.settings());
+            for &field in changed_settings.iter() {
+                match field {
+                    "tone_hz" => state.tone_hz = live.tone_hz,
+                    "volume_dbfs" => state.volume = dbfs_to_amplitude(live.volume_dbfs),
+                    "present_mode" => present_mode = live.present_mode,
+                    "wheel_step_hz" => state.wheel_step_hz = live.wheel_step_hz,
+                    // The window size only applies at startup, the audio latency to DirectSound
+                    _ => (),
+                }
+            }
+        }
+
+        state.tone_hz = state.tone_keys.advance(state.tone_hz, last_frame_seconds);
+        let input = Input {
+            tone_hz: state.tone_hz,
+            volume: state.volume,
+            muted: state.muted,
+            tone_enabled: state.tone_enabled,
+            harmonics: state.harmonics,
+            gamepad: GamepadInput::default(),
+            gamepads: [GamepadInput::default(); MAX_GAMEPADS],
+            mouse: state.mouse,
+            keyboard: state.keyboard.clone(),
+            // Typed text isn't read from X yet
+            text: String::new(),
+            paused: state.paused,
+            step_frames: std::mem::take(&mut state.step_frames),
+            dt: last_frame_seconds,
+        };
+        if std::mem::replace(&mut state.replay_toggle_requested, false) {
+            replay.toggle(&mut game_state, &mut sound_buffer);
+        }
+        state.keyboard.end_frame();
+        let input = replay.process(input, &mut game_state, &mut sound_buffer);
+        let input = if options.test_tone {
+            input.with_test_tone()
+        } else {
+            input
+        };
+
+        game_state.tick(&input, &mut display_buffer, &mut sound_buffer);
+        #[cfg(feature = "cpal")]
+        if let Some(audio) = &audio {
+            audio.update(&sound_buffer, game_state.tone_hz, input.paused);
+        }
+
+        if let (true, Some(fps)) = (options.show_fps, frame_rate.fps()) {
+            let text = format!("{:.0} fps", fps);
+            // The shadow keeps the text readable on any part of the gradient
+            display_buffer.draw_text(5, 5, &text, Pixel::from_rgba(0, 0, 0, 255));
+            display_buffer.draw_text(4, 4, &text, Pixel::from_rgba(255, 255, 255, 255));
+        }
+        if options.show_frame_times {
+            let bottom = display_buffer.height - 4;
+            frame_times.draw(&mut display_buffer, 4, bottom, target_frame.as_secs_f32());
+        }
+
+        if let Some(frame_recorder) = &mut recorder {
+            if let Err(error) = frame_recorder.record(&display_buffer) {
+                // Not fatal - keep playing, just stop recording
+                eprintln!("Stopped recording: {}", error);
+                recorder = None;
+            }
+        }
+
+        display_buffer.swap();
+        let mut image = front_image(&display_buffer, depth);
+        unsafe {
+            XPutImage(
+                display,
+                window,
+                gc,
+                &mut image,
+                0,
+                0,
+                0,
+                0,
+                display_buffer.width as c_uint,
+                display_buffer.height as c_uint,
+            );
+            XFlush(display);
+        }
+
+        // Without vsync to wait on, vsync is the same as the cap
+        if present_mode != PresentMode::Immediate {
+            if let Some(remaining) = target_frame.checked_sub(frame_start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+        last_frame_seconds = frame_start.elapsed().as_secs_f32();
+        frame_rate.record(last_frame_seconds);
+        frame_times.record(last_frame_seconds);
+    }
+
+    unsafe {
+        XDestroyWindow(display, window);
+        XCloseDisplay(display);
+    }
+    Ok(())
+}
@@ -1,16 +1,355 @@
+#[cfg(windows)]
 #[macro_use]
 extern crate static_assertions;
 
-mod game;
-
+#[cfg(feature = "cpal")]
+mod cpal_audio;
+#[cfg(target_os = "linux")]
+mod linux;
+mod settings;
 #[cfg(windows)]
 mod win32;
 
-use std::io;
+pub use settings::SettingsWatcher;
+use settings::{Settings, SETTINGS_PATH};
+use std::{fs, io, path::Path, time::SystemTime};
+
+/// Initial client area in logical pixels when none is given on the command line
+const DEFAULT_WIDTH: i32 = 1280;
+const DEFAULT_HEIGHT: i32 = 720;
+/// Tone change per mouse wheel notch, as far as a tap of Up or Down used to move it
+const DEFAULT_WHEEL_STEP_HZ: u16 = 64;
+/// Tone played at startup unless the settings file says otherwise
+const DEFAULT_TONE_HZ: u16 = 512;
+
+/// How finished frames are paced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresentMode {
+    /// Present as soon as a frame is done and start the next straight away. Frames that land
+    /// mid-refresh tear, and the loop uses a whole core.
+    Immediate,
+    /// Wait for the compositor's next frame before presenting (Windows 8+, or Vista and 7 with
+    /// composition on), so the loop runs at the display's refresh rate without tearing. Falls
+    /// back to `CappedFps` where there is no compositor to wait on.
+    Vsync,
+    /// Present immediately, then sleep out the rest of a fixed-length frame. Keeps a steady rate
+    /// on any display, but isn't lined up with its refresh.
+    CappedFps,
+}
+
+impl PresentMode {
+    /// The mode `--present` and the settings file call `name`: `immediate`, `vsync` or `capped`
+    fn from_name(name: &str) -> Option<PresentMode> {
+        match name {
+            "immediate" => Some(PresentMode::Immediate),
+            "vsync" => Some(PresentMode::Vsync),
+            "capped" => Some(PresentMode::CappedFps),
+            _ => None,
+        }
+    }
+}
+
+/// Settings taken from the settings file and the command line
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// `--show-stats`: print frame timings to the debugger output every frame
+    pub show_stats: bool,
+    /// `--show-fps`: draw a smoothed frame rate into the top-left corner of the backbuffer
+    pub show_fps: bool,
+    /// `--show-frametimes`: draw a bar graph of recent frame times into the bottom-left corner
+    pub show_frame_times: bool,
+    /// `--icon <path>`: .ico file replacing the built-in window icon
+    pub icon_path: Option<String>,
+    /// `--width <pixels>` and `--height <pixels>`: initial client area in logical pixels
+    pub width: i32,
+    pub height: i32,
+    /// `--fullscreen`: start in borderless fullscreen
+    pub fullscreen: bool,
+    /// `--stretch`: fill the window even if that distorts the image, instead of letterboxing
+    pub stretch: bool,
+    /// `--render-resolution <width>x<height>`: keep the backbuffer at this size whatever the
+    /// window size, scaled up by the largest whole number that fits
+    pub render_resolution: Option<(i32, i32)>,
+    /// `--confirm-exit`: ask before closing the window or quitting with a key
+    pub confirm_on_exit: bool,
+    /// `--sample-format <16|24|float>`: how samples are written to the DirectSound buffer
+    pub sample_format: game::SampleFormat,
+    /// `--wheel-step <hz>`: how far one notch of the mouse wheel moves the tone
+    pub wheel_step_hz: u16,
+    /// `--present <immediate|vsync|capped>`: how frames are paced
+    pub present_mode: PresentMode,
+    /// `--test-tone`: play a fixed calibration tone instead of the one the controls set
+    pub test_tone: bool,
+    /// `--record`: save every presented frame to `frames/NNNNN.png`
+    pub record_frames: bool,
+    /// `--audio-latency-ms <ms>`: keep this much sound queued ahead of the DirectSound play
+    /// cursor instead of following the frame time, up to half the sound buffer
+    pub audio_latency_ms: Option<u32>,
+    /// Tone played at startup, only set from the settings file
+    pub tone_hz: u16,
+    /// Volume at startup in dBFS, only set from the settings file
+    pub volume_dbfs: f32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            show_stats: false,
+            show_fps: false,
+            show_frame_times: false,
+            icon_path: None,
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            fullscreen: false,
+            stretch: false,
+            render_resolution: None,
+            confirm_on_exit: false,
+            sample_format: game::SampleFormat::Pcm16,
+            wheel_step_hz: DEFAULT_WHEEL_STEP_HZ,
+            present_mode: PresentMode::CappedFps,
+            test_tone: false,
+            record_frames: false,
+            audio_latency_ms: None,
+            tone_hz: DEFAULT_TONE_HZ,
+            volume_dbfs: game::DEFAULT_VOLUME_DBFS,
+        }
+    }
+}
+
+impl Options {
+    /// Applies `args` on top of `defaults`, usually the settings file's. `args` excludes the
+    /// program name. Unknown arguments are ignored, and a value that is missing, malformed or out
+    /// of range keeps what `defaults` has for it.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn parse<I: IntoIterator<Item = String>>(defaults: Options, args: I) -> Options {
+        let parse_size = |value: Option<String>| {
+            value
+                .and_then(|value| value.parse::<i32>().ok())
+                .filter(|&size| size > 0)
+        };
+
+        let mut options = defaults;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--show-stats" => options.show_stats = true,
+                "--show-fps" => options.show_fps = true,
+                "--show-frametimes" => options.show_frame_times = true,
+                "--fullscreen" => options.fullscreen = true,
+                "--stretch" => options.stretch = true,
+                "--confirm-exit" => options.confirm_on_exit = true,
+                "--test-tone" => options.test_tone = true,
+                "--record" => options.record_frames = true,
+                "--icon" => options.icon_path = args.next(),
+                "--present" => {
+                    options.present_mode = args
+                        .next()
+                        .and_then(|name| PresentMode::from_name(&name))
+                        .unwrap_or(options.present_mode)
+                }
+                "--sample-format" => {
+                    options.sample_format = match args.next().as_deref() {
+                        Some("16") => game::SampleFormat::Pcm16,
+                        Some("24") => game::SampleFormat::Pcm24,
+                        Some("float") => game::SampleFormat::Float32,
+                        _ => options.sample_format,
+                    }
+                }
+                "--width" => options.width = parse_size(args.next()).unwrap_or(options.width),
+                "--height" => options.height = parse_size(args.next()).unwrap_or(options.height),
+                "--wheel-step" => {
+                    options.wheel_step_hz = args
+                        .next()
+                        .and_then(|value| value.parse::<u16>().ok())
+                        .unwrap_or(options.wheel_step_hz)
+                }
+                "--audio-latency-ms" => {
+                    options.audio_latency_ms = args
+                        .next()
+                        .and_then(|value| value.parse().ok())
+                        .filter(|&latency_ms| latency_ms > 0)
+                        .or(options.audio_latency_ms)
+                }
+                "--render-resolution" => {
+                    options.render_resolution = args
+                        .next()
+                        .and_then(|value| {
+                            let (width, height) = value.split_once('x')?;
+                            Some((
+                                parse_size(Some(width.to_string()))?,
+                                parse_size(Some(height.to_string()))?,
+                            ))
+                        })
+                        .or(options.render_resolution)
+                }
+                _ => (),
+            }
+        }
+        options
+    }
+
+    /// `settings` with the command line on top. Starts over from the built-in defaults, so a
+    /// setting taken out of the file goes back to its default rather than its old value.
+    fn from_settings(settings: &Settings) -> Options {
+        Options::parse(settings.apply(Options::default()), std::env::args().skip(1))
+    }
+}
+
+/// When `path` was last written, or `None` if it doesn't exist or can't be read
+fn file_write_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// A file that exists and was written since it was last loaded (or that was attempted) is worth
+/// reloading, like the game DLL or the settings file
+fn file_changed(loaded: Option<SystemTime>, current: Option<SystemTime>) -> bool {
+    match current {
+        Some(current) => loaded != Some(current),
+        None => false,
+    }
+}
 
 fn main() -> io::Result<()> {
+    let settings = SettingsWatcher::new(SETTINGS_PATH);
+    let options = Options::from_settings(settings.settings());
     #[cfg(windows)]
-    win32::win32_main()?;
+    {
+        let icon = match &options.icon_path {
+            Some(path) => Some(std::fs::read(path)?),
+            None => None,
+        };
+        win32::win32_main(&options, settings, icon.as_deref())?;
+    }
+    #[cfg(target_os = "linux")]
+    linux::linux_main(&options, settings)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// Options as a settings file might have left them, all different from `Options::default()`
+    fn file_options() -> Options {
+        Options {
+            width: 1600,
+            height: 900,
+            render_resolution: Some((320, 180)),
+            sample_format: game::SampleFormat::Pcm24,
+            wheel_step_hz: 10,
+            present_mode: PresentMode::Vsync,
+            audio_latency_ms: Some(30),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn window_size_arguments() {
+        let defaults = Options::default();
+        let size = |arguments: &[&str]| {
+            let options = Options::parse(Options::default(), args(arguments));
+            (options.width, options.height, options.fullscreen)
+        };
+
+        assert_eq!(
+            size(&["--width", "800", "--height", "600", "--fullscreen"]),
+            (800, 600, true)
+        );
+        assert_eq!(size(&[]), (defaults.width, defaults.height, false));
+        // A flag at the end with its value missing
+        assert_eq!(
+            size(&["--height", "600", "--width"]),
+            (defaults.width, 600, false)
+        );
+        assert_eq!(
+            size(&["--width", "8OO", "--height", "0"]),
+            (defaults.width, defaults.height, false)
+        );
+    }
+
+    #[test]
+    fn only_a_newer_existing_file_has_changed() {
+        let loaded = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        let newer = loaded + std::time::Duration::from_secs(1);
+
+        assert!(!file_changed(Some(loaded), Some(loaded)));
+        assert!(file_changed(Some(loaded), Some(newer)));
+        // Never loaded, or loading failed
+        assert!(file_changed(None, Some(loaded)));
+        // Deleted, or mid-write and locked: keep what's loaded
+        assert!(!file_changed(Some(loaded), None));
+        assert!(!file_changed(None, None));
+
+        let missing = std::env::temp_dir().join("again_file_changed_missing.dll");
+        assert_eq!(file_write_time(&missing), None);
+    }
+
+    #[test]
+    fn command_line_overrides_the_defaults() {
+        let options = Options::parse(
+            file_options(),
+            args(&[
+                "--width",
+                "800",
+                "--render-resolution",
+                "640x360",
+                "--sample-format",
+                "float",
+                "--present",
+                "immediate",
+                "--audio-latency-ms",
+                "50",
+                "--show-fps",
+            ]),
+        );
+        assert_eq!(
+            options,
+            Options {
+                width: 800,
+                render_resolution: Some((640, 360)),
+                sample_format: game::SampleFormat::Float32,
+                present_mode: PresentMode::Immediate,
+                audio_latency_ms: Some(50),
+                show_fps: true,
+                ..file_options()
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_arguments_keep_the_defaults() {
+        let options = Options::parse(
+            file_options(),
+            args(&[
+                "--width",
+                "wide",
+                "--height",
+                "-1",
+                "--render-resolution",
+                "320by180",
+                "--sample-format",
+                "8",
+                "--wheel-step",
+                "70000",
+                "--present",
+                "fast",
+                "--audio-latency-ms",
+                "0",
+                "--unknown",
+            ]),
+        );
+        assert_eq!(options, file_options());
+
+        // A value left off the end
+        let options = Options::parse(file_options(), args(&["--audio-latency-ms"]));
+        assert_eq!(options, file_options());
+    }
+}
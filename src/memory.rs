@@ -0,0 +1,148 @@
+use std::{marker::PhantomData, mem, ptr, slice};
+
+use crate::GameState;
+
+/// Memory the platform layer allocates once and hands to the game every frame. Everything the
+/// game keeps between frames lives in `permanent`, so it survives the game DLL being reloaded.
+pub struct GameMemory<'a> {
+    /// Zeroed by the platform, holds the `GameState` at its start
+    pub permanent: &'a mut [u8],
+    /// Scratch space with no guarantees about its contents between frames
+    pub transient: &'a mut [u8],
+    /// Set once the `GameState` has been written to `permanent`
+    pub initialized: bool,
+}
+
+impl<'a> GameMemory<'a> {
+    /// The state in the permanent block, written there on first use.
+    /// Panics if the block is too small to hold it.
+    pub fn game_state(&mut self) -> &mut GameState {
+        let mut arena = Arena::new(self.permanent);
+        if !self.initialized {
+            arena
+                .push_struct(GameState::default())
+                .expect("permanent storage is too small for the game state");
+            self.initialized = true;
+        }
+
+        // Initialization put the state at the first suitably aligned address
+        let offset = arena.base.align_offset(mem::align_of::<GameState>());
+        assert!(offset + mem::size_of::<GameState>() <= arena.size);
+        unsafe { &mut *(arena.base.add(offset) as *mut GameState) }
+    }
+}
+
+/// Bump allocator over a block of memory. Allocations are never freed individually, the block
+/// is reused by making a new `Arena` over it once nothing allocated from it is in use.
+/// Only `Copy` types can be allocated since nothing is ever dropped.
+pub struct Arena<'a> {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+    _memory: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> Arena<'a> {
+    pub fn new(memory: &'a mut [u8]) -> Arena<'a> {
+        Arena {
+            base: memory.as_mut_ptr(),
+            size: memory.len(),
+            used: 0,
+            _memory: PhantomData,
+        }
+    }
+
+    /// Bytes not yet handed out, some of which may be lost to padding by the next allocation
+    pub fn remaining(&self) -> usize {
+        self.size - self.used
+    }
+
+    /// Reserves `size` bytes aligned to `align`, or returns `None` if they don't fit
+    fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let padding = unsafe { self.base.add(self.used) }.align_offset(align);
+        let start = self.used.checked_add(padding)?;
+        let end = start.checked_add(size)?;
+        if end > self.size {
+            return None;
+        }
+
+        self.used = end;
+        Some(unsafe { self.base.add(start) })
+    }
+
+    /// Moves `value` into the arena, or returns `None` if there is no room for it
+    pub fn push_struct<T: Copy>(&mut self, value: T) -> Option<&'a mut T> {
+        let pointer = self.allocate(mem::size_of::<T>(), mem::align_of::<T>())? as *mut T;
+        unsafe {
+            ptr::write(pointer, value);
+            Some(&mut *pointer)
+        }
+    }
+
+    /// Allocates `count` copies of `value`, or returns `None` if there is no room for them
+    pub fn push_array<T: Copy>(&mut self, count: usize, value: T) -> Option<&'a mut [T]> {
+        let size = mem::size_of::<T>().checked_mul(count)?;
+        let pointer = self.allocate(size, mem::align_of::<T>())? as *mut T;
+        unsafe {
+            for index in 0..count {
+                ptr::write(pointer.add(index), value);
+            }
+            Some(slice::from_raw_parts_mut(pointer, count))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_are_aligned() {
+        let mut memory = [0u8; 64];
+        let mut arena = Arena::new(&mut memory);
+
+        let byte = arena.push_struct(7u8).unwrap() as *mut u8;
+        let word = arena.push_struct(0x1234_5678u32).unwrap();
+        assert_eq!(*word, 0x1234_5678);
+        let word = word as *mut u32;
+        assert_eq!(word as usize % mem::align_of::<u32>(), 0);
+        assert!(word as usize > byte as usize);
+
+        let array = arena.push_array(3, 1.5f64).unwrap();
+        assert_eq!(array, [1.5; 3]);
+        assert_eq!(array.as_ptr() as usize % mem::align_of::<f64>(), 0);
+    }
+
+    #[test]
+    fn allocations_past_the_end_fail() {
+        let mut memory = [0u8; 16];
+        let mut arena = Arena::new(&mut memory);
+
+        assert!(arena.push_array(4, 0u32).is_some());
+        assert_eq!(arena.remaining(), 0);
+        assert!(arena.push_struct(0u8).is_none());
+
+        let mut memory = [0u8; 16];
+        let mut arena = Arena::new(&mut memory);
+        assert!(arena.push_array(17, 0u8).is_none());
+        assert!(arena.push_array(usize::MAX, 0u64).is_none());
+        // A failed allocation takes nothing
+        assert_eq!(arena.remaining(), 16);
+    }
+
+    #[test]
+    fn game_state_survives_between_calls() {
+        let mut permanent = vec![0u8; 4096];
+        let mut transient = Vec::new();
+        let mut memory = GameMemory {
+            permanent: &mut permanent,
+            transient: &mut transient,
+            initialized: false,
+        };
+
+        assert_eq!(*memory.game_state(), GameState::default());
+        assert!(memory.initialized);
+        memory.game_state().tone_hz = 440;
+        assert_eq!(memory.game_state().tone_hz, 440);
+    }
+}
@@ -0,0 +1,351 @@
+//! Settings kept between runs in `settings.toml`, read at startup before the command line, which
+//! overrides them, and reread whenever the file is saved. Only the flat part of TOML is
+//! understood: `key = value` lines with strings, numbers and booleans, plus comments. For example:
+//!
+//! ```toml
+//! width = 1600
+//! height = 900
+//! tone_hz = 440
+//! audio_latency_ms = 30
+//! volume_dbfs = -12.0
+//! present_mode = "vsync"
+//! wheel_step_hz = 32
+//! ```
+
+use crate::{file_changed, file_write_time, Options, PresentMode};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Where `Settings::load` looks, relative to the working directory
+pub const SETTINGS_PATH: &str = "settings.toml";
+
+/// What `settings.toml` sets. Fields the file leaves out are `None` and keep the built-in
+/// defaults.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Settings {
+    /// Initial client area in logical pixels
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// Tone played at startup
+    pub tone_hz: Option<u16>,
+    pub audio_latency_ms: Option<u32>,
+    /// Volume at startup, in decibels relative to full scale
+    pub volume_dbfs: Option<f32>,
+    /// `"immediate"`, `"vsync"` or `"capped"`
+    pub present_mode: Option<PresentMode>,
+    /// Tone change per mouse wheel notch
+    pub wheel_step_hz: Option<u16>,
+}
+
+/// Settings that only take effect at startup, changing them in a running game does nothing
+pub const RESTART_SETTINGS: [&str; 2] = ["width", "height"];
+
+/// A value on the right of a `key = value` line
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+impl Settings {
+    /// Reads `path`. A missing file gives no settings; one that can't be read is reported and
+    /// ignored.
+    pub fn load(path: &Path) -> Settings {
+        match fs::read_to_string(path) {
+            Ok(text) => Settings::parse(&text),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Settings::default(),
+            Err(error) => {
+                // Not fatal - the built-in defaults work fine
+                eprintln!("Could not read {}: {}", path.display(), error);
+                Settings::default()
+            }
+        }
+    }
+
+    /// Parses the text of a settings file. Lines that don't parse, unknown keys and values of
+    /// the wrong type or out of range are reported and skipped, leaving the rest in effect.
+    pub fn parse(text: &str) -> Settings {
+        let mut settings = Settings::default();
+        for (index, line) in text.lines().enumerate() {
+            let (key, value) = match parse_line(line) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => continue,
+                Err(error) => {
+                    eprintln!("settings line {}: {}", index + 1, error);
+                    continue;
+                }
+            };
+
+            let integer = |min: i64, max: i64| match value {
+                Value::Integer(value) if (min..=max).contains(&value) => Some(value),
+                _ => None,
+            };
+            let applied = match key {
+                "width" => {
+                    integer(1, i64::from(i32::MAX)).map(|width| settings.width = Some(width as i32))
+                }
+                "height" => integer(1, i64::from(i32::MAX))
+                    .map(|height| settings.height = Some(height as i32)),
+                "tone_hz" => integer(0, i64::from(u16::MAX))
+                    .map(|tone_hz| settings.tone_hz = Some(tone_hz as u16)),
+                "audio_latency_ms" => integer(1, i64::from(u32::MAX))
+                    .map(|latency_ms| settings.audio_latency_ms = Some(latency_ms as u32)),
+                "volume_dbfs" => match value {
+                    Value::Float(value) => Some(value as f32),
+                    Value::Integer(value) => Some(value as f32),
+                    _ => None,
+                }
+                .map(|volume_dbfs| settings.volume_dbfs = Some(volume_dbfs)),
+                "present_mode" => match &value {
+                    Value::String(name) => PresentMode::from_name(name),
+                    _ => None,
+                }
+                .map(|present_mode| settings.present_mode = Some(present_mode)),
+                "wheel_step_hz" => integer(0, i64::from(u16::MAX))
+                    .map(|step_hz| settings.wheel_step_hz = Some(step_hz as u16)),
+                _ => {
+                    eprintln!("settings line {}: unknown setting `{}`", index + 1, key);
+                    continue;
+                }
+            };
+            if applied.is_none() {
+                eprintln!(
+                    "settings line {}: `{}` can't be {:?}",
+                    index + 1,
+                    key,
+                    value
+                );
+            }
+        }
+        settings
+    }
+
+    /// Names of the settings that differ from those in `new`, in the order they are declared
+    pub fn changed_fields(&self, new: &Settings) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.width != new.width {
+            changed.push("width");
+        }
+        if self.height != new.height {
+            changed.push("height");
+        }
+        if self.tone_hz != new.tone_hz {
+            changed.push("tone_hz");
+        }
+        if self.audio_latency_ms != new.audio_latency_ms {
+            changed.push("audio_latency_ms");
+        }
+        if self.volume_dbfs != new.volume_dbfs {
+            changed.push("volume_dbfs");
+        }
+        if self.present_mode != new.present_mode {
+            changed.push("present_mode");
+        }
+        if self.wheel_step_hz != new.wheel_step_hz {
+            changed.push("wheel_step_hz");
+        }
+        changed
+    }
+
+    /// `options` with every setting the file has in place of its own
+    pub fn apply(&self, mut options: Options) -> Options {
+        options.width = self.width.unwrap_or(options.width);
+        options.height = self.height.unwrap_or(options.height);
+        options.tone_hz = self.tone_hz.unwrap_or(options.tone_hz);
+        options.audio_latency_ms = self.audio_latency_ms.or(options.audio_latency_ms);
+        options.volume_dbfs = self.volume_dbfs.unwrap_or(options.volume_dbfs);
+        options.present_mode = self.present_mode.unwrap_or(options.present_mode);
+        options.wheel_step_hz = self.wheel_step_hz.unwrap_or(options.wheel_step_hz);
+        options
+    }
+}
+
+/// Splits a line into its key and value, or `None` for a blank or comment line
+fn parse_line(line: &str) -> Result<Option<(&str, Value)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    if line.starts_with('[') {
+        return Err("tables aren't supported".to_string());
+    }
+
+    let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| format!("expected `key = value`, found `{}`", line))?;
+    let key = key.trim();
+    let value = value.trim();
+
+    if let Some(quoted) = value.strip_prefix('"') {
+        let end = quoted
+            .find('"')
+            .ok_or_else(|| format!("unterminated string for `{}`", key))?;
+        let rest = quoted[end + 1..].trim();
+        if !(rest.is_empty() || rest.starts_with('#')) {
+            return Err(format!(
+                "unexpected `{}` after the value of `{}`",
+                rest, key
+            ));
+        }
+        return Ok(Some((key, Value::String(quoted[..end].to_string()))));
+    }
+
+    // Anything after the value is a comment
+    let value = value.split('#').next().unwrap_or("").trim();
+    let value = match value {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => {
+            // TOML allows underscores between digits
+            let number = value.replace('_', "");
+            if let Ok(integer) = number.parse::<i64>() {
+                Value::Integer(integer)
+            } else if let Ok(float) = number.parse::<f64>() {
+                Value::Float(float)
+            } else {
+                return Err(format!("can't read `{}` as the value of `{}`", value, key));
+            }
+        }
+    };
+    Ok(Some((key, value)))
+}
+
+/// The settings file, reread whenever it is saved so changes apply while the game runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingsWatcher {
+    path: PathBuf,
+    /// Write time of the file when it was last read
+    last_write_time: Option<SystemTime>,
+    settings: Settings,
+}
+
+impl SettingsWatcher {
+    /// Reads the settings at `path` now, see `Settings::load`
+    pub fn new<P: Into<PathBuf>>(path: P) -> SettingsWatcher {
+        let path = path.into();
+        SettingsWatcher {
+            last_write_time: file_write_time(&path),
+            settings: Settings::load(&path),
+            path,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Rereads the file if it was written since the last look. Returns the names of the
+    /// settings that changed, logging them and any that need a restart to take effect.
+    pub fn poll(&mut self) -> Vec<&'static str> {
+        let write_time = file_write_time(&self.path);
+        if !file_changed(self.last_write_time, write_time) {
+            return Vec::new();
+        }
+        self.last_write_time = write_time;
+
+        let settings = Settings::load(&self.path);
+        let changed = self.settings.changed_fields(&settings);
+        self.settings = settings;
+        if !changed.is_empty() {
+            println!("Settings changed: {}", changed.join(", "));
+        }
+        let needs_restart: Vec<_> = changed
+            .iter()
+            .filter(|field| RESTART_SETTINGS.contains(field))
+            .copied()
+            .collect();
+        if !needs_restart.is_empty() {
+            println!("Restart to apply: {}", needs_restart.join(", "));
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_settings_file() {
+        let text = r#"
+            # Window
+            width = 1_600
+            height = 900 # 16:9
+
+            tone_hz = 440
+            audio_latency_ms = 30
+            volume_dbfs = -12.5
+            present_mode = "vsync"
+            wheel_step_hz = 32
+        "#;
+        assert_eq!(
+            Settings::parse(text),
+            Settings {
+                width: Some(1600),
+                height: Some(900),
+                tone_hz: Some(440),
+                audio_latency_ms: Some(30),
+                volume_dbfs: Some(-12.5),
+                present_mode: Some(PresentMode::Vsync),
+                wheel_step_hz: Some(32),
+            }
+        );
+    }
+
+    #[test]
+    fn missing_fields_keep_the_defaults() {
+        let settings = Settings::parse("tone_hz = 440\nvolume_dbfs = -6\n");
+        assert_eq!(
+            settings.apply(Options::default()),
+            Options {
+                tone_hz: 440,
+                volume_dbfs: -6.0,
+                ..Options::default()
+            }
+        );
+        assert_eq!(
+            Settings::parse("").apply(Options::default()),
+            Options::default()
+        );
+    }
+
+    #[test]
+    fn changed_fields_lists_every_difference_in_order() {
+        let old = Settings {
+            width: Some(800),
+            tone_hz: Some(440),
+            wheel_step_hz: Some(10),
+            ..Settings::default()
+        };
+        let new = Settings {
+            width: Some(900),
+            tone_hz: Some(440),
+            volume_dbfs: Some(-3.0),
+            ..Settings::default()
+        };
+        let changed = ["width", "volume_dbfs", "wheel_step_hz"];
+        assert_eq!(old.changed_fields(&new), changed);
+        assert_eq!(new.changed_fields(&old), changed);
+        assert!(old.changed_fields(&old.clone()).is_empty());
+        assert!(Settings::default()
+            .changed_fields(&Settings::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let text = "width = -5\nheight = \"tall\"\n[window]\nnonsense\nspeed = 3\ntone_hz = 300\n";
+        assert_eq!(
+            Settings::parse(text),
+            Settings {
+                tone_hz: Some(300),
+                ..Settings::default()
+            }
+        );
+    }
+}
@@ -1,15 +1,53 @@
-use std::{ffi::OsStr, io, mem::MaybeUninit, ptr};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ffi::OsStr,
+    fs, io,
+    mem::MaybeUninit,
+    ops::Deref,
+    path::Path,
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::SystemTime,
+};
 
 use std::os::windows::ffi::OsStrExt;
 use winapi::{
-    shared::{minwindef::*, mmreg::*, windef::*, winerror::*},
+    shared::{
+        basetsd::LONG_PTR,
+        ksmedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, KSDATAFORMAT_SUBTYPE_PCM},
+        minwindef::*,
+        mmreg::*,
+        windef::*,
+        winerror::{self, *},
+    },
     um::{
-        cguid::*, dsound::*, libloaderapi::GetModuleHandleW, profileapi::*, wingdi::*, winnt::*,
-        winuser::*, xinput::*,
+        cguid::*,
+        consoleapi::SetConsoleCtrlHandler,
+        debugapi::OutputDebugStringW,
+        dsound::*,
+        dwmapi::{DwmFlush, DwmIsCompositionEnabled},
+        libloaderapi::{FreeLibrary, GetModuleHandleW, GetProcAddress, LoadLibraryW},
+        memoryapi::{VirtualAlloc, VirtualFree},
+        mmsystem::TIMERR_NOERROR,
+        processthreadsapi::GetCurrentThreadId,
+        profileapi::*,
+        synchapi::Sleep,
+        timeapi::*,
+        unknwnbase::IUnknown,
+        winbase::INFINITE,
+        wincon::*,
+        wingdi::*,
+        winnt::*,
+        winuser::*,
+        xinput::*,
     },
 };
 
-use crate::game::*;
+#[cfg(feature = "cpal")]
+use crate::cpal_audio::CpalAudio;
+use crate::{file_changed, file_write_time, Options, PresentMode, SettingsWatcher};
+use game::*;
 
 #[cfg(windows)]
 fn win32_string(value: &str) -> Vec<u16> {
@@ -19,42 +57,82 @@ fn win32_string(value: &str) -> Vec<u16> {
         .collect()
 }
 
-impl DisplayBuffer {
-    fn resize_dib_section(&mut self, info: &mut BITMAPINFO, window_width: i32, window_height: i32) {
-        assert!(window_width > 0 && window_height > 0);
+/// Win32 drawing for the platform-independent `DisplayBuffer`
+trait Win32Display {
+    fn resize_dib_section(&mut self, info: &mut BITMAPINFO, window_width: i32, window_height: i32);
 
-        self.width = window_width;
-        info.bmiHeader.biWidth = window_width;
+    /// Requires that `device_context` is a valid device context and that info is valid.
+    /// Fills the client area outside of `destination` with black.
+    fn draw_to_window(
+        &self,
+        info: &BITMAPINFO,
+        device_context: HDC,
+        destination: &DestinationRect,
+        client: &WindowDimension,
+    );
+}
 
-        self.height = window_height;
-        info.bmiHeader.biHeight = window_height;
+impl Win32Display for DisplayBuffer {
+    fn resize_dib_section(&mut self, info: &mut BITMAPINFO, window_width: i32, window_height: i32) {
+        assert!(window_width > 0 && window_height > 0);
 
-        let new_size = window_width as usize * window_height as usize;
-        if new_size != self.memory.len() {
-            self.memory.resize_with(new_size, Default::default);
-        }
+        // The last frame stays up, with black in the new space, until the next one is rendered
+        self.resize(window_width, window_height);
 
-        self.step_render(1);
+        info.bmiHeader.biWidth = window_width;
+        // Negative height makes the DIB top-down so (0, 0) is the top-left corner
+        info.bmiHeader.biHeight = -window_height;
     }
 
-    /// Requires that `device_context` is a valid device context and that info is valid
     fn draw_to_window(
         &self,
         info: &BITMAPINFO,
         device_context: HDC,
-        window_width: i32,
-        window_height: i32,
+        destination: &DestinationRect,
+        client: &WindowDimension,
     ) {
+        // Only the bars are cleared, clearing under the image too would flicker
+        let bars = [
+            (0, 0, destination.x, client.height),
+            (
+                destination.x + destination.width,
+                0,
+                client.width,
+                client.height,
+            ),
+            (0, 0, client.width, destination.y),
+            (
+                0,
+                destination.y + destination.height,
+                client.width,
+                client.height,
+            ),
+        ];
+        for &(left, top, right, bottom) in bars.iter() {
+            if right > left && bottom > top {
+                unsafe {
+                    PatBlt(
+                        device_context,
+                        left,
+                        top,
+                        right - left,
+                        bottom - top,
+                        BLACKNESS,
+                    )
+                };
+            }
+        }
+
         let success = unsafe {
             StretchDIBits(
                 // Destination device context handle
                 device_context,
                 // Upper left corner of destination rectangle coords
-                0,
-                0,
+                destination.x,
+                destination.y,
                 // Dimensions of destination rectangle
-                window_width,
-                window_height,
+                destination.width,
+                destination.height,
                 // Source rectangle of image
                 0,
                 0,
@@ -62,7 +140,7 @@ impl DisplayBuffer {
                 self.width,
                 self.height,
                 // Memory buffer of image
-                self.memory.as_ptr() as *const _,
+                self.front.as_ptr() as *const _,
                 // Pointer to BITMAPINFO containing DIB information
                 info as *const _,
                 // Image contains RGB values
@@ -77,13 +155,189 @@ impl DisplayBuffer {
     }
 }
 
-static mut RUNNING: bool = false;
+/// Cleared to leave the main loop. Atomic so it can be flipped from anywhere without `static mut`.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+fn request_quit() {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+/// Whether to quit when the user asks to, given whether that needs confirming and their answer
+/// if they were asked. A confirmation that couldn't be shown quits anyway rather than leaving the
+/// window impossible to close.
+fn should_quit(confirm: bool, user_choice: Option<bool>) -> bool {
+    !confirm || user_choice.unwrap_or(true)
+}
+
+/// Quits for the close button, Alt+F4 and the quit key, asking first with `--confirm-exit`
+fn request_quit_confirmed(window: HWND, state: &WindowState) {
+    let user_choice = if state.confirm_on_exit {
+        let text = win32_string("Quit the game?");
+        let caption = win32_string("Quit");
+        match unsafe {
+            MessageBoxW(
+                window,
+                text.as_ptr(),
+                caption.as_ptr(),
+                MB_YESNO | MB_ICONQUESTION,
+            )
+        } {
+            // The message box failed to show
+            0 => None,
+            choice => Some(choice == IDYES),
+        }
+    } else {
+        None
+    };
+    if should_quit(state.confirm_on_exit, user_choice) {
+        request_quit();
+    }
+}
+
+/// Thread running the message loop, so other threads can wake it up from `GetMessageW`
+static MAIN_THREAD_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Runs on its own thread when the console the game was started from gets Ctrl+C, Ctrl+Break or
+/// is closed. Quits through the main loop so cleanup still happens.
+unsafe extern "system" fn console_ctrl_handler(ctrl_type: DWORD) -> BOOL {
+    match ctrl_type {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT | CTRL_CLOSE_EVENT => {
+            request_quit();
+            // The loop may be blocked waiting for messages while the window is inactive
+            PostThreadMessageW(MAIN_THREAD_ID.load(Ordering::SeqCst), WM_NULL, 0, 0);
+            if ctrl_type == CTRL_CLOSE_EVENT {
+                // The process is terminated as soon as this returns. Block instead so the main
+                // loop can clean up, exiting the process ends this thread.
+                Sleep(INFINITE);
+            }
+            TRUE
+        }
+        _ => FALSE,
+    }
+}
 
+/// Size of a window's client area in physical pixels. This is what the backbuffer is scaled to
+/// fill, not necessarily its size: see `DisplayBuffer::dimensions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct WindowDimension {
     width: i32,
     height: i32,
 }
 
+/// How the backbuffer is fitted to the client area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScaleMode {
+    /// Fill the client area, distorting the image if the aspect ratios differ
+    Stretch,
+    /// Scale as large as fits without distortion, centered between black bars
+    Letterbox,
+    /// Scale by the largest whole number that fits, centered between black bars, so every
+    /// backbuffer pixel covers the same number of screen pixels
+    Integer,
+}
+
+/// Part of the client area the backbuffer is drawn to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DestinationRect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+fn destination_rect(
+    mode: ScaleMode,
+    buffer_width: i32,
+    buffer_height: i32,
+    client: &WindowDimension,
+) -> DestinationRect {
+    let fill = DestinationRect {
+        x: 0,
+        y: 0,
+        width: client.width,
+        height: client.height,
+    };
+    if mode == ScaleMode::Stretch || buffer_width <= 0 || buffer_height <= 0 {
+        return fill;
+    }
+    if mode == ScaleMode::Integer {
+        return integer_scale_rect(buffer_width, buffer_height, client);
+    }
+
+    // Compare aspect ratios by cross-multiplying, widened so large sizes can't overflow
+    let (buffer_width, buffer_height) = (i64::from(buffer_width), i64::from(buffer_height));
+    let (client_width, client_height) = (i64::from(client.width), i64::from(client.height));
+    if client_width * buffer_height > client_height * buffer_width {
+        // Client is wider: bars left and right
+        let width = (client_height * buffer_width / buffer_height) as i32;
+        DestinationRect {
+            x: (client.width - width) / 2,
+            width,
+            ..fill
+        }
+    } else {
+        // Client is taller (or the same shape): bars top and bottom
+        let height = (client_width * buffer_height / buffer_width) as i32;
+        DestinationRect {
+            y: (client.height - height) / 2,
+            height,
+            ..fill
+        }
+    }
+}
+
+/// Centers the backbuffer scaled by the largest whole number that fits in `client`. A client area
+/// smaller than the buffer shows its middle unscaled, cropping the edges, so the offsets go
+/// negative. The buffer size must be positive.
+fn integer_scale_rect(
+    buffer_width: i32,
+    buffer_height: i32,
+    client: &WindowDimension,
+) -> DestinationRect {
+    let scale = (client.width / buffer_width)
+        .min(client.height / buffer_height)
+        .max(1);
+    let (width, height) = (buffer_width * scale, buffer_height * scale);
+    DestinationRect {
+        x: (client.width - width) / 2,
+        y: (client.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+/// Adds a wheel `delta` to the `remainder` of a notch left over from earlier ones, returning
+/// `(whole notches, new remainder)`. High-resolution wheels send fractions of `WHEEL_DELTA`, which
+/// add up to a notch here.
+fn wheel_notches(remainder: i32, delta: i32) -> (i32, i32) {
+    let total = remainder.saturating_add(delta);
+    let notch = i32::from(WHEEL_DELTA);
+    (total / notch, total % notch)
+}
+
+/// Converts a position in client coordinates to backbuffer coordinates, undoing the scaling
+/// `draw_to_window` applies to draw the buffer to `destination`. Positions on the bars map
+/// outside the buffer.
+fn client_to_buffer(
+    x: i32,
+    y: i32,
+    destination: &DestinationRect,
+    buffer: &DisplayBuffer,
+) -> (i32, i32) {
+    if destination.width <= 0 || destination.height <= 0 {
+        return (x, y);
+    }
+
+    let scale = |value: i32, offset: i32, destination_size: i32, buffer_size: i32| {
+        (i64::from(value - offset) * i64::from(buffer_size) / i64::from(destination_size)) as i32
+    };
+    (
+        scale(x, destination.x, destination.width, buffer.width),
+        scale(y, destination.y, destination.height, buffer.height),
+    )
+}
+
+/// The live client area of `window`, which may have changed since the last `WM_SIZE` was handled
 fn get_window_dimension(window: HWND) -> WindowDimension {
     let client_rect = unsafe {
         let mut client_rect = MaybeUninit::uninit();
@@ -104,33 +358,710 @@ fn get_window_dimension(window: HWND) -> WindowDimension {
     }
 }
 
-static mut HZ: u16 = 512;
+/// DPI at which one logical pixel is one physical pixel (100% scaling)
+const DEFAULT_DPI: i32 = 96;
 
-/// `unsafe` precondition: must be called from main thread
-unsafe fn handle_key_press(vk_code: WPARAM, l_param: LPARAM) {
-    assert!(vk_code < i32::max_value() as usize);
-    let _was_down = (l_param & (1 << 30)) != 0;
+/// Physical size of a `logical_width` x `logical_height` area at `dpi_scale`
+/// (1.0 at 100% scaling, 1.5 at 150%...), rounded to the nearest pixel
+fn backbuffer_size(logical_width: i32, logical_height: i32, dpi_scale: f32) -> (i32, i32) {
+    let scale = |logical: i32| (logical as f32 * dpi_scale).round() as i32;
+    (scale(logical_width), scale(logical_height))
+}
+
+/// Stops Windows from bitmap-stretching the window on scaled displays.
+/// Per-monitor awareness needs Windows 10 1703, so it is looked up at runtime; older systems
+/// fall back to system-wide awareness.
+fn set_dpi_awareness() {
+    type SetProcessDpiAwarenessContext = unsafe extern "system" fn(DPI_AWARENESS_CONTEXT) -> BOOL;
+    unsafe {
+        let user32 = GetModuleHandleW(win32_string("user32.dll").as_ptr());
+        let proc_address = if user32.is_null() {
+            ptr::null_mut()
+        } else {
+            GetProcAddress(
+                user32,
+                b"SetProcessDpiAwarenessContext\0".as_ptr() as LPCSTR,
+            )
+        };
+        if !proc_address.is_null() {
+            let set_awareness: SetProcessDpiAwarenessContext = std::mem::transmute(proc_address);
+            if set_awareness(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) != 0 {
+                return;
+            }
+        }
+        // Not being DPI aware only makes the window blurry, so failure is ignored
+        SetProcessDPIAware();
+    }
+}
+
+/// Scale factor of the primary monitor; only valid after `set_dpi_awareness`
+fn system_dpi_scale() -> f32 {
+    unsafe {
+        let screen_context = GetDC(ptr::null_mut());
+        if screen_context.is_null() {
+            return 1.0;
+        }
+        let dpi = GetDeviceCaps(screen_context, LOGPIXELSX);
+        ReleaseDC(ptr::null_mut(), screen_context);
+        if dpi <= 0 {
+            1.0
+        } else {
+            dpi as f32 / DEFAULT_DPI as f32
+        }
+    }
+}
+
+/// Input state written by the window procedure and read by the main loop.
+/// Owned by `win32_main` and reachable from the window procedure through `GWLP_USERDATA`.
+/// Fields are `Cell`s so both sides can share a plain reference without `static mut`.
+struct WindowState {
+    tone_hz: Cell<u16>,
+    /// Tone keys held down, applied to `tone_hz` once per frame
+    tone_keys: Cell<ToneKeys>,
+    volume: Cell<f32>,
+    muted: Cell<bool>,
+    tone_enabled: Cell<bool>,
+    /// Cursor position in client coordinates
+    mouse: Cell<MouseInput>,
+    scale_mode: ScaleMode,
+    /// Fixed backbuffer size; `None` sizes the backbuffer to the client area
+    render_resolution: Option<(i32, i32)>,
+    /// Windowed placement to restore; `Some` while in borderless fullscreen
+    windowed_placement: Cell<Option<WINDOWPLACEMENT>>,
+    /// Cleared while another application has focus
+    active: Cell<bool>,
+    /// Set by the replay key, consumed by the main loop which owns the replay
+    replay_toggle_requested: Cell<bool>,
+    /// Toggled with the pause key, freezes the game while the window keeps running
+    paused: Cell<bool>,
+    /// Step key presses while paused, consumed by the main loop
+    step_frames: Cell<u32>,
+    /// Harmonics toggled with the number keys
+    harmonics: Cell<[bool; HARMONIC_COUNT]>,
+    key_bindings: KeyBindings,
+    button_bindings: ButtonBindings,
+    /// Whether quitting from the window asks for confirmation first
+    confirm_on_exit: bool,
+    /// Tone change per wheel notch
+    wheel_step_hz: Cell<u16>,
+    /// Wheel movement short of a whole notch, carried to the next `WM_MOUSEWHEEL`
+    wheel_remainder: Cell<i32>,
+    /// Pairs up the surrogates of characters outside the BMP across `WM_CHAR` messages
+    utf16_decoder: Cell<Utf16Decoder>,
+    /// Typed since the last frame, taken by the main loop
+    text: RefCell<String>,
+    /// Fed by key messages, snapshotted and advanced once per frame by the main loop
+    keyboard: RefCell<Keyboard>,
+    /// Drawn by the main loop, resized on `WM_SIZE` and shown again on `WM_PAINT`. The main loop
+    /// doesn't pump messages while it has it borrowed.
+    display_buffer: RefCell<DisplayBuffer>,
+    /// Describes `display_buffer` to GDI, kept in step with it by `resize_dib_section`
+    bitmap_info: RefCell<BITMAPINFO>,
+}
+
+impl WindowState {
+    fn new(options: &Options) -> WindowState {
+        WindowState {
+            tone_hz: Cell::new(options.tone_hz),
+            tone_keys: Cell::new(ToneKeys::default()),
+            volume: Cell::new(dbfs_to_amplitude(options.volume_dbfs)),
+            muted: Cell::new(false),
+            tone_enabled: Cell::new(true),
+            mouse: Cell::new(MouseInput::default()),
+            scale_mode: if options.render_resolution.is_some() {
+                ScaleMode::Integer
+            } else if options.stretch {
+                ScaleMode::Stretch
+            } else {
+                ScaleMode::Letterbox
+            },
+            render_resolution: options.render_resolution,
+            windowed_placement: Cell::new(None),
+            active: Cell::new(true),
+            replay_toggle_requested: Cell::new(false),
+            paused: Cell::new(false),
+            step_frames: Cell::new(0),
+            harmonics: Cell::new([false; HARMONIC_COUNT]),
+            key_bindings: KeyBindings::default(),
+            button_bindings: ButtonBindings::default(),
+            confirm_on_exit: options.confirm_on_exit,
+            wheel_step_hz: Cell::new(options.wheel_step_hz),
+            wheel_remainder: Cell::new(0),
+            utf16_decoder: Cell::new(Utf16Decoder::default()),
+            text: RefCell::new(String::new()),
+            keyboard: RefCell::new(Keyboard::default()),
+            display_buffer: RefCell::new(INITIAL_DISPLAY_BUFFER),
+            bitmap_info: RefCell::new(INITIAL_BITMAP_INFO),
+        }
+    }
+
+    /// Requires that `window` was created with a pointer to a `WindowState` that outlives it
+    unsafe fn from_window<'a>(window: HWND) -> Option<&'a WindowState> {
+        (GetWindowLongPtrW(window, GWLP_USERDATA) as *const WindowState).as_ref()
+    }
+
+    /// Size to reallocate the backbuffer to for a `WM_SIZE` to `client`, or `None` to keep it.
+    /// Skips no-op resizes and minimized windows (empty client area). Fullscreen keeps the
+    /// windowed resolution and stretches it to fill the monitor.
+    fn backbuffer_resize(&self, client: &WindowDimension, minimized: bool) -> Option<(i32, i32)> {
+        // A fixed render resolution ignores the window size, draw_to_window scales it instead
+        let (width, height) = self
+            .render_resolution
+            .unwrap_or((client.width, client.height));
+        let fullscreen = self.windowed_placement.get().is_some();
+        (!fullscreen && !minimized && self.display_buffer.borrow().needs_resize(width, height))
+            .then_some((width, height))
+    }
+
+    /// Follows `WM_ACTIVATEAPP`. Key-ups go to whichever window has focus now, so held keys are
+    /// let go of rather than left sliding the tone without them.
+    fn set_active(&self, active: bool) {
+        self.active.set(active);
+        self.tone_keys.set(ToneKeys::default());
+        self.keyboard.borrow_mut().release_all();
+    }
+}
+
+/// Virtual-key code, as passed in the `wParam` of key messages
+type VirtualKey = i32;
+
+const VOLUME_STEP: f32 = 500.0;
+// winapi doesn't define virtual key codes for letters, they match the uppercase ASCII value
+const VK_M: VirtualKey = b'M' as VirtualKey;
+const VK_L: VirtualKey = b'L' as VirtualKey;
+const VK_P: VirtualKey = b'P' as VirtualKey;
+// Same for the number keys above the letters
+const VK_1: VirtualKey = b'1' as VirtualKey;
+
+/// Something the player can bind a key to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    IncreaseTone,
+    DecreaseTone,
+    IncreaseVolume,
+    DecreaseVolume,
+    ToggleMute,
+    ToggleTone,
+    ToggleReplay,
+    TogglePause,
+    /// Runs one update while paused
+    StepFrame,
+    /// Index into `Input::harmonics`
+    ToggleHarmonic(usize),
+    Quit,
+}
+
+/// Which key triggers which action. A key triggers at most one action, an action may have several
+/// keys. Alt+Enter and Alt+F4 are system shortcuts and can't be rebound.
+#[derive(Debug, Clone, PartialEq)]
+struct KeyBindings {
+    actions: HashMap<VirtualKey, Action>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        let mut bindings = KeyBindings {
+            actions: HashMap::new(),
+        };
+        bindings.bind(VK_UP, Action::IncreaseTone);
+        bindings.bind(VK_DOWN, Action::DecreaseTone);
+        bindings.bind(VK_OEM_PLUS, Action::IncreaseVolume);
+        bindings.bind(VK_ADD, Action::IncreaseVolume);
+        bindings.bind(VK_OEM_MINUS, Action::DecreaseVolume);
+        bindings.bind(VK_SUBTRACT, Action::DecreaseVolume);
+        bindings.bind(VK_M, Action::ToggleMute);
+        bindings.bind(VK_SPACE, Action::ToggleTone);
+        bindings.bind(VK_L, Action::ToggleReplay);
+        bindings.bind(VK_P, Action::TogglePause);
+        bindings.bind(VK_OEM_PERIOD, Action::StepFrame);
+        for harmonic in 0..HARMONIC_COUNT {
+            bindings.bind(
+                VK_1 + harmonic as VirtualKey,
+                Action::ToggleHarmonic(harmonic),
+            );
+        }
+        bindings.bind(VK_ESCAPE, Action::Quit);
+        bindings
+    }
+}
+
+impl KeyBindings {
+    /// Makes `key` trigger `action`, replacing whatever it was bound to
+    fn bind(&mut self, key: VirtualKey, action: Action) {
+        self.actions.insert(key, action);
+    }
+}
+
+/// Which controller button triggers which action, like `KeyBindings` for the keyboard. Buttons
+/// are `XINPUT_GAMEPAD_*` masks.
+#[derive(Debug, Clone, PartialEq)]
+struct ButtonBindings {
+    actions: Vec<(WORD, Action)>,
+}
+
+impl Default for ButtonBindings {
+    fn default() -> ButtonBindings {
+        let mut bindings = ButtonBindings {
+            actions: Vec::new(),
+        };
+        bindings.bind(XINPUT_GAMEPAD_DPAD_UP, Action::IncreaseTone);
+        bindings.bind(XINPUT_GAMEPAD_DPAD_DOWN, Action::DecreaseTone);
+        bindings.bind(XINPUT_GAMEPAD_DPAD_RIGHT, Action::IncreaseVolume);
+        bindings.bind(XINPUT_GAMEPAD_DPAD_LEFT, Action::DecreaseVolume);
+        bindings.bind(XINPUT_GAMEPAD_A, Action::ToggleTone);
+        bindings.bind(XINPUT_GAMEPAD_B, Action::ToggleMute);
+        bindings.bind(XINPUT_GAMEPAD_X, Action::ToggleReplay);
+        bindings.bind(XINPUT_GAMEPAD_Y, Action::ToggleHarmonic(0));
+        bindings.bind(XINPUT_GAMEPAD_BACK, Action::TogglePause);
+        bindings.bind(XINPUT_GAMEPAD_START, Action::Quit);
+        bindings
+    }
+}
+
+impl ButtonBindings {
+    /// Makes `button` trigger `action`, replacing whatever it was bound to
+    fn bind(&mut self, button: WORD, action: Action) {
+        self.actions.retain(|&(bound, _)| bound != button);
+        self.actions.push((button, action));
+    }
+}
+
+/// Controller buttons that changed between two polls, as `XINPUT_GAMEPAD_*` bitmasks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct ButtonEdges {
+    /// Down now but not at the previous poll
+    pressed: WORD,
+    /// Down at the previous poll but not now
+    released: WORD,
+    /// Down now, whether or not it just went down
+    held: WORD,
+}
+
+fn button_edges(previous: WORD, current: WORD) -> ButtonEdges {
+    ButtonEdges {
+        pressed: current & !previous,
+        released: previous & !current,
+        held: current,
+    }
+}
+
+/// Triggers the actions bound to buttons that went down or up since the previous poll. Held
+/// buttons repeat nothing, the tone keeps sliding on its own until its button is released.
+fn handle_button_presses(window: HWND, state: &WindowState, edges: ButtonEdges) {
+    for &(button, action) in state.button_bindings.actions.iter() {
+        if (edges.pressed | edges.released) & button != 0 {
+            let is_press = edges.pressed & button != 0;
+            apply_action(window, state, action, edges.held & button != 0, is_press);
+        }
+    }
+}
+
+/// Switches between the normal window and borderless fullscreen on the monitor the window is on
+fn toggle_fullscreen(window: HWND, state: &WindowState) {
+    unsafe {
+        let style = GetWindowLongW(window, GWL_STYLE) as DWORD;
+        match state.windowed_placement.take() {
+            None => {
+                let mut placement = WINDOWPLACEMENT {
+                    length: std::mem::size_of::<WINDOWPLACEMENT>() as UINT,
+                    ..Default::default()
+                };
+                let mut monitor_info = MONITORINFO {
+                    cbSize: std::mem::size_of::<MONITORINFO>() as DWORD,
+                    ..Default::default()
+                };
+                let monitor = MonitorFromWindow(window, MONITOR_DEFAULTTONEAREST);
+                if GetWindowPlacement(window, &mut placement as *mut _) == 0
+                    || GetMonitorInfoW(monitor, &mut monitor_info as *mut _) == 0
+                {
+                    // Stay windowed rather than lose the placement to restore
+                    return;
+                }
+                // Set before resizing so WM_SIZE keeps the backbuffer resolution
+                state.windowed_placement.set(Some(placement));
+                SetWindowLongW(window, GWL_STYLE, (style & !WS_OVERLAPPEDWINDOW) as i32);
+                let monitor_rect = monitor_info.rcMonitor;
+                SetWindowPos(
+                    window,
+                    HWND_TOP,
+                    monitor_rect.left,
+                    monitor_rect.top,
+                    monitor_rect.right - monitor_rect.left,
+                    monitor_rect.bottom - monitor_rect.top,
+                    SWP_NOOWNERZORDER | SWP_FRAMECHANGED,
+                );
+            }
+            Some(placement) => {
+                SetWindowLongW(window, GWL_STYLE, (style | WS_OVERLAPPEDWINDOW) as i32);
+                SetWindowPlacement(window, &placement as *const _);
+                // Style changes only take effect once the frame is recalculated
+                SetWindowPos(
+                    window,
+                    ptr::null_mut(),
+                    0,
+                    0,
+                    0,
+                    0,
+                    SWP_NOMOVE | SWP_NOSIZE | SWP_NOZORDER | SWP_NOOWNERZORDER | SWP_FRAMECHANGED,
+                );
+            }
+        }
+    }
+}
+
+/// Joins the UTF-16 code units `WM_CHAR` delivers one message at a time into `char`s, holding
+/// on to the first half of a surrogate pair until the second arrives
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Utf16Decoder {
+    high_surrogate: Option<u16>,
+}
+
+impl Utf16Decoder {
+    /// Returns the character `unit` completes, if any. Surrogates missing their other half are
+    /// dropped.
+    fn push(&mut self, unit: u16) -> Option<char> {
+        if (0xD800..0xDC00).contains(&unit) {
+            self.high_surrogate = Some(unit);
+            return None;
+        }
+        match self.high_surrogate.take() {
+            Some(high) => std::char::decode_utf16([high, unit].iter().copied())
+                .last()?
+                .ok(),
+            None => std::char::decode_utf16(std::iter::once(unit)).next()?.ok(),
+        }
+    }
+}
+
+/// Whether a typed character goes into `Input::text`. Of the control characters only Backspace
+/// and Enter do, the rest come from shortcuts like Ctrl+C and Escape.
+fn is_text_char(c: char) -> bool {
+    !c.is_control() || c == '\u{8}' || c == '\r'
+}
+
+/// Records a key message in `state.keyboard` for the main loop to act on. Only the system
+/// shortcuts, Alt+Enter and Alt+F4, take effect right away.
+fn handle_key_press(window: HWND, state: &WindowState, vk_code: WPARAM, l_param: LPARAM) {
+    assert!(vk_code < i32::MAX as usize);
+    let was_down = (l_param & (1 << 30)) != 0;
     let is_down = (l_param & (1 << 31)) == 0;
 
     let alt_key_pressed = (l_param & (1 << 29)) != 0;
-    match vk_code as i32 {
-        VK_UP if is_down => HZ = HZ.saturating_add(64),
-        VK_DOWN if is_down => HZ = HZ.saturating_sub(64),
-        VK_ESCAPE => RUNNING = false,
-        VK_F4 if alt_key_pressed => RUNNING = false,
+    let key = vk_code as VirtualKey;
+    if alt_key_pressed {
+        match key {
+            VK_RETURN if is_down && !was_down => {
+                toggle_fullscreen(window, state);
+                return;
+            }
+            // Handled by hand since WM_SYSKEYDOWN never reaches DefWindowProcW
+            VK_F4 => {
+                if is_down && !was_down {
+                    request_quit_confirmed(window, state);
+                }
+                return;
+            }
+            _ => (),
+        }
+    }
+
+    let mut keyboard = state.keyboard.borrow_mut();
+    if is_down {
+        keyboard.key_down(key as KeyCode);
+    } else {
+        keyboard.key_up(key as KeyCode);
+    }
+}
+
+/// Triggers the actions bound to keys that changed in `keyboard`'s frame: once when a key goes
+/// down, again for each auto-repeat and once more when it comes up. Toggles only act on the
+/// first, so holding the key doesn't flicker.
+fn handle_keyboard(window: HWND, state: &WindowState, keyboard: &Keyboard) {
+    for (&key, &action) in state.key_bindings.actions.iter() {
+        let key = key as KeyCode;
+        let released = keyboard.was_released_this_frame(key);
+        // Let go and pressed again within the frame: the release came first
+        if released && keyboard.is_down(key) {
+            apply_action(window, state, action, false, false);
+        }
+        if keyboard.was_pressed_this_frame(key) {
+            apply_action(window, state, action, true, true);
+        }
+        for _ in 0..keyboard.repeat_count(key) {
+            apply_action(window, state, action, true, false);
+        }
+        if released && !keyboard.is_down(key) {
+            apply_action(window, state, action, false, false);
+        }
+    }
+}
+
+/// Carries out `action` for a key or button that is now down (`is_down`), and went down just now
+/// rather than repeating (`is_press`), or went up
+fn apply_action(window: HWND, state: &WindowState, action: Action, is_down: bool, is_press: bool) {
+    match action {
+        // The main loop slides the tone for as long as these are held
+        Action::IncreaseTone | Action::DecreaseTone => {
+            let mut tone_keys = state.tone_keys.get();
+            if action == Action::IncreaseTone {
+                tone_keys.raise_held = is_down;
+            } else {
+                tone_keys.lower_held = is_down;
+            }
+            state.tone_keys.set(tone_keys);
+        }
+        Action::IncreaseVolume if is_down => state
+            .volume
+            .set((state.volume.get() + VOLUME_STEP).min(MAX_VOLUME)),
+        Action::DecreaseVolume if is_down => state
+            .volume
+            .set((state.volume.get() - VOLUME_STEP).max(0.0)),
+        Action::ToggleMute if is_press => state.muted.set(!state.muted.get()),
+        Action::ToggleTone if is_press => state.tone_enabled.set(!state.tone_enabled.get()),
+        Action::ToggleReplay if is_press => state.replay_toggle_requested.set(true),
+        Action::TogglePause if is_press => state.paused.set(!state.paused.get()),
+        // Once per press, holding the key doesn't keep stepping
+        Action::StepFrame if is_press && state.paused.get() => {
+            state.step_frames.set(state.step_frames.get() + 1)
+        }
+        Action::ToggleHarmonic(harmonic) if is_press => {
+            let mut harmonics = state.harmonics.get();
+            if let Some(harmonic) = harmonics.get_mut(harmonic) {
+                *harmonic = !*harmonic;
+            }
+            state.harmonics.set(harmonics);
+        }
+        Action::Quit if is_press => request_quit_confirmed(window, state),
         _ => (),
     }
 }
 
-#[must_use]
-/// Guaranteed to return valid (non-null) pointers
+/// Returns `None` if there is no controller at `controller_index`
+fn get_controller_state(controller_index: DWORD) -> Option<XINPUT_STATE> {
+    let mut controller_state = MaybeUninit::uninit();
+    unsafe {
+        if XInputGetState(
+            // Index of controller
+            controller_index,
+            // Out pointer for state to set
+            controller_state.as_mut_ptr(),
+        ) == ERROR_SUCCESS
+        {
+            // Function succeeded - state is initialized
+            Some(controller_state.assume_init())
+        } else {
+            None
+        }
+    }
+}
+
+/// Frames to wait before polling an empty controller slot again.
+/// `XInputGetState` is slow to fail, so calling it every frame for every empty slot is costly.
+const DISCONNECTED_POLL_INTERVAL: u32 = 120;
+
+/// Tracks whether a controller is plugged into an XInput slot
+#[derive(Debug, Default, Clone, Copy)]
+struct ControllerSlot {
+    connected: bool,
+    frames_until_poll: u32,
+}
+
+impl ControllerSlot {
+    /// Calls `poll` unless the slot is empty and still cooling down, returning its result.
+    /// Logs when a controller appears or disappears.
+    fn poll<T, F: FnOnce() -> Option<T>>(&mut self, controller_index: DWORD, poll: F) -> Option<T> {
+        if !self.connected && self.frames_until_poll > 0 {
+            self.frames_until_poll -= 1;
+            return None;
+        }
+
+        let state = poll();
+        let connected = state.is_some();
+        if connected != self.connected {
+            if connected {
+                println!("Controller {} connected", controller_index);
+            } else {
+                println!("Controller {} disconnected", controller_index);
+            }
+        }
+
+        self.connected = connected;
+        if !connected {
+            self.frames_until_poll = DISCONNECTED_POLL_INTERVAL;
+        }
+        state
+    }
+}
+
+fn gamepad_input_from(pad: &XINPUT_GAMEPAD) -> GamepadInput {
+    let button = |mask| (pad.wButtons & mask) != 0;
+    let trigger = |value: BYTE| f32::from(value) / f32::from(u8::MAX);
+    let (left_stick_x, left_stick_y) = apply_deadzone(
+        pad.sThumbLX,
+        pad.sThumbLY,
+        XINPUT_GAMEPAD_LEFT_THUMB_DEADZONE,
+    );
+    let (right_stick_x, right_stick_y) = apply_deadzone(
+        pad.sThumbRX,
+        pad.sThumbRY,
+        XINPUT_GAMEPAD_RIGHT_THUMB_DEADZONE,
+    );
+
+    GamepadInput {
+        connected: true,
+
+        up: button(XINPUT_GAMEPAD_DPAD_UP),
+        down: button(XINPUT_GAMEPAD_DPAD_DOWN),
+        left: button(XINPUT_GAMEPAD_DPAD_LEFT),
+        right: button(XINPUT_GAMEPAD_DPAD_RIGHT),
+
+        a: button(XINPUT_GAMEPAD_A),
+        b: button(XINPUT_GAMEPAD_B),
+        x: button(XINPUT_GAMEPAD_X),
+        y: button(XINPUT_GAMEPAD_Y),
+
+        start: button(XINPUT_GAMEPAD_START),
+        back: button(XINPUT_GAMEPAD_BACK),
+        left_shoulder: button(XINPUT_GAMEPAD_LEFT_SHOULDER),
+        right_shoulder: button(XINPUT_GAMEPAD_RIGHT_SHOULDER),
+
+        left_trigger: trigger(pad.bLeftTrigger),
+        right_trigger: trigger(pad.bRightTrigger),
+
+        left_stick_x,
+        left_stick_y,
+        right_stick_x,
+        right_stick_y,
+    }
+}
+
+/// Sets the speed of the low-frequency (left) and high-frequency (right) rumble motors.
+/// Returns `false` if the controller isn't connected.
+fn set_controller_vibration(controller_index: DWORD, left_motor: u16, right_motor: u16) -> bool {
+    let mut vibration = XINPUT_VIBRATION {
+        wLeftMotorSpeed: left_motor,
+        wRightMotorSpeed: right_motor,
+    };
+    unsafe {
+        XInputSetState(
+            // Index of controller
+            controller_index,
+            // Motor speeds to set
+            &mut vibration as *mut _,
+        ) == ERROR_SUCCESS
+    }
+}
+
+/// Bytes in one frame (one sample per channel) of `format` sound
+fn block_align(format: SampleFormat, channel_count: u16) -> u16 {
+    const BITS_PER_BYTE: u16 = 8;
+
+    // product of channels and bits per sample divided by bits per byte
+    channel_count * format.bits_per_sample() / BITS_PER_BYTE
+}
+
+/// Describes `format` sound to DirectSound. 16-bit PCM is a plain `WAVEFORMATEX`, the leading
+/// `Format` of the result, other formats need the extensible description.
+fn wave_format(
+    format: SampleFormat,
+    channel_count: u16,
+    samples_per_second: u32,
+) -> WAVEFORMATEXTENSIBLE {
+    let bits_per_sample = format.bits_per_sample();
+    let block_align = block_align(format, channel_count);
+    let header = WAVEFORMATEX {
+        wFormatTag: WAVE_FORMAT_PCM,
+        nChannels: channel_count,
+        nSamplesPerSec: samples_per_second,
+        // product of sample rate and block align
+        nAvgBytesPerSec: samples_per_second * u32::from(block_align),
+        nBlockAlign: block_align,
+        wBitsPerSample: bits_per_sample,
+        // Ignored for PCM
+        cbSize: 0,
+    };
+    let (format_tag, sub_format) = match format {
+        SampleFormat::Pcm16 => (WAVE_FORMAT_PCM, GUID_NULL),
+        SampleFormat::Pcm24 => (WAVE_FORMAT_EXTENSIBLE, KSDATAFORMAT_SUBTYPE_PCM),
+        SampleFormat::Float32 => (WAVE_FORMAT_EXTENSIBLE, KSDATAFORMAT_SUBTYPE_IEEE_FLOAT),
+    };
+    let extension_size = if format_tag == WAVE_FORMAT_EXTENSIBLE {
+        (std::mem::size_of::<WAVEFORMATEXTENSIBLE>() - std::mem::size_of::<WAVEFORMATEX>()) as u16
+    } else {
+        0
+    };
+
+    WAVEFORMATEXTENSIBLE {
+        Format: WAVEFORMATEX {
+            wFormatTag: format_tag,
+            cbSize: extension_size,
+            ..header
+        },
+        // Every bit of each sample is used
+        Samples: bits_per_sample,
+        dwChannelMask: match channel_count {
+            1 => SPEAKER_FRONT_CENTER,
+            2 => SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT,
+            // Let the driver pick
+            _ => 0,
+        },
+        SubFormat: sub_format,
+    }
+}
+
+fn direct_sound_error(message: &str, result: winerror::HRESULT) -> io::Error {
+    io::Error::other(format!("{} (HRESULT {:#010x})", message, result))
+}
+
+/// Owns one reference to a COM object, released when dropped
+struct ComPtr<T: Deref<Target = IUnknown>> {
+    ptr: NonNull<T>,
+}
+
+impl<T: Deref<Target = IUnknown>> ComPtr<T> {
+    /// Takes over the reference `ptr` holds, e.g. one just returned through an out pointer.
+    /// Panics if `ptr` is null. It must otherwise point to a live COM object.
+    unsafe fn new(ptr: *mut T) -> ComPtr<T> {
+        ComPtr {
+            ptr: NonNull::new(ptr).expect("Null COM pointer"),
+        }
+    }
+}
+
+impl<T: Deref<Target = IUnknown>> Deref for ComPtr<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Valid until the reference is released in drop
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: Deref<Target = IUnknown>> Drop for ComPtr<T> {
+    fn drop(&mut self) {
+        unsafe { self.Release() };
+    }
+}
+
+/// The DirectSound objects sound is played through. Fields drop in order, so the buffers are
+/// released before the device that made them.
+struct DirectSoundObjects {
+    secondary_buffer: ComPtr<IDirectSoundBuffer>,
+    /// Kept for as long as the secondary buffer plays in its format
+    _primary_buffer: ComPtr<IDirectSoundBuffer>,
+    _direct_sound: ComPtr<IDirectSound>,
+}
+
+/// Fails if there is no usable sound device; anything created before the failure is released.
 fn initialize_direct_sound(
     window: HWND,
     buffer_size: u32,
     samples_per_second: u32,
-) -> (LPDIRECTSOUND, LPDIRECTSOUNDBUFFER, LPDIRECTSOUNDBUFFER) {
+    channel_count: u16,
+    format: SampleFormat,
+) -> io::Result<DirectSoundObjects> {
     let mut direct_sound_ptr: LPDIRECTSOUND = ptr::null_mut();
-    if unsafe {
+    let result = unsafe {
         DirectSoundCreate(
             // Null for device default
             ptr::null(),
@@ -139,22 +1070,25 @@ fn initialize_direct_sound(
             // Must be null
             ptr::null_mut(),
         )
-    } != DS_OK
-    {
-        panic!("Failed to create DirectSound");
+    };
+    if result != DS_OK {
+        return Err(direct_sound_error("Failed to create DirectSound", result));
     }
-    assert!(!direct_sound_ptr.is_null());
+    let direct_sound = unsafe { ComPtr::new(direct_sound_ptr) };
 
-    if unsafe {
-        (*direct_sound_ptr).SetCooperativeLevel(
+    let result = unsafe {
+        direct_sound.SetCooperativeLevel(
             // window handle
             window,
             // flags
             DSSCL_PRIORITY,
         )
-    } != DS_OK
-    {
-        panic!("Failed to set DirectSound cooperative level")
+    };
+    if result != DS_OK {
+        return Err(direct_sound_error(
+            "Failed to set DirectSound cooperative level",
+            result,
+        ));
     }
 
     let primary_buffer_description = DSBUFFERDESC {
@@ -172,8 +1106,8 @@ fn initialize_direct_sound(
         guid3DAlgorithm: GUID_NULL,
     };
     let mut primary_buffer_ptr: LPDIRECTSOUNDBUFFER = ptr::null_mut();
-    if unsafe {
-        (*direct_sound_ptr).CreateSoundBuffer(
+    let result = unsafe {
+        direct_sound.CreateSoundBuffer(
             // DSBUFFERDESC object describing the buffer
             &primary_buffer_description as *const _,
             // Out pointer for allocated buffer
@@ -181,36 +1115,25 @@ fn initialize_direct_sound(
             // Must be null
             ptr::null_mut(),
         )
-    } != DS_OK
-    {
-        panic!("Failed to create primary DirectSound buffer");
-    }
-    assert!(!primary_buffer_ptr.is_null());
-
-    let mut wav_format = {
-        const BITS_PER_BYTE: u16 = 8;
-
-        let num_channels = 2;
-        let bits_per_sample = 16;
-        // product of channels and bits per sample divided by bits per byte
-        let block_align = num_channels * bits_per_sample / BITS_PER_BYTE;
-        // product of sample rate and block align
-        let avg_bytes_per_sec = samples_per_second * u32::from(block_align);
-
-        WAVEFORMATEX {
-            wFormatTag: WAVE_FORMAT_PCM,
-            nChannels: 2,
-            nSamplesPerSec: samples_per_second,
-            nAvgBytesPerSec: avg_bytes_per_sec,
-            nBlockAlign: block_align,
-            wBitsPerSample: bits_per_sample,
-            // Ignored for PCM
-            cbSize: 0,
-        }
     };
-
-    if unsafe { (*primary_buffer_ptr).SetFormat(&wav_format as *const _) } != DS_OK {
-        panic!("Failed to set primary sound buffer format");
+    if result != DS_OK {
+        return Err(direct_sound_error(
+            "Failed to create primary DirectSound buffer",
+            result,
+        ));
+    }
+    let primary_buffer = unsafe { ComPtr::new(primary_buffer_ptr) };
+
+    let mut wav_format = wave_format(format, channel_count, samples_per_second);
+    // DirectSound reads the extension when the header's cbSize says it's there
+    let wav_format_ptr = &mut wav_format as *mut WAVEFORMATEXTENSIBLE as *mut WAVEFORMATEX;
+
+    let result = unsafe { primary_buffer.SetFormat(wav_format_ptr) };
+    if result != DS_OK {
+        return Err(direct_sound_error(
+            "Failed to set primary sound buffer format",
+            result,
+        ));
     }
 
     let secondary_buffer_description = DSBUFFERDESC {
@@ -223,13 +1146,13 @@ fn initialize_direct_sound(
         // Must be 0
         dwReserved: 0,
         // For secondary buffer, pointer to format description
-        lpwfxFormat: &mut wav_format as *mut _,
+        lpwfxFormat: wav_format_ptr,
         // Must be GUID_NULL since 3D flag is not set
         guid3DAlgorithm: GUID_NULL,
     };
     let mut secondary_buffer_ptr: LPDIRECTSOUNDBUFFER = ptr::null_mut();
-    if unsafe {
-        (*direct_sound_ptr).CreateSoundBuffer(
+    let result = unsafe {
+        direct_sound.CreateSoundBuffer(
             // DSBUFFERDESC object describing the buffer
             &secondary_buffer_description as *const _,
             // Out pointer for allocated buffer
@@ -237,30 +1160,198 @@ fn initialize_direct_sound(
             // Must be null
             ptr::null_mut(),
         )
-    } != DS_OK
-    {
-        panic!("Failed to create secondary sound buffer");
+    };
+    if result != DS_OK {
+        return Err(direct_sound_error(
+            "Failed to create secondary sound buffer",
+            result,
+        ));
     }
-    assert!(!secondary_buffer_ptr.is_null());
+    let secondary_buffer = unsafe { ComPtr::new(secondary_buffer_ptr) };
+
+    // Successfully allocated our buffers
+    Ok(DirectSoundObjects {
+        secondary_buffer,
+        _primary_buffer: primary_buffer,
+        _direct_sound: direct_sound,
+    })
+}
 
-    // Successfully allocated our buffers - return their pointers
-    (direct_sound_ptr, primary_buffer_ptr, secondary_buffer_ptr)
+/// Sound queued ahead of the play cursor on top of one frame's worth, so a frame that runs a bit
+/// long doesn't starve the device before the next write
+const SOUND_SAFETY_SECONDS: f32 = 1.0 / 30.0;
+/// Longest frame time the sound latency follows, so a stall doesn't queue up seconds of sound
+const MAX_SOUND_FRAME_SECONDS: f32 = 0.1;
+
+/// Frames of sound to keep queued ahead of the play cursor when frames last `frame_seconds`
+fn latency_sample_count(frame_seconds: f32, sample_rate: u16) -> u32 {
+    let frame_seconds = if frame_seconds.is_finite() {
+        frame_seconds.clamp(0.0, MAX_SOUND_FRAME_SECONDS)
+    } else {
+        MAX_SOUND_FRAME_SECONDS
+    };
+    ((frame_seconds + SOUND_SAFETY_SECONDS) * f32::from(sample_rate)).ceil() as u32
 }
 
-struct SoundOutput {
-    buffer_size: u32,
-    running_sample_index: u32,
-    latency_sample_count: u32,
-    channel_count: u16,
-    sample_rate: u16,
-    bytes_per_sample: u16,
+/// Frames of sound `latency_ms` milliseconds stands for, rounded up. Kept to half of a ring
+/// buffer holding `buffer_frames`, the most that can be queued without the writes overtaking
+/// the play cursor. Returns whether it had to be clamped.
+fn latency_ms_to_samples(latency_ms: u32, sample_rate: u16, buffer_frames: u32) -> (u32, bool) {
+    let samples = (u64::from(latency_ms) * u64::from(sample_rate)).div_ceil(1000);
+    let max_samples = u64::from(buffer_frames / 2);
+    (samples.min(max_samples) as u32, samples > max_samples)
 }
 
-impl SoundOutput {
-    fn clear_buffer(&self, destination_buffer: &mut IDirectSoundBuffer) {
-        let mut region_1_ptr: LPVOID = ptr::null_mut();
-        let mut region_1_size: DWORD = 0;
-        let mut region_2_ptr: LPVOID = ptr::null_mut();
+/// Region of a `buffer_size` byte ring buffer to write so that the data ends `latency_bytes` ahead
+/// of `play_cursor`, continuing from `write_position`. Returns `(byte_to_lock, bytes_to_write)`.
+///
+/// At most half the buffer is queued. A write position further ahead than that is taken to have
+/// been overtaken by the play cursor, and writing restarts at the play cursor.
+fn ring_write_region(
+    play_cursor: u32,
+    write_position: u32,
+    latency_bytes: u32,
+    buffer_size: u32,
+) -> (u32, u32) {
+    if buffer_size == 0 {
+        return (0, 0);
+    }
+    let play_cursor = play_cursor % buffer_size;
+    let write_position = write_position % buffer_size;
+    let target_ahead = latency_bytes.min(buffer_size / 2);
+
+    // How far the written data already reaches past the play cursor, going round the end
+    let ahead = if write_position >= play_cursor {
+        write_position - play_cursor
+    } else {
+        buffer_size - play_cursor + write_position
+    };
+
+    if ahead <= target_ahead {
+        (write_position, target_ahead - ahead)
+    } else if ahead <= buffer_size / 2 {
+        // Queued further than needed, e.g. after the latency shrank: let the device catch up
+        (write_position, 0)
+    } else {
+        (play_cursor, target_ahead)
+    }
+}
+
+/// Whether the sound written to a `buffer_size` byte ring buffer, ending at `write_position`, ran
+/// out before the device could play it: the end falls short of `write_cursor`, the point up to
+/// which the device has already committed to playing, or the play cursor went past it. An end
+/// more than half the buffer ahead of the play cursor is taken to be behind it, as in
+/// `ring_write_region`.
+fn is_underrun(play_cursor: u32, write_cursor: u32, write_position: u32, buffer_size: u32) -> bool {
+    if buffer_size == 0 {
+        return false;
+    }
+    // Distance from the play cursor forward to `position`, going round the end
+    let ahead = |position: u32| {
+        (position % buffer_size + buffer_size - play_cursor % buffer_size) % buffer_size
+    };
+    let written_ahead = ahead(write_position);
+    written_ahead < ahead(write_cursor) || written_ahead > buffer_size / 2
+}
+
+/// Samples to copy into the two regions of a locked ring buffer from a source holding `available`
+/// samples, as `(region_1, region_2)`. Regions are filled in order and never past the source.
+fn region_sample_counts(region_1: usize, region_2: usize, available: usize) -> (usize, usize) {
+    let region_1 = region_1.min(available);
+    let region_2 = region_2.min(available - region_1);
+    (region_1, region_2)
+}
+
+struct SoundOutput {
+    buffer_size: u32,
+    /// Frame the next write starts at, kept below the buffer's frame count
+    running_sample_index: u32,
+    channel_count: u16,
+    sample_rate: u16,
+    format: SampleFormat,
+    /// Bytes per frame in `format`
+    bytes_per_sample: u16,
+    /// Frames kept queued ahead of the play cursor. `None` follows the frame time instead.
+    latency_samples: Option<u32>,
+    /// Whether anything has been queued yet, before which an empty buffer isn't an underrun
+    started: bool,
+    /// Bytes between the play and write cursors at the last position query, the device's own
+    /// latency
+    cursor_gap: u32,
+    /// Times the sound ran out since startup, each an audible gap
+    underrun_count: u32,
+}
+
+impl SoundOutput {
+    /// Number of `i16` samples (not frames or bytes) that fit in the DirectSound buffer
+    fn sample_capacity(&self) -> usize {
+        let frame_count = self.buffer_size / u32::from(self.bytes_per_sample);
+        frame_count as usize * usize::from(self.channel_count)
+    }
+
+    /// Keeps `latency_ms` of sound queued from now on, or follows the frame time for `None`.
+    /// Latency that doesn't fit the buffer is clamped, with a warning.
+    fn set_latency_ms(&mut self, latency_ms: Option<u32>) {
+        self.latency_samples = latency_ms.map(|latency_ms| {
+            let buffer_frames = self.buffer_size / u32::from(self.bytes_per_sample);
+            let (samples, clamped) =
+                latency_ms_to_samples(latency_ms, self.sample_rate, buffer_frames);
+            if clamped {
+                eprintln!(
+                    "Audio latency of {}ms doesn't fit the sound buffer, using {}ms",
+                    latency_ms,
+                    samples * 1000 / u32::from(self.sample_rate)
+                );
+            }
+            samples
+        });
+    }
+
+    /// Region of the DirectSound buffer to write this frame, as `(byte_to_lock, bytes_to_write)`,
+    /// queueing enough sound to last frames of `frame_seconds`. Without the play and write
+    /// cursors (no sound device, or the position query failed) nothing is written.
+    fn bytes_to_write(&mut self, cursors: Option<(u32, u32)>, frame_seconds: f32) -> (u32, u32) {
+        let (play_cursor, write_cursor) = match cursors {
+            Some(cursors) => cursors,
+            None => return (0, 0),
+        };
+        let bytes_per_sample = u32::from(self.bytes_per_sample);
+
+        self.cursor_gap = (write_cursor + self.buffer_size - play_cursor) % self.buffer_size.max(1);
+        if self.started
+            && is_underrun(
+                play_cursor,
+                write_cursor,
+                self.running_sample_index * bytes_per_sample,
+                self.buffer_size,
+            )
+        {
+            self.underrun_count += 1;
+            eprintln!(
+                "Sound underrun, {} so far ({} bytes between the cursors)",
+                self.underrun_count, self.cursor_gap
+            );
+        }
+        self.started = true;
+
+        let (byte_to_lock, bytes_to_write) = ring_write_region(
+            // Keep writes on whole frames
+            play_cursor - play_cursor % bytes_per_sample,
+            self.running_sample_index * bytes_per_sample,
+            self.latency_samples
+                .unwrap_or_else(|| latency_sample_count(frame_seconds, self.sample_rate))
+                * bytes_per_sample,
+            self.buffer_size,
+        );
+        // Picks up from the play cursor if it overtook us
+        self.running_sample_index = byte_to_lock / bytes_per_sample;
+        (byte_to_lock, bytes_to_write)
+    }
+
+    fn clear_buffer(&self, destination_buffer: &IDirectSoundBuffer) {
+        let mut region_1_ptr: LPVOID = ptr::null_mut();
+        let mut region_1_size: DWORD = 0;
+        let mut region_2_ptr: LPVOID = ptr::null_mut();
         let mut region_2_size: DWORD = 0;
         unsafe {
             if destination_buffer.Lock(
@@ -285,11 +1376,16 @@ impl SoundOutput {
 
     fn fill_buffer(
         &mut self,
-        destination_buffer: &mut IDirectSoundBuffer,
+        destination_buffer: &IDirectSoundBuffer,
         source_buffer: &SoundBuffer,
         byte_to_lock: u32,
         bytes_to_write: u32,
     ) {
+        // Only the first `sample_count` frames were rendered this frame
+        let available = (source_buffer.sample_count * usize::from(source_buffer.channels))
+            .min(source_buffer.samples.len());
+        debug_assert!(bytes_to_write as usize / self.format.bytes_per_sample() <= available);
+
         let mut region_1_ptr: LPVOID = ptr::null_mut();
         let mut region_1_size: DWORD = 0;
         let mut region_2_ptr: LPVOID = ptr::null_mut();
@@ -309,46 +1405,62 @@ impl SoundOutput {
                 return;
             }
 
-            let region_1_sample_count = region_1_size / std::mem::size_of::<i16>() as u32;
-            let region_2_sample_count = region_2_size / std::mem::size_of::<i16>() as u32;
+            let bytes_per_sample = self.format.bytes_per_sample();
+            let region_1_capacity = region_1_size as usize / bytes_per_sample;
+            let region_2_capacity = region_2_size as usize / bytes_per_sample;
+            debug_assert!(region_1_capacity + region_2_capacity <= available);
+            let (region_1_sample_count, region_2_sample_count) =
+                region_sample_counts(region_1_capacity, region_2_capacity, available);
 
-            assert!(
-                region_1_sample_count as usize + region_2_sample_count as usize
-                    <= source_buffer.samples.len()
-            );
-
-            ptr::copy_nonoverlapping(
-                source_buffer.samples.as_ptr(),
-                region_1_ptr as *mut _,
-                region_1_sample_count as usize,
-            );
-            ptr::copy_nonoverlapping(
-                source_buffer
-                    .samples
-                    .as_ptr()
-                    .add(region_1_sample_count as usize),
-                region_2_ptr as *mut _,
-                region_2_sample_count as usize,
+            let region_1 =
+                std::slice::from_raw_parts_mut(region_1_ptr as *mut u8, region_1_size as usize);
+            let region_1_sample_count = source_buffer.encode_samples(
+                0,
+                self.format,
+                &mut region_1[..region_1_sample_count * bytes_per_sample],
             );
-
-            self.running_sample_index += region_1_sample_count / u32::from(self.channel_count)
-                + region_2_sample_count / u32::from(self.channel_count);
+            // Whatever the source couldn't cover plays as silence rather than last lap's sound
+            region_1[region_1_sample_count * bytes_per_sample..].fill(0);
+            // Region 2 is null unless the locked range wraps around the end of the buffer
+            let region_2_sample_count = if region_2_ptr.is_null() {
+                0
+            } else {
+                let region_2 =
+                    std::slice::from_raw_parts_mut(region_2_ptr as *mut u8, region_2_size as usize);
+                let region_2_sample_count = source_buffer.encode_samples(
+                    region_1_sample_count,
+                    self.format,
+                    &mut region_2[..region_2_sample_count * bytes_per_sample],
+                );
+                region_2[region_2_sample_count * bytes_per_sample..].fill(0);
+                region_2_sample_count
+            };
+
+            // Only what was copied counts, the silence gets written over next frame
+            let frames_written = (region_1_sample_count + region_2_sample_count) as u32
+                / u32::from(self.channel_count);
+            self.running_sample_index = (self.running_sample_index + frames_written)
+                % (self.buffer_size / u32::from(self.bytes_per_sample));
 
             destination_buffer.Unlock(region_1_ptr, region_1_size, region_2_ptr, region_2_size);
         }
     }
 }
 
-const_assert!(std::mem::size_of::<BITMAPINFOHEADER>() < u32::max_value() as usize);
+const_assert!(std::mem::size_of::<BITMAPINFOHEADER>() < u32::MAX as usize);
 
-static mut DISPLAY_BUFFER: DisplayBuffer = DisplayBuffer {
-    memory: Vec::new(),
-    current_offset: 0,
+/// Size of the backbuffer until the first `WM_SIZE`
+const INITIAL_DISPLAY_BUFFER: DisplayBuffer = DisplayBuffer {
+    back: Vec::new(),
+    front: Vec::new(),
+    gradient_mode: GradientMode::FullRgb,
+    blend_mode: BlendMode::Linear,
     width: 1280,
     height: 720,
 };
 
-static mut BITMAP_INFO: BITMAPINFO = BITMAPINFO {
+/// A 32-bit top-down DIB, sized by `resize_dib_section`
+const INITIAL_BITMAP_INFO: BITMAPINFO = BITMAPINFO {
     bmiHeader: BITMAPINFOHEADER {
         biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
         biWidth: 0,
@@ -377,12 +1489,107 @@ unsafe extern "system" fn main_window_callback(
     w_param: WPARAM,
     l_param: LPARAM,
 ) -> LRESULT {
-    DISPLAY_BUFFER.resize_dib_section(&mut BITMAP_INFO, 1280, 720);
+    if message == WM_NCCREATE {
+        // Stash the state passed to CreateWindowExW so later messages can reach it
+        let create_struct = &*(l_param as *const CREATESTRUCTW);
+        SetWindowLongPtrW(
+            window,
+            GWLP_USERDATA,
+            create_struct.lpCreateParams as LONG_PTR,
+        );
+    }
+    let state = match WindowState::from_window(window) {
+        Some(state) => state,
+        // A few messages arrive before WM_NCCREATE
+        None => return DefWindowProcW(window, message, w_param, l_param),
+    };
 
     let mut result = 0;
     match message {
-        WM_CLOSE | WM_DESTROY => RUNNING = false,
-        WM_KEYUP | WM_KEYDOWN | WM_SYSKEYUP | WM_SYSKEYDOWN => handle_key_press(w_param, l_param),
+        WM_SIZE => {
+            // lParam holds the new client size, saving a GetClientRect call
+            let dimension = WindowDimension {
+                width: i32::from(LOWORD(l_param as DWORD)),
+                height: i32::from(HIWORD(l_param as DWORD)),
+            };
+            if let Some((width, height)) =
+                state.backbuffer_resize(&dimension, w_param == SIZE_MINIMIZED)
+            {
+                state.display_buffer.borrow_mut().resize_dib_section(
+                    &mut state.bitmap_info.borrow_mut(),
+                    width,
+                    height,
+                );
+            }
+        }
+        WM_DPICHANGED => {
+            // Fullscreen already covers the monitor, whatever its scaling
+            if state.windowed_placement.get().is_none() {
+                // lParam points to the size and position Windows suggests for the new DPI
+                let suggested = &*(l_param as *const RECT);
+                SetWindowPos(
+                    window,
+                    ptr::null_mut(),
+                    suggested.left,
+                    suggested.top,
+                    suggested.right - suggested.left,
+                    suggested.bottom - suggested.top,
+                    SWP_NOZORDER | SWP_NOACTIVATE,
+                );
+            }
+        }
+        WM_CLOSE => request_quit_confirmed(window, state),
+        WM_DESTROY => request_quit(),
+        // wParam is TRUE when one of our windows is being activated
+        WM_ACTIVATEAPP => state.set_active(w_param != 0),
+        WM_MOUSEMOVE => {
+            // Client coordinates are packed as signed 16-bit values
+            state.mouse.set(MouseInput {
+                x: i32::from(LOWORD(l_param as DWORD) as i16),
+                y: i32::from(HIWORD(l_param as DWORD) as i16),
+                ..state.mouse.get()
+            });
+        }
+        WM_MOUSEWHEEL => {
+            // The high word is the signed distance turned, in multiples of WHEEL_DELTA per notch
+            let delta = i32::from(HIWORD(w_param as DWORD) as i16);
+            let (notches, remainder) = wheel_notches(state.wheel_remainder.get(), delta);
+            state.wheel_remainder.set(remainder);
+            let step = notches * i32::from(state.wheel_step_hz.get());
+            state.tone_hz.set(
+                (i32::from(state.tone_hz.get()) + step)
+                    .clamp(i32::from(MIN_TONE_HZ), i32::from(u16::MAX)) as u16,
+            );
+        }
+        WM_LBUTTONDOWN | WM_LBUTTONUP | WM_RBUTTONDOWN | WM_RBUTTONUP => {
+            let mut mouse = state.mouse.get();
+            match message {
+                WM_LBUTTONDOWN => mouse.left = true,
+                WM_LBUTTONUP => mouse.left = false,
+                WM_RBUTTONDOWN => mouse.right = true,
+                _ => mouse.right = false,
+            }
+            state.mouse.set(mouse);
+
+            // Keep receiving mouse messages while a button is held, even outside the window
+            if mouse.left || mouse.right {
+                SetCapture(window);
+            } else {
+                ReleaseCapture();
+            }
+        }
+        WM_KEYUP | WM_KEYDOWN | WM_SYSKEYUP | WM_SYSKEYDOWN => {
+            handle_key_press(window, state, w_param, l_param)
+        }
+        WM_CHAR => {
+            let mut decoder = state.utf16_decoder.get();
+            if let Some(c) = decoder.push(w_param as u16).filter(|&c| is_text_char(c)) {
+                state.text.borrow_mut().push(c);
+            }
+            state.utf16_decoder.set(decoder);
+        }
+        // Swallow the character from Alt+Enter, DefWindowProcW would beep for it
+        WM_SYSCHAR if w_param == VK_RETURN as WPARAM => (),
         WM_PAINT => {
             let mut paint = MaybeUninit::uninit();
             let device_context = BeginPaint(
@@ -396,12 +1603,18 @@ unsafe extern "system" fn main_window_callback(
             }
             let paint = paint.assume_init();
             let dimension = get_window_dimension(window);
-            DISPLAY_BUFFER.draw_to_window(
-                // Static can only be accessed from main thread
-                &BITMAP_INFO,
+            let display_buffer = state.display_buffer.borrow();
+            let destination = destination_rect(
+                state.scale_mode,
+                display_buffer.width,
+                display_buffer.height,
+                &dimension,
+            );
+            display_buffer.draw_to_window(
+                &state.bitmap_info.borrow(),
                 device_context,
-                dimension.width,
-                dimension.height,
+                &destination,
+                &dimension,
             );
             EndPaint(
                 // Winow handle
@@ -441,8 +1654,376 @@ fn get_cycles() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
 }
 
-pub fn win32_main() -> io::Result<()> {
-    #[allow(unused_variables)]
+const DEFAULT_TARGET_FPS: u32 = 60;
+
+/// Caps the main loop at `target_fps` by waiting out the rest of each frame. Restores the
+/// scheduler granularity when dropped.
+struct FrameLimiter {
+    target_fps: u32,
+    perf_counter_frequency: i64,
+    /// Whether `Sleep` has millisecond granularity, i.e. `timeBeginPeriod(1)` succeeded
+    sleep_is_granular: bool,
+}
+
+impl FrameLimiter {
+    fn target_counts_per_frame(&self) -> i64 {
+        self.perf_counter_frequency / i64::from(self.target_fps.max(1))
+    }
+
+    /// Blocks until a whole frame has passed since the performance counter read `frame_start`
+    fn wait_for_frame_end(&self, frame_start: i64) -> io::Result<()> {
+        let target_counts = self.target_counts_per_frame();
+        let elapsed_counts = |now: LARGE_INTEGER| unsafe { *now.QuadPart() } - frame_start;
+
+        if self.sleep_is_granular {
+            let sleep_ms = sleep_milliseconds(
+                elapsed_counts(get_performance_counter()?),
+                target_counts,
+                self.perf_counter_frequency,
+            );
+            if sleep_ms > 0 {
+                unsafe { Sleep(sleep_ms) };
+            }
+        }
+
+        // Spin for whatever is left, which is less than Sleep can resolve
+        while elapsed_counts(get_performance_counter()?) < target_counts {}
+        Ok(())
+    }
+}
+
+impl Drop for FrameLimiter {
+    fn drop(&mut self) {
+        if self.sleep_is_granular {
+            unsafe { timeEndPeriod(1) };
+        }
+    }
+}
+
+/// Whether the desktop compositor is running, which it always is from Windows 8 on
+fn dwm_composition_enabled() -> bool {
+    let mut enabled: BOOL = FALSE;
+    let result = unsafe { DwmIsCompositionEnabled(&mut enabled) };
+    result == S_OK && enabled != FALSE
+}
+
+/// The present mode to start in. Vsync waits on the compositor, so without one it falls back to
+/// the frame rate cap.
+fn startup_present_mode(requested: PresentMode, composition_enabled: bool) -> PresentMode {
+    match requested {
+        PresentMode::Vsync if !composition_enabled => PresentMode::CappedFps,
+        mode => mode,
+    }
+}
+
+/// Presents a frame in `mode`. Vsync calls `wait_for_compositor`, which returns `false` if it
+/// couldn't wait, before `present`; the cap calls `wait_for_frame_end` after it. Returns the mode
+/// for the next frame: a failed compositor wait (composition was turned off) falls back to the
+/// cap, starting with this frame.
+fn present_frame<W, P, C>(
+    mode: PresentMode,
+    wait_for_compositor: W,
+    present: P,
+    wait_for_frame_end: C,
+) -> io::Result<PresentMode>
+where
+    W: FnOnce() -> bool,
+    P: FnOnce(),
+    C: FnOnce() -> io::Result<()>,
+{
+    let mode = match mode {
+        PresentMode::Vsync if !wait_for_compositor() => PresentMode::CappedFps,
+        mode => mode,
+    };
+    present();
+    if mode == PresentMode::CappedFps {
+        wait_for_frame_end()?;
+    }
+    Ok(mode)
+}
+
+/// Formats a line like `16.7 ms/f, 60 fps, 35 Mc/f`
+fn format_frame_stats(
+    counter_elapsed: i64,
+    perf_counter_frequency: i64,
+    cycles_elapsed: u64,
+) -> String {
+    let counter_elapsed = counter_elapsed.max(1) as f64;
+    let perf_counter_frequency = perf_counter_frequency as f64;
+
+    let ms_per_frame = 1000.0 * counter_elapsed / perf_counter_frequency;
+    let fps = perf_counter_frequency / counter_elapsed;
+    let mega_cycles_per_frame = cycles_elapsed as f64 / 1_000_000.0;
+    format!(
+        "{:.1} ms/f, {:.0} fps, {:.0} Mc/f\n",
+        ms_per_frame, fps, mega_cycles_per_frame
+    )
+}
+
+fn seconds_elapsed(start_counter: i64, end_counter: i64, perf_counter_frequency: i64) -> f32 {
+    (end_counter - start_counter) as f32 / perf_counter_frequency as f32
+}
+
+/// Whole milliseconds that can be slept once `elapsed_counts` of a `target_counts` long frame
+/// have passed. One millisecond is held back since `Sleep` can overshoot; the caller busy-waits
+/// the remainder.
+fn sleep_milliseconds(elapsed_counts: i64, target_counts: i64, frequency: i64) -> u32 {
+    let remaining_counts = target_counts - elapsed_counts;
+    if remaining_counts <= 0 || frequency <= 0 {
+        return 0;
+    }
+
+    let remaining_ms = remaining_counts * 1000 / frequency;
+    (remaining_ms - 1).clamp(0, i64::from(u32::MAX)) as u32
+}
+
+/// Dispatches all queued messages to the window procedure. With `wait`, first blocks until a
+/// message arrives.
+fn process_messages(wait: bool) {
+    unsafe {
+        let mut message = MaybeUninit::uninit();
+        if wait {
+            // 0 for WM_QUIT and -1 for errors, neither of which should be dispatched
+            if GetMessageW(message.as_mut_ptr(), ptr::null_mut(), 0, 0) <= 0 {
+                request_quit();
+                return;
+            }
+            let message = message.assume_init();
+            TranslateMessage(&message as *const _);
+            DispatchMessageW(&message as *const _);
+        }
+
+        while PeekMessageW(
+            // Out pointer for message
+            message.as_mut_ptr(),
+            // Null to receive all messages meant for current thread
+            ptr::null_mut(),
+            // Next two params 0 to receive all available messages
+            0,
+            0,
+            // Remove messages from queue after peek
+            PM_REMOVE,
+        ) != 0
+        {
+            // Non-zero return value means messages are available, so message is initialized
+            let message = message.assume_init();
+            if message.message == WM_QUIT {
+                request_quit();
+            }
+
+            TranslateMessage(&message as *const _);
+            DispatchMessageW(&message as *const _);
+        }
+    }
+}
+
+/// Sizes of the blocks making up `GameMemory`
+const PERMANENT_STORAGE_SIZE: usize = 1024 * 1024;
+const TRANSIENT_STORAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Built from `src/lib.rs` next to the executable
+const GAME_DLL_NAME: &str = "game.dll";
+/// What the DLL is copied to before loading, leaving the original free for the linker to replace
+const GAME_DLL_TEMP_NAME: &str = "game_temp.dll";
+
+/// Game code in use: loaded from the game DLL, or the copy linked into the executable when there
+/// is no usable DLL
+struct GameCode {
+    /// Null when running the linked code
+    library: HMODULE,
+    update: UpdateFn,
+    render: RenderFn,
+    /// Write time of the DLL this was loaded from (or failed to load from)
+    last_write_time: Option<SystemTime>,
+}
+
+impl GameCode {
+    fn linked(last_write_time: Option<SystemTime>) -> GameCode {
+        GameCode {
+            library: ptr::null_mut(),
+            update: game_update,
+            render: game_render,
+            last_write_time,
+        }
+    }
+
+    /// Falls back to the linked code if the DLL can't be loaded. A DLL that can't be copied
+    /// (e.g. the linker is still writing it) is retried on the next check; one that is broken
+    /// waits until it is rebuilt.
+    fn load(dll_path: &Path, temp_dll_path: &Path) -> GameCode {
+        let write_time = file_write_time(dll_path);
+        if write_time.is_none() || fs::copy(dll_path, temp_dll_path).is_err() {
+            return GameCode::linked(None);
+        }
+
+        unsafe {
+            let library = LoadLibraryW(win32_string(&temp_dll_path.to_string_lossy()).as_ptr());
+            if library.is_null() {
+                eprintln!("Failed to load {}", temp_dll_path.display());
+                return GameCode::linked(write_time);
+            }
+            let update = GetProcAddress(library, b"game_update\0".as_ptr() as LPCSTR);
+            let render = GetProcAddress(library, b"game_render\0".as_ptr() as LPCSTR);
+            if update.is_null() || render.is_null() {
+                eprintln!("{} is missing game exports", dll_path.display());
+                FreeLibrary(library);
+                return GameCode::linked(write_time);
+            }
+
+            GameCode {
+                library,
+                // The exports have these signatures, see src/lib.rs
+                update: std::mem::transmute::<FARPROC, UpdateFn>(update),
+                render: std::mem::transmute::<FARPROC, RenderFn>(render),
+                last_write_time: write_time,
+            }
+        }
+    }
+
+    /// Switches back to the linked code, after which the DLL can be replaced
+    fn unload(&mut self) {
+        // Dropping the loaded code frees the DLL
+        *self = GameCode::linked(self.last_write_time);
+    }
+}
+
+impl Drop for GameCode {
+    fn drop(&mut self) {
+        if !self.library.is_null() {
+            unsafe { FreeLibrary(self.library) };
+        }
+    }
+}
+
+/// Calls `release` with `resource` when dropped, so it is released exactly once on every path out
+/// of `win32_main`, early returns and panics included
+struct Guard<T: Copy> {
+    resource: T,
+    release: fn(T),
+}
+
+impl<T: Copy> Guard<T> {
+    fn new(resource: T, release: fn(T)) -> Guard<T> {
+        Guard { resource, release }
+    }
+}
+
+impl<T: Copy> Drop for Guard<T> {
+    fn drop(&mut self) {
+        (self.release)(self.resource);
+    }
+}
+
+/// Destroys the window when dropped
+fn window_guard(window: HWND) -> Guard<HWND> {
+    Guard::new(window, |window| {
+        if unsafe { DestroyWindow(window) } == 0 {
+            eprintln!("Failed to destroy window");
+        }
+    })
+}
+
+/// Releases a device context from `GetDC` when dropped. Must be dropped before the window.
+fn device_context_guard(window: HWND, device_context: HDC) -> Guard<(HWND, HDC)> {
+    Guard::new(
+        (window, device_context),
+        |(window, device_context)| unsafe {
+            ReleaseDC(window, device_context);
+        },
+    )
+}
+
+/// Destroys an icon from `create_icon` when dropped, if there is one
+fn icon_guard(icon: HICON) -> Guard<HICON> {
+    Guard::new(icon, |icon| {
+        if !icon.is_null() {
+            unsafe { DestroyIcon(icon) };
+        }
+    })
+}
+
+/// Frees memory from `VirtualAlloc` when dropped
+fn virtual_memory_guard(memory: LPVOID) -> Guard<LPVOID> {
+    Guard::new(memory, |memory| unsafe {
+        VirtualFree(memory, 0, MEM_RELEASE);
+    })
+}
+
+/// Built-in window icon, used unless `win32_main` is given another one
+const DEFAULT_ICON: &[u8] = include_bytes!("../assets/icon.ico");
+
+/// Finds the image in an `.ico` file best suited to `size` x `size` pixels: the smallest one at
+/// least that big, otherwise the biggest. Returns `None` if the file is malformed.
+fn select_icon_image(ico: &[u8], size: u32) -> Option<&[u8]> {
+    let read_u16 = |offset: usize| -> Option<u16> {
+        Some(u16::from_le_bytes([
+            *ico.get(offset)?,
+            *ico.get(offset + 1)?,
+        ]))
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from(read_u16(offset)?) | u32::from(read_u16(offset + 2)?) << 16)
+    };
+
+    // ICONDIR: reserved, type (1 for icons), entry count
+    if read_u16(0)? != 0 || read_u16(2)? != 1 {
+        return None;
+    }
+    let entry_count = usize::from(read_u16(4)?);
+
+    let mut best: Option<(u32, &[u8])> = None;
+    for entry in 0..entry_count {
+        // ICONDIRENTRY: 16 bytes each, a width of 0 means 256
+        let entry_offset = 6 + entry * 16;
+        let width = match *ico.get(entry_offset)? {
+            0 => 256,
+            width => u32::from(width),
+        };
+        let image_size = read_u32(entry_offset + 8)? as usize;
+        let image_offset = read_u32(entry_offset + 12)? as usize;
+        let image = ico.get(image_offset..image_offset.checked_add(image_size)?)?;
+
+        let is_better = match best {
+            None => true,
+            Some((best_width, _)) if best_width < size => width > best_width,
+            Some((best_width, _)) => width >= size && width < best_width,
+        };
+        if is_better {
+            best = Some((width, image));
+        }
+    }
+    best.map(|(_, image)| image)
+}
+
+/// Creates a `size` x `size` icon from an `.ico` file. Returns null on failure, which callers
+/// can pass on to get the system default icon.
+fn create_icon(ico: &[u8], size: i32) -> HICON {
+    let image = match select_icon_image(ico, size.max(0) as u32) {
+        Some(image) => image,
+        None => return ptr::null_mut(),
+    };
+    unsafe {
+        CreateIconFromResourceEx(
+            // Image data, which CreateIconFromResourceEx doesn't write to
+            image.as_ptr() as *mut _,
+            image.len() as DWORD,
+            // Icon rather than cursor
+            TRUE,
+            // Format version, must be 0x00030000
+            0x0003_0000,
+            size,
+            size,
+            LR_DEFAULTCOLOR,
+        )
+    }
+}
+
+/// `icon` is the contents of an `.ico` file to use instead of the built-in window icon
+pub fn win32_main(
+    options: &Options,
+    mut settings: SettingsWatcher,
+    icon: Option<&[u8]>,
+) -> io::Result<()> {
     let perf_counter_frequency = unsafe {
         let mut perf_counter_frequency = MaybeUninit::uninit();
         if QueryPerformanceFrequency(perf_counter_frequency.as_mut_ptr()) == 0 {
@@ -451,6 +2032,13 @@ pub fn win32_main() -> io::Result<()> {
         *perf_counter_frequency.assume_init().QuadPart()
     };
 
+    // Ask for 1ms scheduler granularity so the frame limiter can Sleep precisely
+    let frame_limiter = FrameLimiter {
+        target_fps: DEFAULT_TARGET_FPS,
+        perf_counter_frequency,
+        sleep_is_granular: unsafe { timeBeginPeriod(1) } == TIMERR_NOERROR,
+    };
+
     let window_name = win32_string("HandmadeWindowClass");
     let title = win32_string("Handmade!");
 
@@ -460,6 +2048,11 @@ pub fn win32_main() -> io::Result<()> {
             ptr::null(),
         )
     };
+    // Null if the icon can't be used, which falls back to the default one
+    let icon = icon.unwrap_or(DEFAULT_ICON);
+    let big_icon = icon_guard(create_icon(icon, unsafe { GetSystemMetrics(SM_CXICON) }));
+    let small_icon = icon_guard(create_icon(icon, unsafe { GetSystemMetrics(SM_CXSMICON) }));
+
     let window_class = WNDCLASSW {
         // Redraw if size changes
         style: CS_HREDRAW | CS_VREDRAW,
@@ -472,7 +2065,7 @@ pub fn win32_main() -> io::Result<()> {
         // Instance that contains the window procedure (this one)
         hInstance: hinstance,
         // Handle to class icon - null for system default
-        hIcon: ptr::null_mut(),
+        hIcon: big_icon.resource,
         // Handle for class cursor - null for system default
         hCursor: ptr::null_mut(),
         // Handle to class background brush - null for application to paint its own background
@@ -483,6 +2076,38 @@ pub fn win32_main() -> io::Result<()> {
         lpszClassName: window_name.as_ptr(),
     };
 
+    let window_state = WindowState::new(options);
+
+    MAIN_THREAD_ID.store(unsafe { GetCurrentThreadId() }, Ordering::SeqCst);
+    // Without a console (e.g. started from Explorer) this fails harmlessly
+    unsafe { SetConsoleCtrlHandler(Some(console_ctrl_handler), TRUE) };
+
+    // Must happen before the window is created for it to be sized in physical pixels
+    set_dpi_awareness();
+    let window_style = WS_TILEDWINDOW | WS_VISIBLE;
+    let window_ex_style = WS_EX_LEFT;
+    let (window_width, window_height) = {
+        let (client_width, client_height) =
+            backbuffer_size(options.width, options.height, system_dpi_scale());
+        let mut window_rect = RECT {
+            left: 0,
+            top: 0,
+            right: client_width,
+            bottom: client_height,
+        };
+        // Grow the rect by the frame so the client area gets the requested size
+        if unsafe { AdjustWindowRectEx(&mut window_rect, window_style, FALSE, window_ex_style) }
+            == 0
+        {
+            (CW_USEDEFAULT, CW_USEDEFAULT)
+        } else {
+            (
+                window_rect.right - window_rect.left,
+                window_rect.bottom - window_rect.top,
+            )
+        }
+    };
+
     let (window, device_context) = unsafe {
         if RegisterClassW(
             // Pointer to WNDCLASS settings
@@ -493,236 +2118,1212 @@ pub fn win32_main() -> io::Result<()> {
         }
         let window = CreateWindowExW(
             // Default window style
-            WS_EX_LEFT,
+            window_ex_style,
             // Must be same as lpszClassName of previous call to RegisterClassW
             window_name.as_ptr(),
             // Title bar string
             title.as_ptr(),
             // Visible, tiled window
-            WS_TILEDWINDOW | WS_VISIBLE,
+            window_style,
             // Default horizontal position
             CW_USEDEFAULT,
             // Default vertical position
             CW_USEDEFAULT,
-            // Default width
-            CW_USEDEFAULT,
-            // Default height
-            CW_USEDEFAULT,
+            // Width including the frame
+            window_width,
+            // Height including the frame
+            window_height,
             // Parent window: null since no parent
             ptr::null_mut(),
             // Child window identifier - null
             ptr::null_mut(),
             // Instance of the module associated with the window
             hinstance,
-            // Initial message to be sent to the window - null for no additional data
-            ptr::null_mut(),
+            // Passed to WM_NCCREATE - lives on this stack frame until after the window is destroyed
+            &window_state as *const WindowState as LPVOID,
         );
         if window.is_null() {
             return Err(io::Error::last_os_error());
         }
 
+        // The class only holds the big icon, the title bar and taskbar use the small one
+        SendMessageW(
+            window,
+            WM_SETICON,
+            ICON_BIG as WPARAM,
+            big_icon.resource as LPARAM,
+        );
+        SendMessageW(
+            window,
+            WM_SETICON,
+            ICON_SMALL as WPARAM,
+            small_icon.resource as LPARAM,
+        );
+
         // Get device constant assuming requires a valid window handle
         let device_context = GetDC(window);
         (window, device_context)
     };
+    // Everything from here on is cleaned up by guards, dropped in the reverse order they're
+    // declared in: the device context before the window, the window before `window_state` it
+    // points to and the icons it uses
+    let _window_guard = window_guard(window);
+    let _device_context_guard = device_context_guard(window, device_context);
+
+    // After creation so the window has a placement to return to
+    if options.fullscreen {
+        toggle_fullscreen(window, &window_state);
+    }
 
     let mut sound_output = {
         let sample_rate = 48000;
         let channel_count = 2;
-        let bytes_per_sample = std::mem::size_of::<WORD>() as u16 * channel_count;
+        let format = options.sample_format;
+        let bytes_per_sample = block_align(format, channel_count);
         let buffer_size = u32::from(sample_rate) * u32::from(bytes_per_sample);
 
         SoundOutput {
             channel_count,
             sample_rate,
             buffer_size,
-            latency_sample_count: u32::from(sample_rate) / 15,
+            format,
             bytes_per_sample,
+            latency_samples: None,
             running_sample_index: 0,
+            started: false,
+            cursor_gap: 0,
+            underrun_count: 0,
+        }
+    };
+    sound_output.set_latency_ms(options.audio_latency_ms);
+
+    // We'll only be writing to the secondary buffer, but need to retain the other two pointers to release them.
+    // With the `cpal` feature sound goes through cpal instead.
+    let direct_sound = if cfg!(feature = "cpal") {
+        None
+    } else {
+        match initialize_direct_sound(
+            window,
+            sound_output.buffer_size,
+            u32::from(sound_output.sample_rate),
+            sound_output.channel_count,
+            sound_output.format,
+        ) {
+            Ok(pointers) => Some(pointers),
+            Err(error) => {
+                // Not fatal - the game is still playable without sound
+                eprintln!("Running without sound: {}", error);
+                None
+            }
         }
     };
 
-    // We'll only be writing to the secondary buffer, but need to retain the other two pointers to release them
-    let (direct_sound_ptr, primary_buffer_ptr, secondary_buffer_ptr) = initialize_direct_sound(
-        window,
-        sound_output.buffer_size,
-        u32::from(sound_output.sample_rate),
-    );
-
-    let secondary_buffer = unsafe { secondary_buffer_ptr.as_mut().unwrap() };
-    sound_output.clear_buffer(secondary_buffer);
-    unsafe {
-        // Begin playing secondary buffer
-        secondary_buffer.Play(
-            // Must be 0
-            0,
-            // Must be 0
-            0,
-            // Circular buffer: looping
-            DSBPLAY_LOOPING,
-        );
+    let secondary_buffer = direct_sound
+        .as_ref()
+        .map(|objects| &*objects.secondary_buffer);
+    if let Some(secondary_buffer) = secondary_buffer {
+        sound_output.clear_buffer(secondary_buffer);
+        unsafe {
+            // Begin playing secondary buffer
+            secondary_buffer.Play(
+                // Must be 0
+                0,
+                // Must be 0
+                0,
+                // Circular buffer: looping
+                DSBPLAY_LOOPING,
+            );
+        }
     }
 
-    // Static can only be accessed from main thread
-    unsafe {
-        RUNNING = true;
-    }
+    RUNNING.store(true, Ordering::SeqCst);
 
     let mut sound_buffer = SoundBuffer {
-        samples: vec![0; sound_output.buffer_size as usize],
+        // Enough interleaved samples to fill the whole ring buffer in one go
+        samples: vec![0; sound_output.sample_capacity()],
         sample_count: 0,
-        t_sin: 0.0,
-        volume: 4000.0,
+        volume: window_state.volume.get(),
+        muted: false,
+        tone_enabled: true,
         sample_rate: sound_output.sample_rate,
+        channels: sound_output.channel_count,
+        waveform: Waveform::Sine,
+        sample_clock: 0,
+        // 10ms
+        ramp_samples: u32::from(sound_output.sample_rate) / 100,
+        tone_ramp: Ramp::new(f32::from(window_state.tone_hz.get())),
+        // Fade in from silence on startup
+        volume_ramp: Ramp::new(0.0),
+        clip: None,
+        oscillators: Vec::new(),
+        // Takes the edge off square and sawtooth waves, leaves the sine alone. Off for the test
+        // tone, which has to come out at exactly its level.
+        filter_cutoff: if options.test_tone {
+            f32::INFINITY
+        } else {
+            8000.0
+        },
+        filter_state: 0.0,
+    };
+    // Renders its own samples from the settings the game leaves in `sound_buffer`
+    #[cfg(feature = "cpal")]
+    let audio = match CpalAudio::open(&sound_buffer, window_state.tone_hz.get()) {
+        Ok(audio) => Some(audio),
+        Err(error) => {
+            // Not fatal - the game is still playable without sound
+            eprintln!("Running without sound: {}", error);
+            None
+        }
+    };
+
+    let mut controller_slots = [ControllerSlot::default(); XUSER_MAX_COUNT as usize];
+    // Buttons of the controller driving the game at the previous poll
+    let mut previous_buttons = 0;
+    let mut replay = InputReplay::default();
+    // One zeroed allocation for the lifetime of the game, split into the two blocks
+    let game_memory_ptr = unsafe {
+        VirtualAlloc(
+            // Let the system pick the address
+            ptr::null_mut(),
+            PERMANENT_STORAGE_SIZE + TRANSIENT_STORAGE_SIZE,
+            MEM_RESERVE | MEM_COMMIT,
+            PAGE_READWRITE,
+        )
+    } as *mut u8;
+    if game_memory_ptr.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    // Declared before `game_memory` so it outlives the slices into it
+    let _game_memory_guard = virtual_memory_guard(game_memory_ptr as LPVOID);
+    let (permanent, transient) = unsafe {
+        std::slice::from_raw_parts_mut(
+            game_memory_ptr,
+            PERMANENT_STORAGE_SIZE + TRANSIENT_STORAGE_SIZE,
+        )
+    }
+    .split_at_mut(PERMANENT_STORAGE_SIZE);
+    let mut game_memory = GameMemory {
+        permanent,
+        transient,
+        initialized: false,
     };
 
-    #[allow(unused_mut, unused_variables)]
+    let game_dll_path = std::env::current_exe()?.with_file_name(GAME_DLL_NAME);
+    let temp_game_dll_path = game_dll_path.with_file_name(GAME_DLL_TEMP_NAME);
+    let mut game_code = GameCode::load(&game_dll_path, &temp_game_dll_path);
+
     let mut last_counter = get_performance_counter()?;
-    #[allow(unused_mut, unused_variables)]
+    let mut last_frame_seconds = 1.0 / frame_limiter.target_fps as f32;
+    let mut present_mode = startup_present_mode(options.present_mode, dwm_composition_enabled());
     let mut last_cycle_count = get_cycles();
+    let mut frame_rate = FrameRateCounter::default();
+    let mut frame_times = FrameTimeHistory::default();
+    let mut recorder = options
+        .record_frames
+        .then(|| FrameRecorder::new(RECORDING_DIRECTORY));
+
+    while RUNNING.load(Ordering::SeqCst) {
+        process_messages(false);
+
+        if !window_state.active.get() {
+            // Nobody is watching or listening in the background: silence the sound and sleep in
+            // GetMessageW instead of rendering frames
+            if let Some(secondary_buffer) = secondary_buffer {
+                unsafe { secondary_buffer.Stop() };
+            }
+            #[cfg(feature = "cpal")]
+            if let Some(audio) = &audio {
+                audio.pause();
+            }
+            while RUNNING.load(Ordering::SeqCst) && !window_state.active.get() {
+                process_messages(true);
+            }
+            if let Some(secondary_buffer) = secondary_buffer {
+                unsafe { secondary_buffer.Play(0, 0, DSBPLAY_LOOPING) };
+            }
+            #[cfg(feature = "cpal")]
+            if let Some(audio) = &audio {
+                audio.play();
+            }
 
-    while unsafe { RUNNING } {
-        unsafe {
-            let mut message = MaybeUninit::uninit();
-            while PeekMessageW(
-                // Out pointer for message
-                message.as_mut_ptr(),
-                // Null to receive all messages meant for current thread
-                ptr::null_mut(),
-                // Next two params 0 to receive all available messages
-                0,
-                0,
-                // Remove messages from queue after peek
-                PM_REMOVE,
-            ) != 0
-            {
-                // Non-zero return value means messages are available, so message is initialized
-                let message = message.assume_init();
-                if message.message == WM_QUIT {
-                    RUNNING = false;
-                }
+            // The time spent in the background isn't a frame, don't let it jump the game forward
+            last_counter = get_performance_counter()?;
+            last_cycle_count = get_cycles();
+            continue;
+        }
 
-                TranslateMessage(&message as *const _);
-                DispatchMessageW(&message as *const _);
+        // Act on this frame's keys. The snapshot is taken first since actions can show a message
+        // box, which pumps key messages of its own.
+        let keyboard = {
+            let mut keyboard = window_state.keyboard.borrow_mut();
+            let snapshot = keyboard.clone();
+            keyboard.end_frame();
+            snapshot
+        };
+        handle_keyboard(window, &window_state, &keyboard);
+
+        // Pick up edits to the settings file. The command line still overrides them.
+        let changed_settings = settings.poll();
+        if !changed_settings.is_empty() {
+            let live = Options::from_settings(settings.settings());
+            for &field in changed_settings.iter() {
+                match field {
+                    "tone_hz" => window_state.tone_hz.set(live.tone_hz),
+                    "volume_dbfs" => window_state.volume.set(dbfs_to_amplitude(live.volume_dbfs)),
+                    "audio_latency_ms" => sound_output.set_latency_ms(live.audio_latency_ms),
+                    "present_mode" => {
+                        present_mode =
+                            startup_present_mode(live.present_mode, dwm_composition_enabled())
+                    }
+                    "wheel_step_hz" => window_state.wheel_step_hz.set(live.wheel_step_hz),
+                    // The rest only apply at startup
+                    _ => (),
+                }
             }
         }
 
-        // Handle gamepad input
-        unsafe {
-            for controller_index in 0..XUSER_MAX_COUNT {
-                let mut controller_state = MaybeUninit::uninit();
-                if XInputGetState(
-                    // Index of controller
-                    controller_index,
-                    // Out pointer for state to set
-                    controller_state.as_mut_ptr(),
-                ) == ERROR_SUCCESS
-                {
-                    // Function succeeded - state is initialized
-                    let controller_state = controller_state.assume_init();
-                    let pad = &controller_state.Gamepad;
-                    let _up_pressed = (pad.wButtons & XINPUT_GAMEPAD_DPAD_UP) != 0;
-                    let _stick_x = pad.sThumbLX;
-                } else {
-                    // Controller not available
-                }
+        // Handle gamepad input - every connected controller plays, their buttons trigger actions
+        // as if pressed on one pad
+        let mut gamepads = [GamepadInput::default(); MAX_GAMEPADS];
+        let mut buttons = 0;
+        for ((controller_index, slot), gamepad) in (0..XUSER_MAX_COUNT)
+            .zip(controller_slots.iter_mut())
+            .zip(gamepads.iter_mut())
+        {
+            if let Some(controller_state) =
+                slot.poll(controller_index, || get_controller_state(controller_index))
+            {
+                let pad = &controller_state.Gamepad;
+                *gamepad = gamepad_input_from(pad);
+                buttons |= pad.wButtons;
             }
         }
 
-        let mut play_cursor: DWORD = 0;
-        let mut write_cursor: DWORD = 0;
-        if {
-            unsafe {
+        // Unplugging a controller releases its buttons
+        handle_button_presses(
+            window,
+            &window_state,
+            button_edges(previous_buttons, buttons),
+        );
+        previous_buttons = buttons;
+
+        let cursors = secondary_buffer.and_then(|secondary_buffer| {
+            let mut play_cursor: DWORD = 0;
+            let mut write_cursor: DWORD = 0;
+            let result = unsafe {
                 secondary_buffer.GetCurrentPosition(
                     // Out pointer for play cursor
                     &mut play_cursor as *mut _,
                     // Out pointer for write cursor
                     &mut write_cursor as *mut _,
                 )
+            };
+            // Skip sound for this frame rather than write at a bogus position
+            if result == DS_OK {
+                Some((play_cursor, write_cursor))
+            } else {
+                None
             }
-        } != DS_OK
-        {
-            panic!("Failed to get current DirectSound buffer position");
-        }
-        let byte_to_lock = (sound_output.running_sample_index
-            * u32::from(sound_output.bytes_per_sample))
-            % sound_output.buffer_size;
-        let target_cursor = (play_cursor
-            + (sound_output.latency_sample_count * u32::from(sound_output.bytes_per_sample)))
-            % sound_output.buffer_size;
-        let bytes_to_write = if byte_to_lock > target_cursor {
-            sound_output.buffer_size - byte_to_lock + target_cursor
-        } else {
-            target_cursor - byte_to_lock
-        };
+        });
+        let (byte_to_lock, bytes_to_write) =
+            sound_output.bytes_to_write(cursors, last_frame_seconds);
 
         sound_buffer.sample_count =
             bytes_to_write as usize / sound_output.bytes_per_sample as usize;
-        update_and_render(
-            // static should only be accessed from main thread
-            unsafe { &mut DISPLAY_BUFFER },
-            &mut sound_buffer,
-            unsafe { HZ },
+        // Held until the frame is presented, nothing in between pumps messages
+        let mut display_buffer = window_state.display_buffer.borrow_mut();
+        let mouse = window_state.mouse.get();
+        let (x, y) = client_to_buffer(
+            mouse.x,
+            mouse.y,
+            &destination_rect(
+                window_state.scale_mode,
+                display_buffer.width,
+                display_buffer.height,
+                &get_window_dimension(window),
+            ),
+            &display_buffer,
         );
+        let mut tone_keys = window_state.tone_keys.get();
+        window_state
+            .tone_hz
+            .set(tone_keys.advance(window_state.tone_hz.get(), last_frame_seconds));
+        window_state.tone_keys.set(tone_keys);
+        let input = Input {
+            tone_hz: window_state.tone_hz.get(),
+            volume: window_state.volume.get(),
+            muted: window_state.muted.get(),
+            tone_enabled: window_state.tone_enabled.get(),
+            harmonics: window_state.harmonics.get(),
+            gamepad: GamepadInput::combine(&gamepads),
+            gamepads,
+            mouse: MouseInput { x, y, ..mouse },
+            keyboard,
+            text: window_state.text.take(),
+            paused: window_state.paused.get(),
+            step_frames: window_state.step_frames.take(),
+            dt: last_frame_seconds,
+        };
 
-        sound_output.fill_buffer(
-            secondary_buffer,
-            &sound_buffer,
-            byte_to_lock,
-            bytes_to_write,
-        );
+        if window_state.replay_toggle_requested.replace(false) {
+            replay.toggle(game_memory.game_state(), &mut sound_buffer);
+        }
+        let input = replay.process(input, game_memory.game_state(), &mut sound_buffer);
+        let input = if options.test_tone {
+            input.with_test_tone()
+        } else {
+            input
+        };
 
-        // Draw image to window
-        let dimension = get_window_dimension(window);
+        // Pick up a rebuilt game DLL. State lives out here, so it carries over.
+        if file_changed(game_code.last_write_time, file_write_time(&game_dll_path)) {
+            game_code.unload();
+            game_code = GameCode::load(&game_dll_path, &temp_game_dll_path);
+        }
+
+        // Simulate in fixed steps, however long the frame took, or not at all while paused
+        let steps = game_memory.game_state().due_updates(&input);
+        let fixed_dt = game_memory.game_state().timestep.fixed_dt;
+        for _ in 0..steps {
+            unsafe { (game_code.update)(&mut game_memory, &input, fixed_dt) };
+        }
+        let (left_motor, right_motor) = if input.paused {
+            (0, 0)
+        } else {
+            game_memory.game_state().rumble.motor_speeds()
+        };
+        for (controller_index, gamepad) in (0..XUSER_MAX_COUNT).zip(input.gamepads.iter()) {
+            if gamepad.connected {
+                // The controller may have been unplugged since polling it - that's fine
+                let _ = set_controller_vibration(controller_index, left_motor, right_motor);
+            }
+        }
+
+        let alpha = game_memory.game_state().timestep.alpha();
         unsafe {
-            // Static can only be accessed from main thread
-            DISPLAY_BUFFER.draw_to_window(
-                &BITMAP_INFO,
-                device_context,
-                dimension.width,
-                dimension.height,
+            (game_code.render)(
+                &mut game_memory,
+                &input,
+                alpha,
+                &mut display_buffer,
+                &mut sound_buffer,
+            )
+        };
+
+        #[cfg(feature = "cpal")]
+        if let Some(audio) = &audio {
+            audio.update(
+                &sound_buffer,
+                game_memory.game_state().tone_hz,
+                input.paused,
             );
         }
 
+        // Without sound (or a play cursor this frame) the sound buffer is empty
+        if let (Some(secondary_buffer), true) = (secondary_buffer, bytes_to_write > 0) {
+            sound_output.fill_buffer(
+                secondary_buffer,
+                &sound_buffer,
+                byte_to_lock,
+                bytes_to_write,
+            );
+        }
+
+        if let (true, Some(fps)) = (options.show_fps, frame_rate.fps()) {
+            let mut text = format!("{:.0} fps", fps);
+            if secondary_buffer.is_some() {
+                text += &format!("\n{} underruns", sound_output.underrun_count);
+            }
+            // The shadow keeps the text readable on any part of the gradient
+            display_buffer.draw_text(5, 5, &text, Pixel::from_rgba(0, 0, 0, 255));
+            display_buffer.draw_text(4, 4, &text, Pixel::from_rgba(255, 255, 255, 255));
+        }
+        if options.show_frame_times {
+            let target_seconds = 1.0 / frame_limiter.target_fps.max(1) as f32;
+            let bottom = display_buffer.height - 4;
+            frame_times.draw(&mut display_buffer, 4, bottom, target_seconds);
+        }
+
+        if let Some(frame_recorder) = &mut recorder {
+            if let Err(error) = frame_recorder.record(&display_buffer) {
+                // Not fatal - keep playing, just stop recording
+                eprintln!("Stopped recording: {}", error);
+                recorder = None;
+            }
+        }
+
+        // Present the finished frame. WM_PAINT keeps showing it until the next swap.
+        let dimension = get_window_dimension(window);
+        present_mode = present_frame(
+            present_mode,
+            || unsafe { DwmFlush() } == S_OK,
+            || {
+                display_buffer.swap();
+                let destination = destination_rect(
+                    window_state.scale_mode,
+                    display_buffer.width,
+                    display_buffer.height,
+                    &dimension,
+                );
+                display_buffer.draw_to_window(
+                    &window_state.bitmap_info.borrow(),
+                    device_context,
+                    &destination,
+                    &dimension,
+                );
+            },
+            || frame_limiter.wait_for_frame_end(unsafe { *last_counter.QuadPart() }),
+        )?;
+        drop(display_buffer);
+
         let end_counter = get_performance_counter()?;
-        // let counter_elapsed = unsafe { end_counter.QuadPart() - last_counter.QuadPart() };
-        // let time_elapsed_in_ms = (counter_elapsed * 1000) / perf_counter_frequency;
-        // let fps = perf_counter_frequency / counter_elapsed;
+        last_frame_seconds = unsafe {
+            seconds_elapsed(
+                *last_counter.QuadPart(),
+                *end_counter.QuadPart(),
+                perf_counter_frequency,
+            )
+        };
+        frame_rate.record(last_frame_seconds);
+        frame_times.record(last_frame_seconds);
 
         let end_cycle_count = get_cycles();
-        // let cycles_elapsed = end_cycle_count - last_cycle_count;
-        // let million_cycles_per_frame = cycles_elapsed / 1_000_000;
-        // println!(
-        //     "Draw frame in {} ms, {} fps, {} million cycles per frame",
-        //     time_elapsed_in_ms, fps, million_cycles_per_frame
-        // );
-
-        #[allow(unused_assignments)]
-        {
-            last_counter = end_counter;
-            last_cycle_count = end_cycle_count;
+        if options.show_stats {
+            let stats = format_frame_stats(
+                unsafe { end_counter.QuadPart() - last_counter.QuadPart() },
+                perf_counter_frequency,
+                // The time stamp counter can wrap around
+                end_cycle_count.wrapping_sub(last_cycle_count),
+            );
+            // Goes to the debugger so stdout stays free
+            unsafe { OutputDebugStringW(win32_string(&stats).as_ptr()) };
         }
+
+        last_counter = end_counter;
+        last_cycle_count = end_cycle_count;
     }
 
-    // Cleanup
-    unsafe {
-        ReleaseDC(
-            // Window handle
-            window,
-            // Device context for given window handle
-            device_context,
+    // The guards and `Drop` impls release everything on the way out
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use winapi::{
+        shared::{guiddef::REFIID, ntdef::HRESULT},
+        um::unknwnbase::IUnknownVtbl,
+    };
+
+    #[test]
+    fn block_align_covers_every_channel() {
+        assert_eq!(block_align(SampleFormat::Pcm16, 2), 4);
+        assert_eq!(block_align(SampleFormat::Pcm24, 2), 6);
+        assert_eq!(block_align(SampleFormat::Float32, 2), 8);
+        assert_eq!(block_align(SampleFormat::Pcm24, 1), 3);
+    }
+
+    #[test]
+    fn gamepad_input_reads_buttons_triggers_and_sticks() {
+        let pad = XINPUT_GAMEPAD {
+            wButtons: XINPUT_GAMEPAD_DPAD_UP
+                | XINPUT_GAMEPAD_A
+                | XINPUT_GAMEPAD_START
+                | XINPUT_GAMEPAD_RIGHT_SHOULDER,
+            bLeftTrigger: u8::MAX,
+            bRightTrigger: 0,
+            sThumbLX: i16::MAX,
+            sThumbLY: 0,
+            // Resting drift inside the deadzone
+            sThumbRX: 100,
+            sThumbRY: -100,
+        };
+
+        let input = gamepad_input_from(&pad);
+        assert_eq!(
+            input,
+            GamepadInput {
+                connected: true,
+                up: true,
+                a: true,
+                start: true,
+                right_shoulder: true,
+                left_trigger: 1.0,
+                left_stick_x: 1.0,
+                ..GamepadInput::default()
+            }
+        );
+    }
+
+    #[test]
+    fn empty_slots_back_off_before_polling_again() {
+        let polls = Cell::new(0);
+        let mut slot = ControllerSlot::default();
+        let poll = |slot: &mut ControllerSlot, plugged_in: bool| {
+            slot.poll(0, || {
+                polls.set(polls.get() + 1);
+                if plugged_in {
+                    Some(())
+                } else {
+                    None
+                }
+            })
+        };
+
+        // Connected controllers are polled every frame
+        assert_eq!(poll(&mut slot, true), Some(()));
+        assert_eq!(poll(&mut slot, true), Some(()));
+        assert!(slot.connected);
+        assert_eq!(polls.get(), 2);
+
+        // Unplugging starts the cooldown, during which the slot isn't polled
+        assert_eq!(poll(&mut slot, false), None);
+        assert!(!slot.connected);
+        for _ in 0..DISCONNECTED_POLL_INTERVAL {
+            assert_eq!(poll(&mut slot, true), None);
+        }
+        assert_eq!(polls.get(), 3);
+
+        // Then it's rescanned and the controller is found again
+        assert_eq!(poll(&mut slot, true), Some(()));
+        assert!(slot.connected);
+        assert_eq!(polls.get(), 4);
+    }
+
+    #[test]
+    fn client_positions_map_through_a_stretched_buffer() {
+        let buffer = DisplayBuffer {
+            width: 160,
+            height: 90,
+            ..INITIAL_DISPLAY_BUFFER
+        };
+        // A square window stretching the 16:9 buffer
+        let destination = DestinationRect {
+            x: 0,
+            y: 0,
+            width: 400,
+            height: 400,
+        };
+
+        assert_eq!(client_to_buffer(0, 0, &destination, &buffer), (0, 0));
+        assert_eq!(client_to_buffer(200, 200, &destination, &buffer), (80, 45));
+        assert_eq!(client_to_buffer(399, 399, &destination, &buffer), (159, 89));
+
+        // Letterboxed into the same window, the bars map outside the buffer
+        let destination = destination_rect(
+            ScaleMode::Letterbox,
+            160,
+            90,
+            &WindowDimension {
+                width: 400,
+                height: 400,
+            },
         );
+        assert_eq!(client_to_buffer(200, 200, &destination, &buffer), (80, 45));
+        assert!(client_to_buffer(200, 10, &destination, &buffer).1 < 0);
+        assert!(client_to_buffer(200, 390, &destination, &buffer).1 >= 90);
+    }
 
-        // Release buffers to free allocated memory
-        (*direct_sound_ptr).Release();
-        (*primary_buffer_ptr).Release();
-        secondary_buffer.Release();
+    #[test]
+    fn quitting_stops_the_main_loop() {
+        RUNNING.store(true, Ordering::SeqCst);
+        request_quit();
+        assert!(!RUNNING.load(Ordering::SeqCst));
 
-        // Destroy given window handle
-        if DestroyWindow(window) == 0 {
-            eprintln!("Failed to destroy window");
+        assert_eq!(
+            KeyBindings::default().actions.get(&VK_ESCAPE),
+            Some(&Action::Quit)
+        );
+    }
+
+    #[test]
+    fn sleeps_whole_milliseconds_short_of_the_frame_end() {
+        // 10 MHz counter, 16 ms frame
+        let frequency = 10_000_000;
+        let target = 160_000;
+        assert_eq!(sleep_milliseconds(0, target, frequency), 15);
+        assert_eq!(sleep_milliseconds(100_000, target, frequency), 5);
+        // 1.5 ms left rounds down to 1, less the millisecond held back
+        assert_eq!(sleep_milliseconds(145_000, target, frequency), 0);
+        assert_eq!(sleep_milliseconds(target, target, frequency), 0);
+        assert_eq!(sleep_milliseconds(200_000, target, frequency), 0);
+        assert_eq!(sleep_milliseconds(0, target, 0), 0);
+    }
+
+    #[test]
+    fn seconds_elapsed_divides_counts_by_frequency() {
+        assert_eq!(seconds_elapsed(0, 10_000_000, 10_000_000), 1.0);
+        assert_eq!(seconds_elapsed(1_000, 1_500, 1_000), 0.5);
+        assert!((seconds_elapsed(5, 5 + 166_667, 10_000_000) - 1.0 / 60.0).abs() < 1e-6);
+        assert_eq!(seconds_elapsed(42, 42, 3_579_545), 0.0);
+    }
+
+    #[test]
+    fn frame_stats_format_time_rate_and_cycles() {
+        assert_eq!(
+            format_frame_stats(166_667, 10_000_000, 35_000_000),
+            "16.7 ms/f, 60 fps, 35 Mc/f\n"
+        );
+        assert_eq!(
+            format_frame_stats(1_000, 1_000, 2_400_000_000),
+            "1000.0 ms/f, 1 fps, 2400 Mc/f\n"
+        );
+        // A zero-length frame doesn't divide by zero
+        assert_eq!(
+            format_frame_stats(0, 1_000, 0),
+            "1.0 ms/f, 1000 fps, 0 Mc/f\n"
+        );
+    }
+
+    fn sound_output() -> SoundOutput {
+        SoundOutput {
+            buffer_size: 48000 * 4,
+            running_sample_index: 0,
+            channel_count: 2,
+            sample_rate: 48000,
+            format: SampleFormat::Pcm16,
+            bytes_per_sample: 4,
+            latency_samples: None,
+            started: false,
+            cursor_gap: 0,
+            underrun_count: 0,
         }
     }
 
-    Ok(())
+    #[test]
+    fn nothing_is_written_without_sound_cursors() {
+        let mut sound_output = sound_output();
+        assert_eq!(sound_output.bytes_to_write(None, 1.0 / 60.0), (0, 0));
+        assert!(!sound_output.started);
+        assert_eq!(sound_output.running_sample_index, 0);
+        assert_eq!(sound_output.underrun_count, 0);
+
+        let (_, bytes_to_write) = sound_output.bytes_to_write(Some((0, 0)), 1.0 / 60.0);
+        assert!(bytes_to_write > 0);
+        assert!(sound_output.started);
+    }
+
+    #[test]
+    fn backbuffer_follows_the_dpi_scale() {
+        assert_eq!(backbuffer_size(1280, 720, 1.0), (1280, 720));
+        assert_eq!(backbuffer_size(1280, 720, 1.5), (1920, 1080));
+        assert_eq!(backbuffer_size(1280, 720, 2.0), (2560, 1440));
+        // Rounded to the nearest pixel
+        assert_eq!(backbuffer_size(101, 33, 1.5), (152, 50));
+    }
+
+    #[test]
+    fn deactivating_lets_go_of_held_keys() {
+        let state = WindowState::new(&Options::default());
+        assert!(state.active.get());
+        let mut tone_keys = ToneKeys::default();
+        tone_keys.raise_held = true;
+        state.tone_keys.set(tone_keys);
+        state.keyboard.borrow_mut().key_down(VK_UP as KeyCode);
+
+        state.set_active(false);
+        assert!(!state.active.get());
+        assert_eq!(state.tone_keys.get(), ToneKeys::default());
+        assert!(!state.keyboard.borrow().is_down(VK_UP as KeyCode));
+
+        state.set_active(true);
+        assert!(state.active.get());
+    }
+
+    #[test]
+    fn backbuffer_is_only_resized_for_a_new_client_size() {
+        let client = |width, height| WindowDimension { width, height };
+        let state = WindowState::new(&Options::default());
+        state.display_buffer.borrow_mut().resize(800, 600);
+
+        assert_eq!(state.backbuffer_resize(&client(800, 600), false), None);
+        assert_eq!(
+            state.backbuffer_resize(&client(1024, 768), false),
+            Some((1024, 768))
+        );
+        assert_eq!(state.backbuffer_resize(&client(1024, 768), true), None);
+        assert_eq!(state.backbuffer_resize(&client(0, 0), false), None);
+
+        state
+            .windowed_placement
+            .set(Some(unsafe { std::mem::zeroed() }));
+        assert_eq!(state.backbuffer_resize(&client(1920, 1080), false), None);
+
+        let fixed = WindowState::new(&Options {
+            render_resolution: Some((320, 180)),
+            ..Options::default()
+        });
+        fixed.display_buffer.borrow_mut().resize(320, 180);
+        assert_eq!(fixed.backbuffer_resize(&client(1024, 768), false), None);
+    }
+
+    #[test]
+    fn letterbox_centers_between_bars() {
+        let letterbox = |width, height| {
+            destination_rect(
+                ScaleMode::Letterbox,
+                160,
+                90,
+                &WindowDimension { width, height },
+            )
+        };
+
+        // Wider than 16:9: bars left and right
+        assert_eq!(
+            letterbox(1000, 360),
+            DestinationRect {
+                x: 180,
+                y: 0,
+                width: 640,
+                height: 360
+            }
+        );
+        // Taller: bars top and bottom
+        assert_eq!(
+            letterbox(640, 600),
+            DestinationRect {
+                x: 0,
+                y: 120,
+                width: 640,
+                height: 360
+            }
+        );
+        // Same shape: no bars
+        assert_eq!(
+            letterbox(320, 180),
+            DestinationRect {
+                x: 0,
+                y: 0,
+                width: 320,
+                height: 180
+            }
+        );
+    }
+
+    #[test]
+    fn write_region_tops_up_to_the_latency() {
+        let size = 1000;
+        // Written data 100 bytes ahead of the play cursor, 300 wanted
+        assert_eq!(ring_write_region(200, 300, 300, size), (300, 200));
+        // The same across the end of the buffer
+        assert_eq!(ring_write_region(900, 0, 300, size), (0, 200));
+        assert_eq!(ring_write_region(800, 950, 300, size), (950, 150));
+        // Already far enough ahead, including after a wrap
+        assert_eq!(ring_write_region(900, 300, 300, size), (300, 0));
+        // Overtaken by the play cursor: start again from it
+        assert_eq!(ring_write_region(500, 400, 300, size), (500, 300));
+        // Never more than half the buffer is queued
+        assert_eq!(ring_write_region(0, 0, 2000, size), (0, 500));
+        assert_eq!(ring_write_region(0, 0, 300, 0), (0, 0));
+    }
+
+    #[test]
+    fn latency_follows_the_frame_time() {
+        // Rounded up, and f32 can land just past a whole sample
+        let latency = |frame_seconds| latency_sample_count(frame_seconds, 48000);
+        let around = |expected: u32| expected..=expected + 1;
+
+        // One frame plus the safety margin
+        assert!(around(2400).contains(&latency(1.0 / 60.0)));
+        assert!(around(3200).contains(&latency(1.0 / 30.0)));
+        // Stalls and nonsense are capped
+        assert!(around(6400).contains(&latency(5.0)));
+        assert_eq!(latency(f32::NAN), latency(5.0));
+        assert!(around(1600).contains(&latency(-1.0)));
+    }
+
+    #[test]
+    fn regions_never_take_more_than_the_source_holds() {
+        // Enough for both regions
+        assert_eq!(region_sample_counts(100, 50, 200), (100, 50));
+        // Runs out in the second region
+        assert_eq!(region_sample_counts(100, 50, 120), (100, 20));
+        // Runs out in the first
+        assert_eq!(region_sample_counts(100, 50, 60), (60, 0));
+        assert_eq!(region_sample_counts(100, 50, 0), (0, 0));
+
+        // Copying by those counts stays inside a short source
+        let source = [1i16; 60];
+        let (region_1, region_2) = region_sample_counts(100, 50, source.len());
+        let copied: Vec<i16> = source[..region_1]
+            .iter()
+            .chain(&source[region_1..region_1 + region_2])
+            .copied()
+            .collect();
+        assert_eq!(copied.len(), source.len());
+    }
+
+    #[test]
+    fn custom_key_bindings_resolve_to_their_actions() {
+        let mut bindings = KeyBindings::default();
+        bindings.bind(VK_LEFT, Action::DecreaseTone);
+        bindings.bind(VK_M, Action::Quit);
+
+        let action = |key| bindings.actions.get(&key).copied();
+        assert_eq!(action(VK_LEFT), Some(Action::DecreaseTone));
+        // Rebinding a key replaces its old action, other keys keep theirs
+        assert_eq!(action(VK_M), Some(Action::Quit));
+        assert_eq!(action(VK_DOWN), Some(Action::DecreaseTone));
+        assert_eq!(action(VK_1 + 2), Some(Action::ToggleHarmonic(2)));
+        assert_eq!(action(VK_RETURN), None);
+    }
+
+    #[test]
+    fn integer_scale_fits_whole_multiples() {
+        let client = |width, height| WindowDimension { width, height };
+
+        // 3x fits 1000x600 (4x would be 1280 wide), centered
+        assert_eq!(
+            integer_scale_rect(320, 180, &client(1000, 600)),
+            DestinationRect {
+                x: 20,
+                y: 30,
+                width: 960,
+                height: 540
+            }
+        );
+        // Exactly 2x
+        assert_eq!(
+            integer_scale_rect(320, 180, &client(640, 360)),
+            DestinationRect {
+                x: 0,
+                y: 0,
+                width: 640,
+                height: 360
+            }
+        );
+        // A smaller window shows the middle unscaled, cropping the edges
+        assert_eq!(
+            integer_scale_rect(320, 180, &client(200, 100)),
+            DestinationRect {
+                x: -60,
+                y: -40,
+                width: 320,
+                height: 180
+            }
+        );
+        // Smaller in one direction only
+        assert_eq!(
+            integer_scale_rect(320, 180, &client(1000, 150)),
+            DestinationRect {
+                x: 340,
+                y: -15,
+                width: 320,
+                height: 180
+            }
+        );
+    }
+
+    #[test]
+    fn surrogate_pairs_decode_across_two_messages() {
+        let mut decoder = Utf16Decoder::default();
+        let mut units = [0; 2];
+        '\u{1F600}'.encode_utf16(&mut units);
+
+        assert_eq!(decoder.push(units[0]), None);
+        assert_eq!(decoder.push(units[1]), Some('\u{1F600}'));
+        assert_eq!(decoder.push('a' as u16), Some('a'));
+        assert_eq!(decoder.push(0x08), Some('\u{8}'));
+
+        // Halves without their partner are dropped, the next character isn't
+        assert_eq!(decoder.push(units[1]), None);
+        assert_eq!(decoder.push(units[0]), None);
+        assert_eq!(decoder.push('b' as u16), Some('b'));
+    }
+
+    #[test]
+    fn quit_needs_a_yes_only_when_confirming() {
+        assert!(should_quit(false, None));
+        assert!(should_quit(true, Some(true)));
+        assert!(!should_quit(true, Some(false)));
+        // The message box couldn't be shown
+        assert!(should_quit(true, None));
+    }
+
+    #[test]
+    fn button_edges_between_polls() {
+        let previous = XINPUT_GAMEPAD_A | XINPUT_GAMEPAD_B;
+        let current = XINPUT_GAMEPAD_B | XINPUT_GAMEPAD_START;
+        assert_eq!(
+            button_edges(previous, current),
+            ButtonEdges {
+                pressed: XINPUT_GAMEPAD_START,
+                released: XINPUT_GAMEPAD_A,
+                held: current,
+            }
+        );
+
+        // Nothing changed: held, but no edges
+        assert_eq!(
+            button_edges(current, current),
+            ButtonEdges {
+                held: current,
+                ..ButtonEdges::default()
+            }
+        );
+        assert_eq!(button_edges(0, 0), ButtonEdges::default());
+    }
+
+    #[test]
+    fn guard_releases_exactly_once() {
+        static RELEASED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+        fn release(resource: u32) {
+            RELEASED.lock().unwrap().push(resource);
+        }
+        let released = || RELEASED.lock().unwrap().clone();
+
+        {
+            let _first = Guard::new(1, release);
+            let _second = Guard::new(2, release);
+            assert!(released().is_empty());
+        }
+        // In reverse order of declaration, like the guards in `win32_main`
+        assert_eq!(released(), [2, 1]);
+
+        // Released on the way out of an early return too
+        fn early_return(fail: bool) -> Result<(), ()> {
+            let _guard = Guard::new(3, release);
+            if fail {
+                return Err(());
+            }
+            panic!("should have returned early");
+        }
+        assert!(early_return(true).is_err());
+        assert_eq!(released(), [2, 1, 3]);
+    }
+
+    #[test]
+    fn com_ptr_releases_its_reference_once() {
+        /// Just enough of a COM object to count its references
+        #[repr(C)]
+        struct FakeComObject {
+            unknown: IUnknown,
+            ref_count: Cell<ULONG>,
+        }
+
+        impl Deref for FakeComObject {
+            type Target = IUnknown;
+
+            fn deref(&self) -> &IUnknown {
+                &self.unknown
+            }
+        }
+
+        unsafe extern "system" fn query_interface(
+            _this: *mut IUnknown,
+            _riid: REFIID,
+            _object: *mut LPVOID,
+        ) -> HRESULT {
+            E_NOINTERFACE
+        }
+        unsafe extern "system" fn add_ref(this: *mut IUnknown) -> ULONG {
+            let object = &*(this as *const FakeComObject);
+            object.ref_count.set(object.ref_count.get() + 1);
+            object.ref_count.get()
+        }
+        unsafe extern "system" fn release(this: *mut IUnknown) -> ULONG {
+            let object = &*(this as *const FakeComObject);
+            object.ref_count.set(object.ref_count.get() - 1);
+            object.ref_count.get()
+        }
+        static VTBL: IUnknownVtbl = IUnknownVtbl {
+            QueryInterface: query_interface,
+            AddRef: add_ref,
+            Release: release,
+        };
+
+        // One reference, as if just returned through an out pointer
+        let object = FakeComObject {
+            unknown: IUnknown { lpVtbl: &VTBL },
+            ref_count: Cell::new(1),
+        };
+        {
+            let com_ptr = unsafe { ComPtr::new(&object as *const _ as *mut FakeComObject) };
+            assert_eq!(com_ptr.ref_count.get(), 1);
+        }
+        assert_eq!(object.ref_count.get(), 0);
+    }
+
+    #[test]
+    fn underruns_are_detected_across_the_ring_wrap() {
+        // Without wrapping: play at 100, device committed up to 200
+        assert!(!is_underrun(100, 200, 300, 1000));
+        assert!(!is_underrun(100, 200, 200, 1000));
+        assert!(is_underrun(100, 200, 150, 1000));
+        // Fill end the play cursor has already gone past
+        assert!(is_underrun(100, 200, 50, 1000));
+
+        // Cursors either side of the end of the buffer
+        assert!(!is_underrun(900, 950, 50, 1000));
+        assert!(is_underrun(900, 950, 920, 1000));
+        // Write cursor wrapped, fill end not yet
+        assert!(!is_underrun(900, 20, 100, 1000));
+        assert!(is_underrun(900, 20, 990, 1000));
+
+        assert!(!is_underrun(0, 0, 0, 0));
+    }
+
+    #[test]
+    fn partial_wheel_deltas_add_up_to_notches() {
+        assert_eq!(wheel_notches(0, 120), (1, 0));
+        assert_eq!(wheel_notches(0, -240), (-2, 0));
+
+        // A high-resolution wheel sending quarter notches
+        let mut remainder = 0;
+        let mut notches = Vec::new();
+        for _ in 0..5 {
+            let (whole, rest) = wheel_notches(remainder, 30);
+            notches.push(whole);
+            remainder = rest;
+        }
+        assert_eq!(notches, [0, 0, 0, 1, 0]);
+        assert_eq!(remainder, 30);
+
+        // Turning back cancels the part notch rather than counting down from it
+        assert_eq!(wheel_notches(30, -30), (0, 0));
+        assert_eq!(wheel_notches(90, -250), (-1, -40));
+    }
+
+    #[test]
+    fn present_mode_picks_the_waits_around_present() {
+        // Runs one frame with mocked calls, returning the next mode and the calls made in order
+        let run = |mode: PresentMode, compositor_waits: bool| {
+            let calls = RefCell::new(Vec::new());
+            let next = present_frame(
+                mode,
+                || {
+                    calls.borrow_mut().push("compositor");
+                    compositor_waits
+                },
+                || calls.borrow_mut().push("present"),
+                || {
+                    calls.borrow_mut().push("frame end");
+                    Ok(())
+                },
+            )
+            .unwrap();
+            (next, calls.into_inner())
+        };
+
+        assert_eq!(
+            run(PresentMode::Immediate, true),
+            (PresentMode::Immediate, vec!["present"])
+        );
+        assert_eq!(
+            run(PresentMode::Vsync, true),
+            (PresentMode::Vsync, vec!["compositor", "present"])
+        );
+        assert_eq!(
+            run(PresentMode::CappedFps, true),
+            (PresentMode::CappedFps, vec!["present", "frame end"])
+        );
+        // Composition turned off mid-run: this frame is already capped, and so are later ones
+        assert_eq!(
+            run(PresentMode::Vsync, false),
+            (
+                PresentMode::CappedFps,
+                vec!["compositor", "present", "frame end"]
+            )
+        );
+
+        let failed = present_frame(
+            PresentMode::CappedFps,
+            || true,
+            || {},
+            || Err(io::Error::other("timer")),
+        );
+        assert!(failed.is_err());
+
+        assert_eq!(
+            startup_present_mode(PresentMode::Vsync, true),
+            PresentMode::Vsync
+        );
+        assert_eq!(
+            startup_present_mode(PresentMode::Vsync, false),
+            PresentMode::CappedFps
+        );
+        assert_eq!(
+            startup_present_mode(PresentMode::Immediate, false),
+            PresentMode::Immediate
+        );
+    }
+
+    #[test]
+    fn dimensions_follow_the_last_dib_resize() {
+        let mut display_buffer = INITIAL_DISPLAY_BUFFER;
+        let mut info = INITIAL_BITMAP_INFO;
+
+        display_buffer.resize_dib_section(&mut info, 320, 180);
+        assert_eq!(display_buffer.dimensions(), (320, 180));
+        assert_eq!(display_buffer.back.len(), 320 * 180);
+        // The DIB describes the same size, top-down
+        assert_eq!(
+            (info.bmiHeader.biWidth, info.bmiHeader.biHeight),
+            (320, -180)
+        );
+
+        display_buffer.resize_dib_section(&mut info, 100, 300);
+        assert_eq!(display_buffer.dimensions(), (100, 300));
+        assert_eq!(
+            (info.bmiHeader.biWidth, info.bmiHeader.biHeight),
+            (100, -300)
+        );
+    }
+
+    #[test]
+    fn two_controllers_combine_into_one() {
+        let first = gamepad_input_from(&XINPUT_GAMEPAD {
+            wButtons: XINPUT_GAMEPAD_A | XINPUT_GAMEPAD_DPAD_LEFT,
+            bLeftTrigger: 51,
+            bRightTrigger: 0,
+            sThumbLX: i16::MAX,
+            sThumbLY: 0,
+            sThumbRX: 0,
+            sThumbRY: 0,
+        });
+        let second = gamepad_input_from(&XINPUT_GAMEPAD {
+            wButtons: XINPUT_GAMEPAD_B,
+            bLeftTrigger: u8::MAX,
+            bRightTrigger: 0,
+            sThumbLX: i16::MAX,
+            sThumbLY: i16::MAX,
+            sThumbRX: 0,
+            sThumbRY: 0,
+        });
+        // Unplugged, whatever it last held
+        let unplugged = GamepadInput {
+            connected: false,
+            ..first
+        };
+
+        let combined = GamepadInput::combine(&[first, second, unplugged]);
+        assert!(combined.connected);
+        assert!(combined.a && combined.b && combined.left);
+        assert!(!combined.x && !combined.right);
+        // Furthest trigger pull
+        assert_eq!(combined.left_trigger, 1.0);
+        // Sticks add up, clamped
+        assert_eq!(combined.left_stick_x, 1.0);
+        assert!(combined.left_stick_y > 0.5 && combined.left_stick_y < 1.0);
+        assert_eq!((combined.right_stick_x, combined.right_stick_y), (0.0, 0.0));
+
+        assert_eq!(GamepadInput::combine(&[unplugged]), GamepadInput::default());
+    }
+
+    #[test]
+    fn latency_ms_converts_to_samples_within_the_ring() {
+        // A second of ring buffer at 48 kHz
+        assert_eq!(latency_ms_to_samples(30, 48000, 48000), (1440, false));
+        assert_eq!(latency_ms_to_samples(0, 48000, 48000), (0, false));
+        // Part samples round up
+        assert_eq!(latency_ms_to_samples(1, 44100, 44100), (45, false));
+        // Up to half the ring
+        assert_eq!(latency_ms_to_samples(500, 48000, 48000), (24000, false));
+        assert_eq!(latency_ms_to_samples(501, 48000, 48000), (24000, true));
+        assert_eq!(
+            latency_ms_to_samples(u32::MAX, u16::MAX, 48000),
+            (24000, true)
+        );
+    }
 }
@@ -0,0 +1,88 @@
+//! Drives the game through its public API alone, the way the platform layers do, with no window
+//! or sound device.
+
+use std::f32;
+
+use game::*;
+
+const WIDTH: i32 = 64;
+const HEIGHT: i32 = 36;
+const SAMPLE_RATE: u16 = 48000;
+const FRAMES_PER_SECOND: u16 = 60;
+const TONE_HZ: u16 = 440;
+
+fn display_buffer() -> DisplayBuffer {
+    let len = WIDTH as usize * HEIGHT as usize;
+    DisplayBuffer {
+        back: vec![Pixel::default(); len],
+        front: vec![Pixel::default(); len],
+        gradient_mode: GradientMode::FullRgb,
+        blend_mode: BlendMode::Linear,
+        width: WIDTH,
+        height: HEIGHT,
+    }
+}
+
+fn sound_buffer() -> SoundBuffer {
+    let frames = usize::from(SAMPLE_RATE / FRAMES_PER_SECOND);
+    SoundBuffer {
+        samples: vec![0; frames * 2],
+        sample_count: frames,
+        volume: 1000.0,
+        muted: false,
+        tone_enabled: true,
+        sample_rate: SAMPLE_RATE,
+        channels: 2,
+        waveform: Waveform::Sine,
+        sample_clock: 0,
+        ramp_samples: 0,
+        tone_ramp: Ramp::new(f32::from(TONE_HZ)),
+        volume_ramp: Ramp::new(1000.0),
+        clip: None,
+        oscillators: Vec::new(),
+        filter_cutoff: f32::INFINITY,
+        filter_state: 0.0,
+    }
+}
+
+/// Ticks `frames` frames of a sixtieth of a second, returning the state and last frame drawn
+fn run(frames: u32) -> (GameState, Vec<Pixel>, Vec<i16>) {
+    let mut game_state = GameState::default();
+    let mut display_buffer = display_buffer();
+    let mut sound_buffer = sound_buffer();
+    let input = Input {
+        tone_hz: TONE_HZ,
+        volume: 1000.0,
+        tone_enabled: true,
+        dt: 1.0 / f32::from(FRAMES_PER_SECOND),
+        ..Input::default()
+    };
+
+    let mut updates = 0;
+    for _ in 0..frames {
+        updates += game_state.tick(&input, &mut display_buffer, &mut sound_buffer);
+    }
+    // Two fixed updates a frame, give or take one to rounding at the end
+    assert!((updates as i64 - 2 * i64::from(frames)).abs() <= 1);
+    (game_state, display_buffer.back, sound_buffer.samples)
+}
+
+#[test]
+fn hundred_frames_scroll_and_advance_the_phase() {
+    let (game_state, _, _) = run(100);
+
+    // 60 pixels a second for 100/60 seconds, less any update still due
+    assert!((game_state.scroll_offset - 100.0).abs() <= 0.5 + 1e-3);
+    assert!(game_state.previous_scroll_offset < game_state.scroll_offset);
+    assert_eq!(game_state.tone_hz, TONE_HZ);
+
+    // 800 samples a frame of 440 Hz at 48 kHz is 7 1/3 periods, so the phase ends a third of
+    // the way round
+    let expected_phase = 2.0 * f32::consts::PI / 3.0;
+    assert!((game_state.t_sin - expected_phase).abs() < 0.01);
+}
+
+#[test]
+fn ticking_is_deterministic() {
+    assert_eq!(run(100), run(100));
+}